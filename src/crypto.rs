@@ -1,4 +1,25 @@
-use crate::runtime::{Js, ThreadPoolTaskKind, RUNTIME};
+mod aead;
+mod hash;
+mod kdf;
+mod random;
+
+pub use aead::{KEY_LEN, MAX_PLAINTEXT_LEN, NONCE_LEN, TAG_LEN};
+pub use hash::{Algorithm, Hasher};
+pub use kdf::ScryptParams;
+
+use crate::encoding::Encoding;
+use crate::runtime::{queue_microtask, runtime, Js, ThreadPoolTaskKind};
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::Path;
+
+/// How much `hash_file` reads at a time.
+const HASH_FILE_CHUNK: usize = 64 * 1024;
+
+/// Smaller payloads are sealed and opened on the loop thread, since handing
+/// them to the pool costs more than the work.
+const AEAD_POOL_THRESHOLD: usize = 64 * 1024;
 
 pub struct Crypto;
 impl Crypto {
@@ -16,7 +37,399 @@ impl Crypto {
             Js::Int(fib)
         };
 
-        let rt = unsafe { &mut *RUNTIME };
+        let rt = runtime();
         rt.register_event_threadpool(work, ThreadPoolTaskKind::Encrypt, cb);
     }
+
+    /// Calls back with the digest of `data` as a `Js::String` in
+    /// `encoding`, usually `Encoding::Hex` or `Encoding::Base64`.
+    pub fn hash(
+        algorithm: Algorithm,
+        data: impl Into<Vec<u8>>,
+        encoding: Encoding,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let data = data.into();
+        let work = move || hash::digest(algorithm, &data);
+        Crypto::run(ThreadPoolTaskKind::Hash, work, encoding, cb);
+    }
+
+    /// Starts an incremental digest that runs on the calling thread. Feed
+    /// it with `update` and get the result with `digest`.
+    pub fn create_hash(algorithm: Algorithm) -> Hasher {
+        Hasher::new(algorithm)
+    }
+
+    /// Hashes the file at `path` in chunks on a single pool thread, so it's
+    /// never all in memory. Binary files are fine.
+    pub fn hash_file(
+        path: impl AsRef<Path>,
+        algorithm: Algorithm,
+        encoding: Encoding,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let path = path.as_ref().to_path_buf();
+        let work = move || -> io::Result<String> {
+            let mut file = File::open(&path)?;
+            let mut hasher = Hasher::new(algorithm);
+            let mut chunk = vec![0; HASH_FILE_CHUNK];
+            loop {
+                match file.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => hasher.update(&chunk[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            hasher.digest(encoding)
+        };
+        let work = move || work().map(Js::String).unwrap_or_else(Js::Error);
+        let rt = runtime();
+        rt.register_event_threadpool(work, ThreadPoolTaskKind::HashFile, cb);
+    }
+
+    /// Like `hash`, but keyed. Compare the result against a signature with
+    /// `timing_safe_equal`, not `==`.
+    pub fn hmac(
+        algorithm: Algorithm,
+        key: impl Into<Vec<u8>>,
+        data: impl Into<Vec<u8>>,
+        encoding: Encoding,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let key = key.into();
+        let data = data.into();
+        let work = move || hash::hmac(algorithm, &key, &data);
+        Crypto::run(ThreadPoolTaskKind::Hmac, work, encoding, cb);
+    }
+
+    /// Derives `key_len` bytes with PBKDF2-HMAC-`algorithm` and calls back
+    /// with them as `Js::Bytes`. Like every CPU-bound task it only gets part
+    /// of the pool, so it can't starve file I/O.
+    pub fn pbkdf2(
+        password: impl Into<Vec<u8>>,
+        salt: impl Into<Vec<u8>>,
+        iterations: u32,
+        key_len: usize,
+        algorithm: Algorithm,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let password = password.into();
+        let salt = salt.into();
+        let work = move || match kdf::pbkdf2(algorithm, &password, &salt, iterations, key_len) {
+            Ok(key) => Js::Bytes(key),
+            Err(e) => Js::Error(e),
+        };
+        let rt = runtime();
+        rt.register_event_threadpool(work, ThreadPoolTaskKind::Pbkdf2, cb);
+    }
+
+    /// Derives `key_len` bytes with scrypt and calls back with them as
+    /// `Js::Bytes`. Bad parameters, or ones that would need more than
+    /// `params.max_mem`, give a `Js::Error` of kind `InvalidInput`.
+    pub fn scrypt(
+        password: impl Into<Vec<u8>>,
+        salt: impl Into<Vec<u8>>,
+        key_len: usize,
+        params: ScryptParams,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let password = password.into();
+        let salt = salt.into();
+        let work = move || match kdf::scrypt(&password, &salt, params, key_len) {
+            Ok(key) => Js::Bytes(key),
+            Err(e) => Js::Error(e),
+        };
+        let rt = runtime();
+        rt.register_event_threadpool(work, ThreadPoolTaskKind::Scrypt, cb);
+    }
+
+    /// Fills `n` bytes from the kernel's CSPRNG on the pool and calls back
+    /// with them as `Js::Bytes`. Worth it for large buffers; small ones can
+    /// use `random_bytes_sync`.
+    pub fn random_bytes(n: usize, cb: impl Fn(Js) + 'static) {
+        let work = move || match random::random_bytes(n) {
+            Ok(bytes) => Js::Bytes(bytes),
+            Err(e) => Js::Error(e),
+        };
+        let rt = runtime();
+        rt.register_event_threadpool(work, ThreadPoolTaskKind::RandomBytes, cb);
+    }
+
+    pub fn random_bytes_sync(n: usize) -> io::Result<Vec<u8>> {
+        random::random_bytes(n)
+    }
+
+    /// A random version 4 UUID in its usual hyphenated form.
+    pub fn random_uuid() -> io::Result<String> {
+        random::random_uuid()
+    }
+
+    /// A uniformly distributed integer in `range`. Fails if it's empty.
+    pub fn random_int(range: Range<u64>) -> io::Result<u64> {
+        random::random_int(range)
+    }
+
+    /// Encrypts `plaintext` with ChaCha20-Poly1305 and calls back with the
+    /// ciphertext and 16 byte tag as one `Js::Bytes`. `key` must be
+    /// `KEY_LEN` bytes and `nonce` `NONCE_LEN`; never reuse a nonce with the
+    /// same key. `aad` is authenticated but not encrypted. At most
+    /// `MAX_PLAINTEXT_LEN` bytes can be sealed with one nonce.
+    pub fn seal(
+        key: impl Into<Vec<u8>>,
+        nonce: impl Into<Vec<u8>>,
+        aad: impl Into<Vec<u8>>,
+        plaintext: impl Into<Vec<u8>>,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let (key, nonce, aad, plaintext) = (key.into(), nonce.into(), aad.into(), plaintext.into());
+        let large = plaintext.len() >= AEAD_POOL_THRESHOLD;
+        let work = move || aead::seal(&key, &nonce, &aad, &plaintext);
+        Crypto::run_aead(ThreadPoolTaskKind::Seal, large, work, cb);
+    }
+
+    /// The reverse of `seal`. Calls back with the plaintext as `Js::Bytes`,
+    /// or a `Js::Error` of kind `InvalidData` if the ciphertext, tag or
+    /// `aad` don't match.
+    pub fn open(
+        key: impl Into<Vec<u8>>,
+        nonce: impl Into<Vec<u8>>,
+        aad: impl Into<Vec<u8>>,
+        sealed: impl Into<Vec<u8>>,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let (key, nonce, aad, sealed) = (key.into(), nonce.into(), aad.into(), sealed.into());
+        let large = sealed.len() >= AEAD_POOL_THRESHOLD;
+        let work = move || aead::open(&key, &nonce, &aad, &sealed);
+        Crypto::run_aead(ThreadPoolTaskKind::Unseal, large, work, cb);
+    }
+
+    pub fn seal_sync(
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        aead::seal(key, nonce, aad, plaintext)
+    }
+
+    pub fn open_sync(key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        aead::open(key, nonce, aad, sealed)
+    }
+
+    /// Compares in time that only depends on the length, so a signature
+    /// check doesn't leak how many leading bytes were right.
+    pub fn timing_safe_equal(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
+    /// Runs `work` on the pool if `large`, otherwise right away with the
+    /// callback as a microtask, so it never runs before `seal` returns.
+    fn run_aead(
+        kind: ThreadPoolTaskKind,
+        large: bool,
+        work: impl Fn() -> io::Result<Vec<u8>> + Send + 'static,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let work = move || match work() {
+            Ok(bytes) => Js::Bytes(bytes),
+            Err(e) => Js::Error(e),
+        };
+        if large {
+            let rt = runtime();
+            rt.register_event_threadpool(work, kind, cb);
+        } else {
+            let res = work();
+            queue_microtask(move || cb(res));
+        }
+    }
+
+    /// Runs `work` on the pool and encodes the bytes it returns.
+    fn run(
+        kind: ThreadPoolTaskKind,
+        work: impl Fn() -> Vec<u8> + Send + 'static,
+        encoding: Encoding,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let work = move || match encoding.encode(&work()) {
+            Ok(digest) => Js::String(digest),
+            Err(e) => Js::Error(e),
+        };
+        let rt = runtime();
+        rt.register_event_threadpool(work, kind, cb);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoding::hex;
+    use crate::fs::Fs;
+    use crate::runtime::Runtime;
+    use std::cell::Cell;
+    use std::fs;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_hash_and_hmac_on_pool() {
+        Runtime::new()
+            .run(|| {
+                Crypto::hash(Algorithm::Sha256, "abc", Encoding::Base64, |res| {
+                    let digest = res.into_string().unwrap();
+                    assert_eq!(digest, "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=");
+                });
+                Crypto::hmac(
+                    Algorithm::Sha1,
+                    "Jefe",
+                    "what do ya want for nothing?",
+                    Encoding::Hex,
+                    |res| {
+                        let signature = res.into_string().unwrap();
+                        assert!(Crypto::timing_safe_equal(
+                            signature.as_bytes(),
+                            b"effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+                        ));
+                    },
+                );
+                Crypto::hash(Algorithm::Md5, "abc", Encoding::Utf8, |res| {
+                    assert!(res.into_error().is_some());
+                });
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_create_hash_and_hash_file() {
+        // Not valid UTF-8, and bigger than one chunk.
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 256) as u8).collect();
+        let file = std::env::temp_dir().join(format!("adven-hash-{}", std::process::id()));
+        fs::write(&file, &data).unwrap();
+
+        let mut hasher = Crypto::create_hash(Algorithm::Sha256);
+        for piece in data.chunks(1000) {
+            hasher.update(piece);
+        }
+        let expected = hasher.digest(Encoding::Hex).unwrap();
+        assert_eq!(expected, hex(&hash::digest(Algorithm::Sha256, &data)));
+
+        let f = file.clone();
+        Runtime::new()
+            .run(move || {
+                Crypto::hash_file(f.clone(), Algorithm::Sha256, Encoding::Hex, move |res| {
+                    assert_eq!(res.into_string().unwrap(), expected);
+                });
+                Crypto::hash_file(
+                    f.with_extension("missing"),
+                    Algorithm::Md5,
+                    Encoding::Hex,
+                    |res| {
+                        assert!(res.into_error().is_some());
+                    },
+                );
+            })
+            .unwrap();
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_key_derivation_on_pool() {
+        Runtime::new()
+            .run(|| {
+                Crypto::pbkdf2("password", "salt", 2, 20, Algorithm::Sha1, |res| {
+                    let key = res.into_bytes().unwrap();
+                    assert_eq!(hex(&key), "ea6c014dc72d6f8ccd1ed92ace1d41f0d8de8957");
+                });
+                let params = ScryptParams {
+                    n: 16,
+                    r: 1,
+                    p: 1,
+                    ..ScryptParams::default()
+                };
+                Crypto::scrypt("", "", 64, params, |res| {
+                    let key = res.into_bytes().unwrap();
+                    assert!(hex(&key).starts_with("77d6576238657b20"));
+                });
+                let params = ScryptParams {
+                    n: 1000,
+                    ..ScryptParams::default()
+                };
+                Crypto::scrypt("", "", 64, params, |res| {
+                    let e = res.into_error().unwrap();
+                    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+                });
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_random_bytes() {
+        let bytes = Crypto::random_bytes_sync(32).unwrap();
+        assert_eq!(bytes.len(), 32);
+        assert_ne!(bytes, Crypto::random_bytes_sync(32).unwrap());
+
+        Runtime::new()
+            .run(|| {
+                Crypto::random_bytes(1 << 20, |res| {
+                    let bytes = res.into_bytes().unwrap();
+                    assert_eq!(bytes.len(), 1 << 20);
+                    assert!(bytes.iter().any(|&b| b != 0));
+                });
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_seal_and_open_file_at_rest() {
+        let key = Crypto::random_bytes_sync(KEY_LEN).unwrap();
+        let nonce = Crypto::random_bytes_sync(NONCE_LEN).unwrap();
+        // Big enough to go to the pool.
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 256) as u8).collect();
+        let file = std::env::temp_dir().join(format!("adven-seal-{}", std::process::id()));
+
+        let f = file.clone();
+        Runtime::new()
+            .run(move || {
+                Crypto::seal(key.clone(), nonce.clone(), "v1", data.clone(), move |res| {
+                    let sealed = res.into_bytes().unwrap();
+                    assert_eq!(sealed.len(), data.len() + TAG_LEN);
+                    let (key, nonce, data, f) =
+                        (key.clone(), nonce.clone(), data.clone(), f.clone());
+                    Fs::write_file(f.clone(), sealed, move |_| {
+                        let (key, nonce, data) = (key.clone(), nonce.clone(), data.clone());
+                        Fs::read_bytes(f.clone(), move |res| {
+                            let sealed = res.into_bytes().unwrap();
+                            let data = data.clone();
+                            Crypto::open(
+                                key.clone(),
+                                nonce.clone(),
+                                "v1",
+                                sealed.clone(),
+                                move |res| {
+                                    assert_eq!(res.into_bytes().unwrap(), data);
+                                },
+                            );
+                            Crypto::open(key.clone(), nonce.clone(), "v2", sealed, |res| {
+                                let e = res.into_error().unwrap();
+                                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                            });
+                        });
+                    });
+                });
+                // Small payloads call back without the pool, but still later.
+                let sealed = Crypto::seal_sync(&[7; KEY_LEN], &[0; NONCE_LEN], b"", b"hi").unwrap();
+                let called = Rc::new(Cell::new(false));
+                let c = called.clone();
+                Crypto::open([7; KEY_LEN], [0; NONCE_LEN], "", sealed, move |res| {
+                    assert_eq!(res.into_bytes().unwrap(), b"hi");
+                    c.set(true);
+                });
+                assert!(!called.get());
+                Crypto::seal([7; 16], [0; NONCE_LEN], "", "hi", |res| {
+                    let e = res.into_error().unwrap();
+                    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+                });
+            })
+            .unwrap();
+        fs::remove_file(&file).unwrap();
+    }
 }
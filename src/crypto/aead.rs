@@ -0,0 +1,346 @@
+//! ChaCha20-Poly1305 authenticated encryption (RFC 8439).
+use std::convert::TryFrom;
+use std::io;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+/// The block counter is 32 bits and starts at 1, which is enough for this
+/// much. Going on would reuse the keystream.
+pub const MAX_PLAINTEXT_LEN: u64 = ((1 << 32) - 1) * 64;
+
+/// Encrypts `plaintext` and returns the ciphertext followed by the tag.
+pub fn seal(key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let (key, nonce) = parse(key, nonce)?;
+    check_len(plaintext.len())?;
+    let mut sealed = plaintext.to_vec();
+    chacha20_xor(&key, 1, &nonce, &mut sealed);
+    let tag = tag(&key, &nonce, aad, &sealed);
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+
+/// Checks the tag at the end of `sealed` and decrypts the rest. Fails with
+/// `InvalidData` if anything, including `aad`, was tampered with.
+pub fn open(key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+    let (key, nonce) = parse(key, nonce)?;
+    if sealed.len() < TAG_LEN {
+        return Err(unauthenticated());
+    }
+    let (ciphertext, expected) = sealed.split_at(sealed.len() - TAG_LEN);
+    check_len(ciphertext.len())?;
+    let tag = tag(&key, &nonce, aad, ciphertext);
+    // Constant time, so a forger can't learn the tag a byte at a time.
+    if tag
+        .iter()
+        .zip(expected)
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        != 0
+    {
+        return Err(unauthenticated());
+    }
+    let mut plaintext = ciphertext.to_vec();
+    chacha20_xor(&key, 1, &nonce, &mut plaintext);
+    Ok(plaintext)
+}
+
+fn parse(key: &[u8], nonce: &[u8]) -> io::Result<([u32; 8], [u32; 3])> {
+    if key.len() != KEY_LEN {
+        return Err(invalid("key must be 32 bytes"));
+    }
+    if nonce.len() != NONCE_LEN {
+        return Err(invalid("nonce must be 12 bytes"));
+    }
+    let mut k = [0; 8];
+    k.iter_mut()
+        .zip(key.chunks_exact(4))
+        .for_each(|(k, b)| *k = le32(b));
+    let mut n = [0; 3];
+    n.iter_mut()
+        .zip(nonce.chunks_exact(4))
+        .for_each(|(n, b)| *n = le32(b));
+    Ok((k, n))
+}
+
+/// Poly1305 over `aad` and `ciphertext`, each padded to 16 bytes, then
+/// both lengths. The one-time key is the first 32 bytes of block 0.
+fn tag(key: &[u32; 8], nonce: &[u32; 3], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_LEN] {
+    let block = chacha20_block(key, 0, nonce);
+    let mut poly = Poly1305::new(&block[..32]);
+    poly.update_padded(aad);
+    poly.update_padded(ciphertext);
+    let mut lengths = [0; 16];
+    lengths[..8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+    lengths[8..].copy_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly.update_padded(&lengths);
+    poly.finish()
+}
+
+fn check_len(len: usize) -> io::Result<()> {
+    if len as u64 > MAX_PLAINTEXT_LEN {
+        return Err(invalid("message too long for one nonce"));
+    }
+    Ok(())
+}
+
+fn chacha20_xor(key: &[u32; 8], counter: u32, nonce: &[u32; 3], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let counter = u32::try_from(i)
+            .ok()
+            .and_then(|i| counter.checked_add(i))
+            .expect("the block counter ran out, see `check_len`");
+        let stream = chacha20_block(key, counter, nonce);
+        chunk.iter_mut().zip(&stream).for_each(|(b, s)| *b ^= s);
+    }
+}
+
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    // "expand 32-byte k"
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    state[4..12].copy_from_slice(key);
+    state[12] = counter;
+    state[13..].copy_from_slice(nonce);
+
+    let mut x = state;
+    let quarter = |x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize| {
+        x[a] = x[a].wrapping_add(x[b]);
+        x[d] = (x[d] ^ x[a]).rotate_left(16);
+        x[c] = x[c].wrapping_add(x[d]);
+        x[b] = (x[b] ^ x[c]).rotate_left(12);
+        x[a] = x[a].wrapping_add(x[b]);
+        x[d] = (x[d] ^ x[a]).rotate_left(8);
+        x[c] = x[c].wrapping_add(x[d]);
+        x[b] = (x[b] ^ x[c]).rotate_left(7);
+    };
+    for _ in 0..10 {
+        // Columns, then diagonals.
+        quarter(&mut x, 0, 4, 8, 12);
+        quarter(&mut x, 1, 5, 9, 13);
+        quarter(&mut x, 2, 6, 10, 14);
+        quarter(&mut x, 3, 7, 11, 15);
+        quarter(&mut x, 0, 5, 10, 15);
+        quarter(&mut x, 1, 6, 11, 12);
+        quarter(&mut x, 2, 7, 8, 13);
+        quarter(&mut x, 3, 4, 9, 14);
+    }
+
+    let mut out = [0; 64];
+    for ((out, x), s) in out.chunks_exact_mut(4).zip(&x).zip(&state) {
+        out.copy_from_slice(&x.wrapping_add(*s).to_le_bytes());
+    }
+    out
+}
+
+/// Poly1305 with the accumulator and `r` in five 26 bit limbs, so the
+/// products fit in a `u64`.
+struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+}
+
+impl Poly1305 {
+    fn new(key: &[u8]) -> Self {
+        // Clamping is folded into the masks.
+        let r = [
+            le32(&key[0..]) & 0x3ff_ffff,
+            (le32(&key[3..]) >> 2) & 0x3ff_ff03,
+            (le32(&key[6..]) >> 4) & 0x3ff_c0ff,
+            (le32(&key[9..]) >> 6) & 0x3f0_3fff,
+            (le32(&key[12..]) >> 8) & 0x00f_ffff,
+        ];
+        let mut pad = [0; 4];
+        pad.iter_mut()
+            .zip(key[16..32].chunks_exact(4))
+            .for_each(|(p, b)| *p = le32(b));
+        Poly1305 { r, h: [0; 5], pad }
+    }
+
+    /// Feeds `data` zero padded to a multiple of 16 bytes, as the AEAD
+    /// construction does.
+    fn update_padded(&mut self, data: &[u8]) {
+        let mut chunks = data.chunks_exact(16);
+        for block in &mut chunks {
+            self.block(block, 1 << 24);
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let mut block = [0; 16];
+            block[..rest.len()].copy_from_slice(rest);
+            self.block(&block, 1 << 24);
+        }
+    }
+
+    /// `hibit` is the 2^128 bit, set for every block except a short final
+    /// one, which carries its own 1 byte instead.
+    fn block(&mut self, m: &[u8], hibit: u32) {
+        let [r0, r1, r2, r3, r4] = self.r.map(u64::from);
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let h = &mut self.h;
+        h[0] += le32(&m[0..]) & 0x3ff_ffff;
+        h[1] += (le32(&m[3..]) >> 2) & 0x3ff_ffff;
+        h[2] += (le32(&m[6..]) >> 4) & 0x3ff_ffff;
+        h[3] += (le32(&m[9..]) >> 6) & 0x3ff_ffff;
+        h[4] += (le32(&m[12..]) >> 8) | hibit;
+        let [h0, h1, h2, h3, h4] = h.map(u64::from);
+
+        let mut d = [
+            h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1,
+            h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2,
+            h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3,
+            h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4,
+            h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0,
+        ];
+        for i in 0..4 {
+            d[i + 1] += d[i] >> 26;
+            h[i] = d[i] as u32 & 0x3ff_ffff;
+        }
+        h[4] = d[4] as u32 & 0x3ff_ffff;
+        h[0] += (d[4] >> 26) as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= 0x3ff_ffff;
+    }
+
+    fn finish(mut self) -> [u8; TAG_LEN] {
+        let h = &mut self.h;
+        for i in 1..4 {
+            h[i + 1] += h[i] >> 26;
+            h[i] &= 0x3ff_ffff;
+        }
+        h[0] += (h[4] >> 26) * 5;
+        h[4] &= 0x3ff_ffff;
+        h[1] += h[0] >> 26;
+        h[0] &= 0x3ff_ffff;
+
+        // g = h + 5 - 2^130, which is h mod p if it didn't go negative.
+        let mut g = [0u32; 5];
+        let mut carry = 5;
+        for i in 0..4 {
+            g[i] = h[i] + carry;
+            carry = g[i] >> 26;
+            g[i] &= 0x3ff_ffff;
+        }
+        g[4] = (h[4] + carry).wrapping_sub(1 << 26);
+        let use_g = (g[4] >> 31).wrapping_sub(1);
+        for i in 0..5 {
+            h[i] = (h[i] & !use_g) | (g[i] & use_g);
+        }
+
+        let words = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut out = [0; TAG_LEN];
+        let mut f = 0u64;
+        for ((out, word), pad) in out.chunks_exact_mut(4).zip(&words).zip(&self.pad) {
+            f = u64::from(*word) + u64::from(*pad) + (f >> 32);
+            out.copy_from_slice(&(f as u32).to_le_bytes());
+        }
+        out
+    }
+}
+
+fn le32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn unauthenticated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "unable to authenticate data")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoding::hex;
+
+    const SUNSCREEN: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+        only one tip for the future, sunscreen would be it.";
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// RFC 8439, section 2.4.2.
+    #[test]
+    fn test_chacha20_vector() {
+        let key: Vec<u8> = (0..32).collect();
+        let (key, nonce) = parse(&key, &unhex("000000000000004a00000000")).unwrap();
+        let mut data = SUNSCREEN.to_vec();
+        chacha20_xor(&key, 1, &nonce, &mut data);
+        assert_eq!(
+            hex(&data),
+            "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b\
+             f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8\
+             07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736\
+             5af90bbf74a35be6b40b8eedf2785e42874d"
+        );
+    }
+
+    /// RFC 8439, section 2.5.2. The message isn't a multiple of 16 bytes,
+    /// so the last block is padded the plain Poly1305 way.
+    #[test]
+    fn test_poly1305_vector() {
+        let key = unhex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+        let msg = b"Cryptographic Forum Research Group";
+        let mut poly = Poly1305::new(&key);
+        poly.block(&msg[..16], 1 << 24);
+        poly.block(&msg[16..32], 1 << 24);
+        let mut last = [0; 16];
+        last[..2].copy_from_slice(&msg[32..]);
+        last[2] = 1;
+        poly.block(&last, 0);
+        assert_eq!(hex(&poly.finish()), "a8061dc1305136c6c22b8baf0c0127a9");
+    }
+
+    /// RFC 8439, section 2.8.2.
+    #[test]
+    fn test_seal_and_open_vector() {
+        let key: Vec<u8> = (0x80..0xa0).collect();
+        let nonce = unhex("070000004041424344454647");
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let sealed = seal(&key, &nonce, &aad, SUNSCREEN).unwrap();
+        assert_eq!(
+            hex(&sealed),
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+             3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+             92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+             3ff4def08e4b7a9de576d26586cec64b6116\
+             1ae10b594f09e26a7e902ecbd0600691"
+        );
+        assert_eq!(open(&key, &nonce, &aad, &sealed).unwrap(), SUNSCREEN);
+
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        let e = open(&key, &nonce, &aad, &tampered).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(open(&key, &nonce, b"other aad", &sealed).is_err());
+        assert!(open(&key, &nonce, &aad, &sealed[..TAG_LEN - 1]).is_err());
+
+        let e = seal(&key[1..], &nonce, &aad, SUNSCREEN).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(seal(&key, &nonce[1..], &aad, SUNSCREEN).is_err());
+    }
+
+    #[test]
+    fn test_max_len() {
+        assert!(check_len(MAX_PLAINTEXT_LEN as usize).is_ok());
+        let e = check_len(MAX_PLAINTEXT_LEN as usize + 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        // The last block the counter allows is still fine.
+        let (key, nonce) = parse(&[0; 32], &[0; 12]).unwrap();
+        let mut data = [0; 64];
+        chacha20_xor(&key, u32::MAX, &nonce, &mut data);
+        assert_eq!(data, chacha20_block(&key, u32::MAX, &nonce));
+    }
+}
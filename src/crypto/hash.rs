@@ -0,0 +1,415 @@
+//! MD5 (RFC 1321), SHA-1 and SHA-256 (FIPS 180-4), and HMAC (RFC 2104) on
+//! top of them. All three work on 64 byte blocks and share the padding.
+use crate::encoding::Encoding;
+use std::io;
+
+/// A digest algorithm. MD5 and SHA-1 are broken for signatures but still
+/// fine for cache keys and checksums.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl Algorithm {
+    /// Length of the digest in bytes.
+    pub fn output_len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha256 => 32,
+        }
+    }
+}
+
+const BLOCK_LEN: usize = 64;
+
+/// Computes a digest incrementally, for data that arrives in pieces or is
+/// too big to hold in memory at once. Created with `Crypto::create_hash`.
+#[derive(Clone)]
+pub struct Hasher {
+    algorithm: Algorithm,
+    state: [u32; 8],
+    buffer: [u8; BLOCK_LEN],
+    buffered: usize,
+    /// Total bytes hashed so far.
+    len: u64,
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        let state = match algorithm {
+            Algorithm::Md5 => [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0, 0, 0, 0],
+            Algorithm::Sha1 => [
+                0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0, 0, 0, 0,
+            ],
+            Algorithm::Sha256 => [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+        };
+        Hasher {
+            algorithm,
+            state,
+            buffer: [0; BLOCK_LEN],
+            buffered: 0,
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        let mut data = data.as_ref();
+        self.len += data.len() as u64;
+
+        if self.buffered > 0 {
+            let n = data.len().min(BLOCK_LEN - self.buffered);
+            self.buffer[self.buffered..self.buffered + n].copy_from_slice(&data[..n]);
+            self.buffered += n;
+            data = &data[n..];
+            if self.buffered < BLOCK_LEN {
+                return;
+            }
+            let block = self.buffer;
+            self.compress(&block);
+            self.buffered = 0;
+        }
+
+        let mut blocks = data.chunks_exact(BLOCK_LEN);
+        for block in &mut blocks {
+            let mut copy = [0; BLOCK_LEN];
+            copy.copy_from_slice(block);
+            self.compress(&copy);
+        }
+        let rest = blocks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    /// The digest of everything passed to `update`, in `encoding`.
+    pub fn digest(self, encoding: Encoding) -> io::Result<String> {
+        encoding.encode(&self.finish())
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let bits = self.len.wrapping_mul(8);
+        let mut padding = vec![0x80];
+        let padded = (self.buffered + 1) % BLOCK_LEN;
+        let zeros = (BLOCK_LEN + 56 - padded) % BLOCK_LEN;
+        padding.resize(1 + zeros, 0);
+        match self.algorithm {
+            Algorithm::Md5 => padding.extend_from_slice(&bits.to_le_bytes()),
+            _ => padding.extend_from_slice(&bits.to_be_bytes()),
+        }
+        self.update(padding);
+        debug_assert_eq!(self.buffered, 0);
+
+        let words = self.algorithm.output_len() / 4;
+        self.state[..words]
+            .iter()
+            .flat_map(|word| match self.algorithm {
+                Algorithm::Md5 => word.to_le_bytes(),
+                _ => word.to_be_bytes(),
+            })
+            .collect()
+    }
+
+    fn compress(&mut self, block: &[u8; BLOCK_LEN]) {
+        match self.algorithm {
+            Algorithm::Md5 => md5_compress(&mut self.state, block),
+            Algorithm::Sha1 => sha1_compress(&mut self.state, block),
+            Algorithm::Sha256 => sha256_compress(&mut self.state, block),
+        }
+    }
+}
+
+pub fn digest(algorithm: Algorithm, data: &[u8]) -> Vec<u8> {
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update(data);
+    hasher.finish()
+}
+
+pub fn hmac(algorithm: Algorithm, key: &[u8], data: &[u8]) -> Vec<u8> {
+    Hmac::new(algorithm, key).mac(data)
+}
+
+/// An HMAC key with the padded key blocks already hashed, for when the
+/// same key signs many messages, as in PBKDF2.
+#[derive(Clone)]
+pub struct Hmac {
+    inner: Hasher,
+    outer: Hasher,
+}
+
+impl Hmac {
+    pub fn new(algorithm: Algorithm, key: &[u8]) -> Self {
+        let mut block_key = [0u8; BLOCK_LEN];
+        if key.len() > BLOCK_LEN {
+            let key = digest(algorithm, key);
+            block_key[..key.len()].copy_from_slice(&key);
+        } else {
+            block_key[..key.len()].copy_from_slice(key);
+        }
+
+        let pad = |byte: u8| block_key.iter().map(|k| k ^ byte).collect::<Vec<_>>();
+        let mut inner = Hasher::new(algorithm);
+        inner.update(pad(0x36));
+        let mut outer = Hasher::new(algorithm);
+        outer.update(pad(0x5c));
+        Hmac { inner, outer }
+    }
+
+    pub fn mac(&self, data: &[u8]) -> Vec<u8> {
+        let mut inner = self.inner.clone();
+        inner.update(data);
+        let mut outer = self.outer.clone();
+        outer.update(inner.finish());
+        outer.finish()
+    }
+}
+
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+fn md5_compress(state: &mut [u32; 8], block: &[u8; BLOCK_LEN]) {
+    let mut m = [0u32; 16];
+    for (i, word) in m.iter_mut().enumerate() {
+        *word = u32::from_le_bytes([
+            block[i * 4],
+            block[i * 4 + 1],
+            block[i * 4 + 2],
+            block[i * 4 + 3],
+        ]);
+    }
+
+    let [mut a, mut b, mut c, mut d] = [state[0], state[1], state[2], state[3]];
+    for i in 0..64 {
+        let (f, g) = match i / 16 {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+            2 => (b ^ c ^ d, (3 * i + 5) % 16),
+            _ => (c ^ (b | !d), (7 * i) % 16),
+        };
+        let shift = MD5_SHIFTS[(i / 16) * 4 + i % 4];
+        let f = f.wrapping_add(a).wrapping_add(MD5_K[i]).wrapping_add(m[g]);
+        a = d;
+        d = c;
+        c = b;
+        b = b.wrapping_add(f.rotate_left(shift));
+    }
+
+    for (s, v) in state.iter_mut().zip(&[a, b, c, d]) {
+        *s = s.wrapping_add(*v);
+    }
+}
+
+fn sha1_compress(state: &mut [u32; 8], block: &[u8; BLOCK_LEN]) {
+    let mut w = [0u32; 80];
+    for i in 0..16 {
+        w[i] = u32::from_be_bytes([
+            block[i * 4],
+            block[i * 4 + 1],
+            block[i * 4 + 2],
+            block[i * 4 + 3],
+        ]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = [state[0], state[1], state[2], state[3], state[4]];
+    for (i, &word) in w.iter().enumerate() {
+        let (f, k) = match i / 20 {
+            0 => ((b & c) | (!b & d), 0x5a827999),
+            1 => (b ^ c ^ d, 0x6ed9eba1),
+            2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6),
+        };
+        let temp = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(word);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = temp;
+    }
+
+    for (s, v) in state.iter_mut().zip(&[a, b, c, d, e]) {
+        *s = s.wrapping_add(*v);
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+fn sha256_compress(state: &mut [u32; 8], block: &[u8; BLOCK_LEN]) {
+    let mut w = [0u32; 64];
+    for i in 0..16 {
+        w[i] = u32::from_be_bytes([
+            block[i * 4],
+            block[i * 4 + 1],
+            block[i * 4 + 2],
+            block[i * 4 + 3],
+        ]);
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let mut v = *state;
+    for i in 0..64 {
+        let [a, b, c, d, e, f, g, h] = v;
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let temp1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(SHA256_K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let temp2 = s0.wrapping_add(maj);
+        v = [
+            temp1.wrapping_add(temp2),
+            a,
+            b,
+            c,
+            d.wrapping_add(temp1),
+            e,
+            f,
+            g,
+        ];
+    }
+
+    for (s, v) in state.iter_mut().zip(&v) {
+        *s = s.wrapping_add(*v);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoding::hex;
+
+    const QUICK_FOX: &[u8] = b"The quick brown fox jumps over the lazy dog";
+    const TWO_BLOCKS: &[u8] = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+
+    #[test]
+    fn test_md5_vectors() {
+        let md5 = |data: &[u8]| hex(&digest(Algorithm::Md5, data));
+        assert_eq!(md5(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(md5(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(md5(QUICK_FOX), "9e107d9d372bb6826bd81d3542a419d6");
+    }
+
+    #[test]
+    fn test_sha1_vectors() {
+        let sha1 = |data: &[u8]| hex(&digest(Algorithm::Sha1, data));
+        assert_eq!(sha1(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(sha1(TWO_BLOCKS), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        assert_eq!(
+            sha1(&[b'a'; 1_000_000]),
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f"
+        );
+    }
+
+    #[test]
+    fn test_sha256_vectors() {
+        let sha256 = |data: &[u8]| hex(&digest(Algorithm::Sha256, data));
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(TWO_BLOCKS),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn test_update_in_pieces() {
+        for &algorithm in &[Algorithm::Md5, Algorithm::Sha1, Algorithm::Sha256] {
+            let mut hasher = Hasher::new(algorithm);
+            for piece in QUICK_FOX.chunks(7) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.finish(), digest(algorithm, QUICK_FOX));
+        }
+    }
+
+    /// RFC 2202 and RFC 4231.
+    #[test]
+    fn test_hmac_vectors() {
+        let hmac = |algorithm, key: &[u8], data: &[u8]| hex(&hmac(algorithm, key, data));
+        let jefe = b"what do ya want for nothing?";
+
+        assert_eq!(
+            hmac(Algorithm::Md5, &[0x0b; 16], b"Hi There"),
+            "9294727a3638bb1c13f48ef8158bfc9d"
+        );
+        assert_eq!(
+            hmac(Algorithm::Md5, b"Jefe", jefe),
+            "750c783e6ab0b503eaa86e310a5db738"
+        );
+        assert_eq!(
+            hmac(Algorithm::Sha1, &[0x0b; 20], b"Hi There"),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        assert_eq!(
+            hmac(Algorithm::Sha1, b"Jefe", jefe),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        assert_eq!(
+            hmac(Algorithm::Sha256, &[0x0b; 20], b"Hi There"),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            hmac(Algorithm::Sha256, b"Jefe", jefe),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hmac(
+                Algorithm::Sha256,
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+}
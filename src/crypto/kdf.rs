@@ -0,0 +1,228 @@
+//! Password based key derivation: PBKDF2 (RFC 8018) and scrypt (RFC 7914).
+use super::hash::{Algorithm, Hmac};
+use std::io;
+
+pub fn pbkdf2(
+    algorithm: Algorithm,
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+    key_len: usize,
+) -> io::Result<Vec<u8>> {
+    if iterations == 0 {
+        return Err(invalid("iterations must be at least 1"));
+    }
+
+    let prf = Hmac::new(algorithm, password);
+    let mut key = Vec::with_capacity(key_len);
+    let mut block_index = 1u32;
+    while key.len() < key_len {
+        let mut input = salt.to_vec();
+        input.extend_from_slice(&block_index.to_be_bytes());
+        let mut u = prf.mac(&input);
+        let mut block = u.clone();
+        for _ in 1..iterations {
+            u = prf.mac(&u);
+            block.iter_mut().zip(&u).for_each(|(b, u)| *b ^= u);
+        }
+
+        let n = block.len().min(key_len - key.len());
+        key.extend_from_slice(&block[..n]);
+        block_index += 1;
+    }
+    Ok(key)
+}
+
+/// The cost parameters of scrypt. The defaults are the same as Node's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScryptParams {
+    /// CPU and memory cost. Has to be a power of two.
+    pub n: u64,
+    /// Block size.
+    pub r: u32,
+    /// Parallelization.
+    pub p: u32,
+    /// Fails instead of allocating more than this many bytes (about
+    /// `128 * r * (n + p)`).
+    pub max_mem: usize,
+}
+
+impl Default for ScryptParams {
+    fn default() -> Self {
+        ScryptParams {
+            n: 16384,
+            r: 8,
+            p: 1,
+            max_mem: 32 * 1024 * 1024,
+        }
+    }
+}
+
+pub fn scrypt(
+    password: &[u8],
+    salt: &[u8],
+    params: ScryptParams,
+    key_len: usize,
+) -> io::Result<Vec<u8>> {
+    let ScryptParams { n, r, p, max_mem } = params;
+    if n < 2 || !n.is_power_of_two() {
+        return Err(invalid("n must be a power of two greater than 1"));
+    }
+    if r == 0 || p == 0 || u64::from(r) * u64::from(p) >= 1 << 30 {
+        return Err(invalid("r and p must be positive and r * p below 2^30"));
+    }
+    let block_len = 128 * r as usize;
+    // `v`, the `p` blocks and the scratch space of `ro_mix`, which is how
+    // OpenSSL counts it.
+    (n as usize)
+        .checked_add(2 + p as usize)
+        .and_then(|blocks| blocks.checked_mul(block_len))
+        .filter(|&mem| mem <= max_mem)
+        .ok_or_else(|| invalid("memory limit exceeded"))?;
+
+    let mut blocks = pbkdf2(Algorithm::Sha256, password, salt, 1, p as usize * block_len)?;
+    let mut v = vec![0u32; n as usize * block_len / 4];
+    for block in blocks.chunks_mut(block_len) {
+        ro_mix(block, n as usize, r as usize, &mut v);
+    }
+    pbkdf2(Algorithm::Sha256, password, &blocks, 1, key_len)
+}
+
+fn ro_mix(block: &mut [u8], n: usize, r: usize, v: &mut [u32]) {
+    let words = 32 * r;
+    let mut x: Vec<u32> = block
+        .chunks_exact(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect();
+    let mut scratch = vec![0u32; words];
+
+    for i in 0..n {
+        v[i * words..(i + 1) * words].copy_from_slice(&x);
+        block_mix(&mut x, &mut scratch, r);
+    }
+    for _ in 0..n {
+        // Integerify: the first word of the last 64 byte block, mod n.
+        let j = x[(2 * r - 1) * 16] as usize & (n - 1);
+        x.iter_mut()
+            .zip(&v[j * words..(j + 1) * words])
+            .for_each(|(x, v)| *x ^= v);
+        block_mix(&mut x, &mut scratch, r);
+    }
+
+    for (bytes, word) in block.chunks_exact_mut(4).zip(&x) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+/// BlockMix with Salsa20/8. The even output blocks go to the front and the
+/// odd ones to the back.
+fn block_mix(b: &mut [u32], scratch: &mut [u32], r: usize) {
+    let mut x = [0u32; 16];
+    x.copy_from_slice(&b[(2 * r - 1) * 16..]);
+
+    for i in 0..2 * r {
+        x.iter_mut()
+            .zip(&b[i * 16..(i + 1) * 16])
+            .for_each(|(x, b)| *x ^= b);
+        salsa20_8(&mut x);
+        let out = (i / 2 + (i % 2) * r) * 16;
+        scratch[out..out + 16].copy_from_slice(&x);
+    }
+    b.copy_from_slice(scratch);
+}
+
+fn salsa20_8(block: &mut [u32; 16]) {
+    let mut x = *block;
+    let quarter = |x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize| {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    };
+    for _ in 0..4 {
+        // Columns, then rows.
+        quarter(&mut x, 0, 4, 8, 12);
+        quarter(&mut x, 5, 9, 13, 1);
+        quarter(&mut x, 10, 14, 2, 6);
+        quarter(&mut x, 15, 3, 7, 11);
+        quarter(&mut x, 0, 1, 2, 3);
+        quarter(&mut x, 5, 6, 7, 4);
+        quarter(&mut x, 10, 11, 8, 9);
+        quarter(&mut x, 15, 12, 13, 14);
+    }
+    block
+        .iter_mut()
+        .zip(&x)
+        .for_each(|(b, x)| *b = b.wrapping_add(*x));
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoding::hex;
+
+    /// RFC 6070.
+    #[test]
+    fn test_pbkdf2_sha1_vectors() {
+        let pbkdf2 = |password: &[u8], salt: &[u8], iterations, len| {
+            hex(&pbkdf2(Algorithm::Sha1, password, salt, iterations, len).unwrap())
+        };
+        assert_eq!(
+            pbkdf2(b"password", b"salt", 1, 20),
+            "0c60c80f961f0e71f3a9b524af6012062fe037a6"
+        );
+        assert_eq!(
+            pbkdf2(b"password", b"salt", 4096, 20),
+            "4b007901b765489abead49d926f721d065a429c1"
+        );
+        assert_eq!(
+            pbkdf2(
+                b"passwordPASSWORDpassword",
+                b"saltSALTsaltSALTsaltSALTsaltSALTsalt",
+                4096,
+                25
+            ),
+            "3d2eec4fe41c849b80c8d83662c0e44a8b291a964cf2f07038"
+        );
+    }
+
+    /// RFC 7914, section 11.
+    #[test]
+    fn test_pbkdf2_sha256_vector() {
+        let key = pbkdf2(Algorithm::Sha256, b"passwd", b"salt", 1, 64).unwrap();
+        assert_eq!(
+            hex(&key),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc\
+             49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+        );
+    }
+
+    /// RFC 7914, section 12.
+    #[test]
+    fn test_scrypt_vectors() {
+        let params = |n, r, p| ScryptParams {
+            n,
+            r,
+            p,
+            ..ScryptParams::default()
+        };
+        assert_eq!(
+            hex(&scrypt(b"", b"", params(16, 1, 1), 64).unwrap()),
+            "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442\
+             fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906"
+        );
+        assert_eq!(
+            hex(&scrypt(b"password", b"NaCl", params(1024, 8, 16), 64).unwrap()),
+            "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162\
+             2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"
+        );
+        assert!(scrypt(b"", b"", params(15, 1, 1), 64).is_err());
+        assert!(scrypt(b"", b"", params(1 << 20, 8, 1), 64).is_err());
+        // Little for `v`, but a lot for the blocks.
+        assert!(scrypt(b"", b"", params(16, 8, 40_000), 64).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! Randomness straight from the kernel's CSPRNG, good for keys and tokens.
+use crate::encoding::hex;
+use crate::sys;
+use std::io;
+use std::ops::Range;
+
+pub fn random_bytes(n: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; n];
+    sys::fill_random(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// A version 4 UUID (RFC 4122) like `"1b4e28ba-2fa1-41d2-883f-0016d3cca427"`.
+pub fn random_uuid() -> io::Result<String> {
+    let mut bytes = random_bytes(16)?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = hex(&bytes);
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+/// A uniformly distributed integer in `range`, without modulo bias.
+pub fn random_int(range: Range<u64>) -> io::Result<u64> {
+    if range.start >= range.end {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "range is empty",
+        ));
+    }
+
+    let span = range.end - range.start;
+    // The largest multiple of `span` that fits, so every value is equally
+    // likely once the draws at or above it are thrown away.
+    let limit = u64::MAX - u64::MAX % span;
+    loop {
+        let mut bytes = [0u8; 8];
+        sys::fill_random(&mut bytes)?;
+        let x = u64::from_ne_bytes(bytes);
+        if x < limit {
+            return Ok(range.start + x % span);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_random_uuid_format() {
+        let uuid = random_uuid().unwrap();
+        let parts: Vec<_> = uuid.split('-').map(str::len).collect();
+        assert_eq!(parts, vec![8, 4, 4, 4, 12]);
+        assert_eq!(&uuid[14..15], "4");
+        assert!(matches!(&uuid[19..20], "8" | "9" | "a" | "b"));
+        assert_ne!(uuid, random_uuid().unwrap());
+    }
+
+    #[test]
+    fn test_random_int_stays_in_range() {
+        let mut seen = [false; 6];
+        for _ in 0..1000 {
+            let n = random_int(10..16).unwrap();
+            assert!((10..16).contains(&n));
+            seen[(n - 10) as usize] = true;
+        }
+        assert!(seen.iter().all(|&s| s));
+        assert_eq!(random_int(7..8).unwrap(), 7);
+        assert!(random_int(5..5).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::runtime::Js;
+use std::fmt::Write;
+use std::io;
+
+/// How to turn raw bytes into a `String`, like the `encoding` argument of
+/// Node's `fs.readFile`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Fails with `InvalidData` if the bytes aren't valid UTF-8.
+    Utf8,
+    /// Every byte becomes the char with the same code point.
+    Latin1,
+    /// Lowercase hex.
+    Hex,
+    /// Standard alphabet with padding (RFC 4648).
+    Base64,
+}
+
+impl Encoding {
+    pub fn encode(self, bytes: &[u8]) -> io::Result<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Encoding::Latin1 => Ok(bytes.iter().map(|&b| b as char).collect()),
+            Encoding::Hex => Ok(hex(bytes)),
+            Encoding::Base64 => Ok(base64(bytes)),
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for &b in bytes {
+        out.push(DIGITS[(b >> 4) as usize] as char);
+        out.push(DIGITS[(b & 0xf) as usize] as char);
+    }
+    out
+}
+
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).cloned().unwrap_or(0),
+            chunk.get(2).cloned().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Serializes `value` like `JSON.stringify`. Objects come out with their
+/// keys sorted. `Js::Bytes` become base64 strings, errors their message and
+/// `Js::Undefined` `null`.
+pub fn json(value: &Js) -> String {
+    let mut out = String::new();
+    write_json(&mut out, value);
+    out
+}
+
+fn write_json(out: &mut String, value: &Js) {
+    match value {
+        Js::Undefined => out.push_str("null"),
+        Js::Int(n) => write!(out, "{}", n).unwrap(),
+        Js::String(s) => write_json_string(out, s),
+        Js::Bytes(bytes) => write_json_string(out, &base64(bytes)),
+        Js::Error(e) => write_json_string(out, &e.to_string()),
+        Js::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(out, value);
+            }
+            out.push(']');
+        }
+        Js::Object(fields) => {
+            out.push('{');
+            for (i, (key, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json_string(out, key);
+                out.push(':');
+                write_json(out, value);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_base64_rfc4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, expected) in vectors.iter() {
+            assert_eq!(base64(input.as_bytes()), *expected);
+        }
+    }
+
+    #[test]
+    fn test_encodings() {
+        let bytes = [0x48, 0x69, 0xff];
+        assert_eq!(Encoding::Hex.encode(&bytes).unwrap(), "4869ff");
+        assert_eq!(Encoding::Latin1.encode(&bytes).unwrap(), "Hi\u{ff}");
+        assert_eq!(Encoding::Base64.encode(&bytes).unwrap(), "SGn/");
+
+        let err = Encoding::Utf8.encode(&bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_json() {
+        let mut object = std::collections::BTreeMap::new();
+        object.insert(
+            "name".to_string(),
+            Js::String("a \"b\"\n\u{1}é".to_string()),
+        );
+        object.insert("count".to_string(), Js::Int(3));
+        let list = vec![Js::Undefined, Js::Bytes(b"foo".to_vec()), Js::Array(vec![])];
+        object.insert("list".to_string(), Js::Array(list));
+
+        assert_eq!(
+            json(&Js::Object(object)),
+            r#"{"count":3,"list":[null,"Zm9v",[]],"name":"a \"b\"\n\u0001é"}"#
+        );
+    }
+}
@@ -1,20 +1,742 @@
-use crate::runtime::{Js, ThreadPoolTaskKind, RUNTIME};
-use std::io::Read;
+mod handle;
+mod stream;
+mod uring;
+mod walk;
+mod watch;
+
+pub use handle::FileHandle;
+pub use stream::{ReadStream, WriteStream, HIGH_WATER_MARK};
+pub use walk::WalkOptions;
+pub use watch::Watcher;
+
+use crate::encoding::Encoding;
+use crate::runtime::{runtime, Js, ThreadPoolTaskKind};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
 use std::{fs, thread};
 
+thread_local! {
+    static SIMULATED_LATENCY: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
 pub struct Fs {}
 impl Fs {
-    pub fn read(path: &'static str, cb: impl Fn(Js) + 'static) {
+    /// Makes every file operation started from this thread sleep for
+    /// `latency` on the pool first, to simulate a slow disk.
+    pub fn set_simulated_latency(latency: Option<Duration>) {
+        SIMULATED_LATENCY.with(|l| l.set(latency));
+    }
+
+    /// File operations go through io_uring where the kernel supports it,
+    /// and through the thread pool otherwise. This turns io_uring off (or
+    /// back on) for operations started from this thread.
+    pub fn set_io_uring(enabled: bool) {
+        uring::set_enabled(enabled);
+    }
+
+    /// Reads the file as UTF-8.
+    pub fn read(path: impl AsRef<Path>, cb: impl Fn(Js) + 'static) {
+        Fs::read_with_encoding(path, Encoding::Utf8, cb);
+    }
+
+    pub fn read_with_encoding(
+        path: impl AsRef<Path>,
+        encoding: Encoding,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        if let Some(path) = uring::c_path(path.as_ref()) {
+            return uring::read_file(path, move |res| {
+                let res = res.and_then(|data| encoding.encode(&data));
+                cb(res.map(Js::String).unwrap_or_else(Js::Error))
+            });
+        }
+
+        let path = path.as_ref().to_path_buf();
+        let work = move || Ok(Js::String(encoding.encode(&fs::read(&path)?)?));
+        Fs::run(ThreadPoolTaskKind::FileRead, work, cb);
+    }
+
+    /// Calls back with the raw contents as `Js::Bytes`.
+    pub fn read_bytes(path: impl AsRef<Path>, cb: impl Fn(Js) + 'static) {
+        if let Some(path) = uring::c_path(path.as_ref()) {
+            return uring::read_file(path, move |res| {
+                cb(res.map(Js::Bytes).unwrap_or_else(Js::Error))
+            });
+        }
+
+        let path = path.as_ref().to_path_buf();
+        let work = move || fs::read(&path).map(Js::Bytes);
+        Fs::run(ThreadPoolTaskKind::FileRead, work, cb);
+    }
+
+    /// Opens `path` with `flags` and calls back with the id of a
+    /// `FileHandle` as `Js::Int`. The file stays open until the handle is
+    /// closed.
+    pub fn open(path: impl AsRef<Path>, flags: &fs::OpenOptions, cb: impl Fn(Js) + 'static) {
+        FileHandle::open(path.as_ref().to_path_buf(), flags.clone(), cb);
+    }
+
+    /// Streams the file in chunks of up to `chunk_size` bytes. See
+    /// `ReadStream`.
+    pub fn create_read_stream(path: impl AsRef<Path>, chunk_size: usize) -> ReadStream {
+        ReadStream::new(path.as_ref().to_path_buf(), chunk_size)
+    }
+
+    /// Creates or truncates the file at `path`. See `WriteStream`.
+    pub fn create_write_stream(path: impl AsRef<Path>) -> WriteStream {
+        WriteStream::new(path.as_ref().to_path_buf())
+    }
+
+    /// Calls `cb` for every change to `path` until the returned watcher is
+    /// closed. With `recursive` subdirectories are watched too, including
+    /// ones created later. See `Watcher`.
+    pub fn watch(
+        path: impl AsRef<Path>,
+        recursive: bool,
+        cb: impl Fn(Js) + 'static,
+    ) -> io::Result<Watcher> {
+        Watcher::new(path.as_ref().to_path_buf(), recursive, cb)
+    }
+
+    /// Creates or truncates the file at `path`.
+    pub fn write_file(path: impl AsRef<Path>, data: impl Into<Vec<u8>>, cb: impl Fn(Js) + 'static) {
+        let data = data.into();
+        if let Some(path) = uring::c_path(path.as_ref()) {
+            return uring::write_file(path, data, false, move |res| {
+                cb(res.map(|_| Js::Undefined).unwrap_or_else(Js::Error))
+            });
+        }
+
+        let path = path.as_ref().to_path_buf();
+        let work = move || fs::write(&path, &data).map(|_| Js::Undefined);
+        Fs::run(ThreadPoolTaskKind::FileWrite, work, cb);
+    }
+
+    /// Creates the file at `path` if it doesn't exist yet.
+    pub fn append_file(
+        path: impl AsRef<Path>,
+        data: impl Into<Vec<u8>>,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let data = data.into();
+        if let Some(path) = uring::c_path(path.as_ref()) {
+            return uring::write_file(path, data, true, move |res| {
+                cb(res.map(|_| Js::Undefined).unwrap_or_else(Js::Error))
+            });
+        }
+
+        let path = path.as_ref().to_path_buf();
+        let work = move || {
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?
+                .write_all(&data)?;
+            Ok(Js::Undefined)
+        };
+        Fs::run(ThreadPoolTaskKind::FileAppend, work, cb);
+    }
+
+    /// Follows symlinks. Calls back with `{ size, mtime, kind }` where `mtime`
+    /// is in milliseconds since the epoch and `kind` is one of `"file"`,
+    /// `"directory"` or `"other"`.
+    pub fn stat(path: impl AsRef<Path>, cb: impl Fn(Js) + 'static) {
+        let path = path.as_ref().to_path_buf();
+        let work = move || {
+            let meta = fs::metadata(&path)?;
+            let mtime = meta
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as usize)
+                .unwrap_or(0);
+            let kind = if meta.is_file() {
+                "file"
+            } else if meta.is_dir() {
+                "directory"
+            } else {
+                "other"
+            };
+
+            let mut stat = BTreeMap::new();
+            stat.insert("size".to_string(), Js::Int(meta.len() as usize));
+            stat.insert("mtime".to_string(), Js::Int(mtime));
+            stat.insert("kind".to_string(), Js::String(kind.to_string()));
+            Ok(Js::Object(stat))
+        };
+        Fs::run(ThreadPoolTaskKind::Stat, work, cb);
+    }
+
+    /// Calls back with the sorted names of the entries in `path`.
+    pub fn read_dir(path: impl AsRef<Path>, cb: impl Fn(Js) + 'static) {
+        let path = path.as_ref().to_path_buf();
+        let work = move || {
+            let mut names = fs::read_dir(&path)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<io::Result<Vec<_>>>()?;
+            names.sort();
+            Ok(Js::Array(names.into_iter().map(Js::String).collect()))
+        };
+        Fs::run(ThreadPoolTaskKind::ReadDir, work, cb);
+    }
+
+    /// Walks the tree under `root` on the thread pool, parents before their
+    /// children and siblings sorted by name. Entries reach `cb` as
+    /// `Js::Array`s of at most `options.batch_size` objects
+    /// `{ path, kind }`, followed by `Js::Undefined` once the walk is done.
+    /// A directory that can't be read, or an entry that can't be looked at,
+    /// is skipped and reported as `{ path, kind: "error", error }`; only an
+    /// error reading `root` ends the walk with `Js::Error`.
+    pub fn walk(root: impl AsRef<Path>, options: WalkOptions, cb: impl Fn(Js) + 'static) {
+        walk::walk(root.as_ref().to_path_buf(), options, cb);
+    }
+
+    /// Like `mkdir -p`: creates missing parents and doesn't fail if the
+    /// directory already exists.
+    pub fn mkdir(path: impl AsRef<Path>, cb: impl Fn(Js) + 'static) {
+        let path = path.as_ref().to_path_buf();
+        let work = move || fs::create_dir_all(&path).map(|_| Js::Undefined);
+        Fs::run(ThreadPoolTaskKind::Mkdir, work, cb);
+    }
+
+    /// Removes a file, or a directory with everything in it.
+    pub fn remove(path: impl AsRef<Path>, cb: impl Fn(Js) + 'static) {
+        let path = path.as_ref().to_path_buf();
         let work = move || {
-            thread::sleep(std::time::Duration::from_secs(1));
-            let mut buffer = String::new();
-            fs::File::open(&path)
-                .unwrap()
-                .read_to_string(&mut buffer)
+            if fs::symlink_metadata(&path)?.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+            Ok(Js::Undefined)
+        };
+        Fs::run(ThreadPoolTaskKind::Remove, work, cb);
+    }
+
+    pub fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>, cb: impl Fn(Js) + 'static) {
+        let from = from.as_ref().to_path_buf();
+        let to = to.as_ref().to_path_buf();
+        let work = move || fs::rename(&from, &to).map(|_| Js::Undefined);
+        Fs::run(ThreadPoolTaskKind::Rename, work, cb);
+    }
+
+    pub fn copy_file(from: impl AsRef<Path>, to: impl AsRef<Path>, cb: impl Fn(Js) + 'static) {
+        let from = from.as_ref().to_path_buf();
+        let to = to.as_ref().to_path_buf();
+        let work = move || fs::copy(&from, &to).map(|_| Js::Undefined);
+        Fs::run(ThreadPoolTaskKind::CopyFile, work, cb);
+    }
+
+    /// Runs `work` on the pool. Errors reach `cb` as `Js::Error`.
+    fn run(
+        kind: ThreadPoolTaskKind,
+        work: impl Fn() -> io::Result<Js> + Send + 'static,
+        cb: impl Fn(Js) + 'static,
+    ) {
+        let latency = SIMULATED_LATENCY.with(|l| l.get());
+        let work = move || {
+            if let Some(latency) = latency {
+                thread::sleep(latency);
+            }
+            work().unwrap_or_else(Js::Error)
+        };
+        let rt = runtime();
+        rt.register_event_threadpool(work, kind, cb);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::{set_immediate, set_timeout, Runtime};
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("adven-fs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_write_append_stat() {
+        let dir = temp_dir("write");
+        let file = dir.join("a/b/file.txt");
+
+        let f = file.clone();
+        Runtime::new()
+            .run(move || {
+                let file = f.clone();
+                Fs::mkdir(f.parent().unwrap(), move |_| {
+                    let file = file.clone();
+                    Fs::write_file(file.clone(), "Hello", move |_| {
+                        let file = file.clone();
+                        Fs::append_file(file.clone(), " world!", move |_| {
+                            Fs::stat(file.clone(), |res| {
+                                let stat = res.into_object().unwrap();
+                                assert!(matches!(stat["size"], Js::Int(12)));
+                                assert!(matches!(&stat["kind"], Js::String(k) if k == "file"));
+                            });
+                        });
+                    });
+                });
+            })
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(&file).unwrap(), "Hello world!");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_copy_rename_read_dir() {
+        let dir = temp_dir("copy");
+        fs::write(dir.join("file.txt"), "data").unwrap();
+
+        let d = dir.clone();
+        Runtime::new()
+            .run(move || {
+                let dir = d.clone();
+                Fs::copy_file(d.join("file.txt"), d.join("copy.txt"), move |_| {
+                    let dir = dir.clone();
+                    Fs::rename(dir.join("copy.txt"), dir.join("moved.txt"), move |_| {
+                        Fs::read_dir(dir.clone(), |res| {
+                            let names: Vec<String> = res
+                                .into_array()
+                                .unwrap()
+                                .into_iter()
+                                .map(|name| name.into_string().unwrap())
+                                .collect();
+                            assert_eq!(names, vec!["file.txt", "moved.txt"]);
+                        });
+                    });
+                });
+            })
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("moved.txt")).unwrap(), "data");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_and_errors() {
+        let dir = temp_dir("remove");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+
+        let d = dir.clone();
+        Runtime::new()
+            .run(move || {
+                let dir = d.clone();
+                Fs::remove(d.clone(), move |res| {
+                    assert!(matches!(res, Js::Undefined));
+                    Fs::stat(dir.clone(), |res| {
+                        let err = res.into_error().unwrap();
+                        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+                    });
+                });
+            })
+            .unwrap();
+
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_read_binary_and_dynamic_paths() {
+        let dir = temp_dir("read");
+        let file = dir.join(String::from("binary.bin"));
+        fs::write(&file, [0x48, 0x69, 0xff, 0x00]).unwrap();
+
+        let f = file.clone();
+        Runtime::new()
+            .run(move || {
+                Fs::read_bytes(f.clone(), |res| {
+                    assert_eq!(res.into_bytes().unwrap(), vec![0x48, 0x69, 0xff, 0x00]);
+                });
+                Fs::read_with_encoding(f.clone(), Encoding::Hex, |res| {
+                    assert_eq!(res.into_string().unwrap(), "4869ff00");
+                });
+                Fs::read(f.clone(), |res| {
+                    let err = res.into_error().unwrap();
+                    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                });
+                Fs::read(f.with_extension("missing"), |res| {
+                    let err = res.into_error().unwrap();
+                    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+                });
+            })
+            .unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_more_tasks_than_threads() {
+        let dir = temp_dir("many");
+
+        let d = dir.clone();
+        Runtime::new()
+            .run(move || {
+                for i in 0..16 {
+                    Fs::write_file(d.join(i.to_string()), "x", |res| {
+                        assert!(matches!(res, Js::Undefined));
+                    });
+                }
+            })
+            .unwrap();
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 16);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn write_until_full(stream: WriteStream, written: Rc<Cell<usize>>, total: usize) {
+        while written.get() < total {
+            written.set(written.get() + 1024);
+            if !stream.write(vec![b'x'; 1024]) {
+                return;
+            }
+        }
+        stream.end(|_| ());
+    }
+
+    #[test]
+    fn test_streams_with_backpressure() {
+        let dir = temp_dir("stream");
+        let file = dir.join("big.txt");
+        let total = 10 * HIGH_WATER_MARK;
+        let drains = Rc::new(Cell::new(0));
+
+        let (f, d) = (file.clone(), drains.clone());
+        Runtime::new()
+            .run(move || {
+                let stream = Fs::create_write_stream(&f);
+                let written = Rc::new(Cell::new(0));
+                let (s, w, d) = (stream.clone(), written.clone(), d.clone());
+                stream.on_drain(move |_| {
+                    d.set(d.get() + 1);
+                    write_until_full(s.clone(), w.clone(), total);
+                });
+                write_until_full(stream, written, total);
+            })
+            .unwrap();
+
+        assert!(drains.get() >= 9);
+        assert_eq!(fs::metadata(&file).unwrap().len() as usize, total);
+
+        let received = Rc::new(Cell::new(0));
+        let (f, r) = (file.clone(), received.clone());
+        Runtime::new()
+            .run(move || {
+                let stream = Fs::create_read_stream(&f, 4096);
+                let (s, count) = (stream.clone(), r.clone());
+                stream.on_data(move |chunk| {
+                    let len = chunk.into_bytes().unwrap().len();
+                    assert!(len <= 4096);
+                    count.set(count.get() + len);
+
+                    // The consumer asks for a break after every chunk.
+                    s.pause();
+                    assert!(s.is_paused());
+                    let s = s.clone();
+                    set_immediate(move |_| s.resume());
+                });
+                stream.on_end(move |_| assert_eq!(r.get(), total));
+            })
+            .unwrap();
+
+        assert_eq!(received.get(), total);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_stream_error() {
+        let finished = Rc::new(RefCell::new(vec![]));
+        let f = finished.clone();
+        Runtime::new()
+            .run(move || {
+                let report = |f: Rc<RefCell<Vec<&'static str>>>, order| {
+                    move |res: Js| {
+                        let e = res.into_error().unwrap();
+                        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+                        f.borrow_mut().push(order);
+                    }
+                };
+
+                // Ended before the first write has failed.
+                let stream = Fs::create_write_stream("does/not/exist");
+                stream.write("x");
+                stream.end(report(f.clone(), "before"));
+
+                // And after.
+                let stream = Fs::create_write_stream("does/not/exist");
+                let (s, f) = (stream.clone(), f.clone());
+                stream.on_error(move |_| s.end(report(f.clone(), "after")));
+            })
+            .unwrap();
+
+        // The two fail in whatever order the pool gets to them.
+        finished.borrow_mut().sort();
+        assert_eq!(*finished.borrow(), ["after", "before"]);
+    }
+
+    #[test]
+    fn test_read_stream_error() {
+        let errors = Rc::new(Cell::new(0));
+        let e = errors.clone();
+        Runtime::new()
+            .run(move || {
+                let stream = Fs::create_read_stream("does/not/exist", 16);
+                stream.on_data(|_| panic!("no data expected"));
+                stream.on_end(|_| panic!("no end expected"));
+                let e = e.clone();
+                stream.on_error(move |err| {
+                    assert_eq!(err.into_error().unwrap().kind(), io::ErrorKind::NotFound);
+                    e.set(e.get() + 1);
+                });
+            })
+            .unwrap();
+
+        assert_eq!(errors.get(), 1);
+    }
+
+    #[test]
+    fn test_watch_recursive() {
+        let dir = temp_dir("watch");
+        let changes = Rc::new(RefCell::new(vec![]));
+
+        let (d, c) = (dir.clone(), changes.clone());
+        Runtime::new()
+            .run(move || {
+                let watcher = Fs::watch(&d, true, move |change| {
+                    let mut change = change.into_object().unwrap();
+                    let kind = change.remove("kind").unwrap().into_string().unwrap();
+                    let path = change.remove("path").unwrap().into_string().unwrap();
+                    let from = change.remove("from").and_then(|f| f.into_string());
+                    c.borrow_mut().push((kind, path, from));
+                })
                 .unwrap();
-            Js::String(buffer)
+
+                let dir = d.clone();
+                set_timeout(0, move |_| {
+                    fs::write(dir.join("a.txt"), "a").unwrap();
+                    fs::create_dir(dir.join("sub")).unwrap();
+                });
+                let dir = d.clone();
+                set_timeout(100, move |_| {
+                    fs::write(dir.join("sub/b.txt"), "b").unwrap();
+                    fs::rename(dir.join("a.txt"), dir.join("c.txt")).unwrap();
+                    fs::remove_file(dir.join("c.txt")).unwrap();
+                });
+                set_timeout(200, move |_| watcher.close());
+            })
+            .unwrap();
+
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+        let changes: Vec<_> = changes
+            .borrow()
+            .iter()
+            .filter(|(kind, _, _)| kind != "modify")
+            .cloned()
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("create".to_string(), path("a.txt"), None),
+                ("create".to_string(), path("sub"), None),
+                ("create".to_string(), path("sub/b.txt"), None),
+                ("rename".to_string(), path("c.txt"), Some(path("a.txt"))),
+                ("delete".to_string(), path("c.txt"), None),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_handle() {
+        let dir = temp_dir("handle");
+        let file = dir.join("data.bin");
+
+        let f = file.clone();
+        Runtime::new()
+            .run(move || {
+                let mut flags = fs::OpenOptions::new();
+                flags.read(true).write(true).create(true);
+                Fs::open(f.clone(), &flags, |res| {
+                    let handle = FileHandle(res.into_int().unwrap());
+                    handle.write_at(5, "world", move |_| {
+                        handle.write_at(0, "hello", move |res| {
+                            assert!(matches!(res, Js::Int(5)));
+                            handle.read_at(0, 10, move |res| {
+                                assert_eq!(res.into_bytes().unwrap(), b"helloworld");
+                                handle.truncate(5, move |_| {
+                                    handle.sync(move |_| {
+                                        handle.read_at(0, 100, move |res| {
+                                            assert_eq!(res.into_bytes().unwrap(), b"hello");
+                                            handle.close(move |_| {
+                                                handle.read_at(0, 1, |res| {
+                                                    assert!(res.into_error().is_some());
+                                                });
+                                            });
+                                        });
+                                    });
+                                });
+                            });
+                        });
+                    });
+                });
+            })
+            .unwrap();
+
+        assert_eq!(fs::read(&file).unwrap(), b"hello");
+
+        // Whatever gets opened next, a closed handle stays closed.
+        let other = dir.join("other.bin");
+        fs::write(&other, "other").unwrap();
+        let f = file.clone();
+        Runtime::new()
+            .run(move || {
+                let mut flags = fs::OpenOptions::new();
+                flags.read(true).write(true);
+                Fs::open(f.clone(), &flags.clone(), move |res| {
+                    let stale = FileHandle(res.into_int().unwrap());
+                    let (other, flags) = (other.clone(), flags.clone());
+                    stale.close(move |_| {
+                        Fs::open(other.clone(), &flags, move |res| {
+                            let handle = FileHandle(res.into_int().unwrap());
+                            assert_ne!(handle, stale);
+                            stale.write_at(0, "stale", move |res| {
+                                let e = res.into_error().unwrap();
+                                assert_eq!(e.raw_os_error(), Some(9));
+                                handle.read_at(0, 10, move |res| {
+                                    assert_eq!(res.into_bytes().unwrap(), b"other");
+                                    handle.close(|_| {});
+                                });
+                            });
+                        });
+                    });
+                });
+            })
+            .unwrap();
+
+        // Nor does one left open by a runtime that has ended.
+        let leaked = Rc::new(Cell::new(0));
+        let (f, l) = (file.clone(), leaked.clone());
+        Runtime::new()
+            .run(move || {
+                let mut flags = fs::OpenOptions::new();
+                flags.read(true);
+                let l = l.clone();
+                Fs::open(f.clone(), &flags, move |res| l.set(res.into_int().unwrap()));
+            })
+            .unwrap();
+        Runtime::new()
+            .run(move || {
+                FileHandle(leaked.get()).read_at(0, 5, |res| {
+                    assert_eq!(res.into_error().unwrap().raw_os_error(), Some(9));
+                });
+            })
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_walk() {
+        let dir = temp_dir("walk");
+        for file in &[
+            "README.md",
+            "src/main.rs",
+            "src/fs/walk.rs",
+            "target/build.rs",
+        ] {
+            let file = dir.join(file);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, "").unwrap();
+        }
+
+        let walk = |options: WalkOptions| {
+            let batches = Rc::new(RefCell::new(vec![]));
+            let (d, b) = (dir.clone(), batches.clone());
+            Runtime::new()
+                .run(move || {
+                    Fs::walk(d.clone(), options.clone(), move |res| match res {
+                        Js::Array(batch) => b.borrow_mut().push(batch),
+                        Js::Undefined => b.borrow_mut().push(vec![]),
+                        res => panic!("{:?}", res),
+                    });
+                })
+                .unwrap();
+
+            let batches = batches.borrow_mut().drain(..).collect::<Vec<_>>();
+            assert!(batches.last().unwrap().is_empty(), "walk didn't finish");
+            let batch_sizes = batches.iter().map(|b| b.len()).collect::<Vec<_>>();
+            let paths = batches
+                .into_iter()
+                .flatten()
+                .map(|entry| {
+                    let mut entry = entry.into_object().unwrap();
+                    let path = entry.remove("path").unwrap().into_string().unwrap();
+                    let path = PathBuf::from(path);
+                    path.strip_prefix(&dir)
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect::<Vec<_>>();
+            (batch_sizes, paths)
         };
-        let rt = unsafe { &mut *RUNTIME };
-        rt.register_event_threadpool(work, ThreadPoolTaskKind::FileRead, cb);
+
+        let (batch_sizes, paths) = walk(WalkOptions {
+            include: vec!["**/*.rs".to_string()],
+            exclude: vec!["**/target".to_string()],
+            batch_size: 1,
+            ..WalkOptions::default()
+        });
+        assert_eq!(paths, vec!["src/main.rs", "src/fs/walk.rs"]);
+        assert_eq!(batch_sizes, vec![1, 1, 0]);
+
+        let (_, paths) = walk(WalkOptions {
+            max_depth: Some(1),
+            ..WalkOptions::default()
+        });
+        assert_eq!(paths, vec!["README.md", "src", "target"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_io_uring_and_pool_agree() {
+        let dir = temp_dir("uring");
+
+        for &enabled in &[true, false] {
+            let file = dir.join(format!("{}.txt", enabled));
+            let contents = Rc::new(RefCell::new(vec![]));
+
+            let (f, c) = (file.clone(), contents.clone());
+            Runtime::new()
+                .run(move || {
+                    Fs::set_io_uring(enabled);
+                    let file = f.clone();
+                    Fs::write_file(f.clone(), vec![b'x'; 100_000], move |res| {
+                        assert!(matches!(res, Js::Undefined));
+                        let (file, c) = (file.clone(), c.clone());
+                        Fs::append_file(file.clone(), "end", move |_| {
+                            let c = c.clone();
+                            Fs::read_bytes(file.clone(), move |res| {
+                                *c.borrow_mut() = res.into_bytes().unwrap();
+                            });
+                        });
+                    });
+                    Fs::read(f.with_extension("missing"), |res| {
+                        assert!(res.into_error().is_some());
+                    });
+                })
+                .unwrap();
+
+            let contents = contents.borrow();
+            assert_eq!(contents.len(), 100_003);
+            assert_eq!(&contents[100_000..], b"end");
+        }
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
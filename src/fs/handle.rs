@@ -0,0 +1,132 @@
+use super::{uring, Fs};
+use crate::runtime::{runtime, Js, ThreadPoolTaskKind};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const EBADF: i32 = 9;
+
+/// A file opened with `Fs::open`. It stays open until `close`, so random
+/// reads and writes don't pay for reopening the file every time. All
+/// operations run on the thread pool; several can be in flight at once
+/// since they take an explicit offset instead of moving a shared cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileHandle(pub usize);
+
+impl FileHandle {
+    pub(crate) fn open(path: PathBuf, flags: OpenOptions, cb: impl Fn(Js) + 'static) {
+        let work = move || {
+            let file = flags.open(&path)?;
+            Ok(Js::Int(file.into_raw_fd() as usize))
+        };
+        Fs::run(ThreadPoolTaskKind::Open, work, move |res| match res {
+            Js::Int(fd) => {
+                // The fd was handed over by `into_raw_fd` above.
+                let file = unsafe { File::from_raw_fd(fd as i32) };
+                // Not the fd: the kernel hands that out again once it's
+                // closed, and a stale handle would get at the new file.
+                let rt = runtime();
+                let id = rt.generate_identity();
+                rt.file_handles.insert(id, Arc::new(file));
+                cb(Js::Int(id));
+            }
+            res => cb(res),
+        });
+    }
+
+    /// Calls back with `Js::Bytes` of up to `len` bytes starting at `offset`.
+    /// Fewer bytes are returned only at the end of the file.
+    pub fn read_at(&self, offset: u64, len: usize, cb: impl Fn(Js) + 'static) {
+        let file = self.file();
+        if let (Some(file), true) = (&file, uring::available()) {
+            return uring::read_at(file.clone(), offset, len, move |res| {
+                cb(res.map(Js::Bytes).unwrap_or_else(Js::Error))
+            });
+        }
+
+        let work = move || {
+            let file = file.as_ref().ok_or_else(bad_handle)?;
+            let mut buf = vec![0; len];
+            let mut read = 0;
+            while read < len {
+                match file.read_at(&mut buf[read..], offset + read as u64) {
+                    Ok(0) => break,
+                    Ok(n) => read += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            buf.truncate(read);
+            Ok(Js::Bytes(buf))
+        };
+        Fs::run(ThreadPoolTaskKind::FileRead, work, cb);
+    }
+
+    /// Writes all of `data` at `offset`, growing the file if needed.
+    pub fn write_at(&self, offset: u64, data: impl Into<Vec<u8>>, cb: impl Fn(Js) + 'static) {
+        let file = self.file();
+        let data = data.into();
+        if let (Some(file), true) = (&file, uring::available()) {
+            let len = data.len();
+            return uring::write_at(file.clone(), offset, data, move |res| {
+                cb(res.map(|_| Js::Int(len)).unwrap_or_else(Js::Error))
+            });
+        }
+
+        let work = move || {
+            let file = file.as_ref().ok_or_else(bad_handle)?;
+            file.write_all_at(&data, offset)?;
+            Ok(Js::Int(data.len()))
+        };
+        Fs::run(ThreadPoolTaskKind::FileWrite, work, cb);
+    }
+
+    /// Shrinks or extends the file to `len` bytes.
+    pub fn truncate(&self, len: u64, cb: impl Fn(Js) + 'static) {
+        let file = self.file();
+        let work = move || {
+            let file = file.as_ref().ok_or_else(bad_handle)?;
+            file.set_len(len).map(|_| Js::Undefined)
+        };
+        Fs::run(ThreadPoolTaskKind::Truncate, work, cb);
+    }
+
+    /// Flushes data and metadata to disk.
+    pub fn sync(&self, cb: impl Fn(Js) + 'static) {
+        let file = self.file();
+        if let (Some(file), true) = (&file, uring::available()) {
+            return uring::sync(file.clone(), move |res| {
+                cb(res.map(|_| Js::Undefined).unwrap_or_else(Js::Error))
+            });
+        }
+
+        let work = move || {
+            let file = file.as_ref().ok_or_else(bad_handle)?;
+            file.sync_all().map(|_| Js::Undefined)
+        };
+        Fs::run(ThreadPoolTaskKind::Sync, work, cb);
+    }
+
+    /// The handle can't be used after this. Operations already in flight
+    /// still finish; the file is closed once the last one is done.
+    pub fn close(&self, cb: impl Fn(Js) + 'static) {
+        let file = Mutex::new(runtime().file_handles.remove(&self.0));
+        let work = move || {
+            let file = file.lock().unwrap().take().ok_or_else(bad_handle)?;
+            drop(file);
+            Ok(Js::Undefined)
+        };
+        Fs::run(ThreadPoolTaskKind::CloseFile, work, cb);
+    }
+
+    fn file(&self) -> Option<Arc<File>> {
+        runtime().file_handles.get(&self.0).cloned()
+    }
+}
+
+fn bad_handle() -> io::Error {
+    io::Error::from_raw_os_error(EBADF)
+}
@@ -0,0 +1,346 @@
+use crate::runtime::{runtime, Js, ThreadPoolTaskKind};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+/// `WriteStream::write` returns `false` once this many bytes are queued.
+pub const HIGH_WATER_MARK: usize = 16 * 1024;
+
+type Listener = Option<Rc<dyn Fn(Js)>>;
+
+/// Reads a file in chunks on the thread pool. Only one chunk is in flight at
+/// a time and the next one is only read once the previous one has been
+/// handed to `on_data`, so a consumer that calls `pause` stops the reads.
+#[derive(Clone)]
+pub struct ReadStream {
+    state: Rc<RefCell<ReadState>>,
+}
+
+struct ReadState {
+    path: PathBuf,
+    chunk_size: usize,
+    file: Arc<Mutex<Option<fs::File>>>,
+    paused: bool,
+    reading: bool,
+    done: bool,
+    on_data: Listener,
+    on_end: Listener,
+    on_error: Listener,
+}
+
+impl ReadStream {
+    pub(crate) fn new(path: PathBuf, chunk_size: usize) -> Self {
+        let state = ReadState {
+            path,
+            chunk_size,
+            file: Arc::new(Mutex::new(None)),
+            paused: false,
+            reading: false,
+            done: false,
+            on_data: None,
+            on_end: None,
+            on_error: None,
+        };
+        ReadStream {
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// Called with `Js::Bytes` for every chunk. Reading starts once this is
+    /// set.
+    pub fn on_data(&self, cb: impl Fn(Js) + 'static) {
+        self.state.borrow_mut().on_data = Some(Rc::new(cb));
+        self.read_next();
+    }
+
+    pub fn on_end(&self, cb: impl Fn(Js) + 'static) {
+        self.state.borrow_mut().on_end = Some(Rc::new(cb));
+    }
+
+    /// Called with `Js::Error`. No `on_end` follows an error.
+    pub fn on_error(&self, cb: impl Fn(Js) + 'static) {
+        self.state.borrow_mut().on_error = Some(Rc::new(cb));
+    }
+
+    /// No more chunks are read until `resume`. A chunk already in flight is
+    /// still delivered.
+    pub fn pause(&self) {
+        self.state.borrow_mut().paused = true;
+    }
+
+    pub fn resume(&self) {
+        self.state.borrow_mut().paused = false;
+        self.read_next();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.borrow().paused
+    }
+
+    /// Stops reading for good and drops the listeners, so whatever they
+    /// hold on to is freed. Nothing is called after this.
+    pub fn destroy(&self) {
+        let mut state = self.state.borrow_mut();
+        state.on_data = None;
+        state.on_end = None;
+        state.on_error = None;
+        if !state.done {
+            state.done = true;
+            let file = state.file.clone();
+            runtime().register_close_callback(move |_| drop(file.lock().unwrap().take()));
+        }
+    }
+
+    fn read_next(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.paused || state.reading || state.done || state.on_data.is_none() {
+            return;
+        }
+        state.reading = true;
+
+        let path = state.path.clone();
+        let chunk_size = state.chunk_size;
+        let file = state.file.clone();
+        let work = move || {
+            let mut file = file.lock().unwrap();
+            if file.is_none() {
+                match fs::File::open(&path) {
+                    Ok(f) => *file = Some(f),
+                    Err(e) => return Js::Error(e),
+                }
+            }
+
+            let mut chunk = Vec::with_capacity(chunk_size);
+            let reader = file.as_mut().unwrap();
+            match reader.take(chunk_size as u64).read_to_end(&mut chunk) {
+                Ok(_) => Js::Bytes(chunk),
+                Err(e) => Js::Error(e),
+            }
+        };
+
+        let stream = self.clone();
+        drop(state);
+        runtime().register_event_threadpool(work, ThreadPoolTaskKind::FileRead, move |res| {
+            stream.on_chunk(res)
+        });
+    }
+
+    fn on_chunk(&self, res: Js) {
+        self.state.borrow_mut().reading = false;
+
+        match res {
+            Js::Bytes(chunk) if !chunk.is_empty() => {
+                let on_data = self.state.borrow().on_data.clone();
+                if let Some(on_data) = on_data {
+                    on_data(Js::Bytes(chunk));
+                }
+                self.read_next();
+            }
+            Js::Error(e) => self.finish(|s| s.on_error.clone(), Js::Error(e)),
+            _ => self.finish(|s| s.on_end.clone(), Js::Undefined),
+        }
+    }
+
+    fn finish(&self, listener: impl Fn(&ReadState) -> Listener, data: Js) {
+        let (listener, file) = {
+            let mut state = self.state.borrow_mut();
+            state.done = true;
+            (listener(&state), state.file.clone())
+        };
+        if let Some(listener) = listener {
+            listener(data);
+        }
+        runtime().register_close_callback(move |_| drop(file.lock().unwrap().take()));
+    }
+}
+
+/// Writes chunks to a file in order, one pool task at a time. `write` queues
+/// the chunk and returns `false` once more than `HIGH_WATER_MARK` bytes are
+/// waiting; the producer should then wait for `on_drain` before writing more.
+#[derive(Clone)]
+pub struct WriteStream {
+    state: Rc<RefCell<WriteState>>,
+}
+
+struct WriteState {
+    path: PathBuf,
+    file: Arc<Mutex<Option<fs::File>>>,
+    queue: VecDeque<Vec<u8>>,
+    queued_bytes: usize,
+    writing: bool,
+    needs_drain: bool,
+    /// The write that failed, after which nothing more is written.
+    error: Option<io::Error>,
+    on_finish: Option<Box<dyn FnOnce(Js)>>,
+    on_drain: Listener,
+    on_error: Listener,
+}
+
+impl WriteStream {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        let state = WriteState {
+            path,
+            file: Arc::new(Mutex::new(None)),
+            // An empty first chunk creates the file even if nothing is
+            // ever written.
+            queue: VecDeque::from(vec![vec![]]),
+            queued_bytes: 0,
+            writing: false,
+            needs_drain: false,
+            error: None,
+            on_finish: None,
+            on_drain: None,
+            on_error: None,
+        };
+        let stream = WriteStream {
+            state: Rc::new(RefCell::new(state)),
+        };
+        stream.write_next();
+        stream
+    }
+
+    pub fn write(&self, data: impl Into<Vec<u8>>) -> bool {
+        let data = data.into();
+        let below_mark = {
+            let mut state = self.state.borrow_mut();
+            state.queued_bytes += data.len();
+            state.queue.push_back(data);
+            let below_mark = state.queued_bytes < HIGH_WATER_MARK;
+            state.needs_drain |= !below_mark;
+            below_mark
+        };
+        self.write_next();
+        below_mark
+    }
+
+    /// Called once the queue has been flushed after `write` returned `false`.
+    pub fn on_drain(&self, cb: impl Fn(Js) + 'static) {
+        self.state.borrow_mut().on_drain = Some(Rc::new(cb));
+    }
+
+    /// Called with `Js::Error`. Everything still queued is dropped.
+    pub fn on_error(&self, cb: impl Fn(Js) + 'static) {
+        self.state.borrow_mut().on_error = Some(Rc::new(cb));
+    }
+
+    /// Calls `cb` in the close phase once everything queued has been written
+    /// and the file is closed, or with `Js::Error` if a write failed.
+    pub fn end(&self, cb: impl FnOnce(Js) + 'static) {
+        let idle = {
+            let mut state = self.state.borrow_mut();
+            state.on_finish = Some(Box::new(cb));
+            !state.writing && state.queue.is_empty()
+        };
+        if idle {
+            self.finish();
+        }
+    }
+
+    fn write_next(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.writing || state.error.is_some() {
+            return;
+        }
+        let chunk = match state.queue.pop_front() {
+            Some(chunk) => chunk,
+            None => return,
+        };
+        state.writing = true;
+
+        let path = state.path.clone();
+        let file = state.file.clone();
+        let len = chunk.len();
+        let work = move || {
+            let mut file = file.lock().unwrap();
+            if file.is_none() {
+                match fs::File::create(&path) {
+                    Ok(f) => *file = Some(f),
+                    Err(e) => return Js::Error(e),
+                }
+            }
+            match file.as_mut().unwrap().write_all(&chunk) {
+                Ok(_) => Js::Undefined,
+                Err(e) => Js::Error(e),
+            }
+        };
+
+        let stream = self.clone();
+        drop(state);
+        runtime().register_event_threadpool(work, ThreadPoolTaskKind::FileWrite, move |res| {
+            stream.on_written(len, res)
+        });
+    }
+
+    fn on_written(&self, len: usize, res: Js) {
+        let mut state = self.state.borrow_mut();
+        state.writing = false;
+        state.queued_bytes -= len;
+
+        if let Js::Error(e) = res {
+            state.error = Some(copy_error(&e));
+            state.queue.clear();
+            let on_error = state.on_error.clone();
+            let ended = state.on_finish.is_some();
+            drop(state);
+            if let Some(on_error) = on_error {
+                on_error(Js::Error(e));
+            }
+            if ended {
+                self.finish();
+            }
+            return;
+        }
+
+        if !state.queue.is_empty() {
+            drop(state);
+            return self.write_next();
+        }
+
+        let drained = if state.needs_drain {
+            state.needs_drain = false;
+            state.on_drain.clone()
+        } else {
+            None
+        };
+        drop(state);
+        if let Some(on_drain) = drained {
+            on_drain(Js::Undefined);
+        }
+
+        let state = self.state.borrow();
+        let finished = state.on_finish.is_some() && !state.writing && state.queue.is_empty();
+        drop(state);
+        if finished {
+            self.finish();
+        }
+    }
+
+    fn finish(&self) {
+        let (on_finish, file, res) = {
+            let mut state = self.state.borrow_mut();
+            let res = match &state.error {
+                Some(e) => Js::Error(copy_error(e)),
+                None => Js::Undefined,
+            };
+            (state.on_finish.take(), state.file.clone(), res)
+        };
+        if let Some(on_finish) = on_finish {
+            runtime().register_close_callback(move |_| {
+                drop(file.lock().unwrap().take());
+                on_finish(res);
+            });
+        }
+    }
+}
+
+/// `io::Error` isn't `Clone`, but `on_error` and `end` both get the error.
+fn copy_error(e: &io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(e.kind(), e.to_string()),
+    }
+}
@@ -0,0 +1,203 @@
+//! File operations as chains of io_uring ops, so they don't take up a pool
+//! thread while the disk is busy. Each step is submitted from the callback
+//! of the previous one.
+use super::SIMULATED_LATENCY;
+use crate::runtime::{runtime, Js, ThreadPoolTaskKind};
+use crate::sys;
+use crate::uring::Op;
+use std::cell::Cell;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// How much a whole-file read asks for at a time.
+const READ_CHUNK: usize = 64 * 1024;
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+pub(super) fn set_enabled(enabled: bool) {
+    ENABLED.with(|e| e.set(enabled));
+}
+
+/// Whether operations started now should go through io_uring rather than
+/// the pool. The simulated latency only exists on the pool.
+pub(super) fn available() -> bool {
+    ENABLED.with(|e| e.get())
+        && SIMULATED_LATENCY.with(|l| l.get()).is_none()
+        && runtime().has_uring()
+}
+
+/// `path` as io_uring wants it, or `None` if the operation should go to the
+/// pool instead.
+pub(super) fn c_path(path: &Path) -> Option<CString> {
+    if !available() {
+        return None;
+    }
+    CString::new(path.as_os_str().as_bytes()).ok()
+}
+
+fn submit(op: Op, kind: ThreadPoolTaskKind, cb: impl FnOnce(io::Result<Js>) + 'static) {
+    runtime().register_event_uring(op, kind, move |res| match res {
+        Js::Error(e) => cb(Err(e)),
+        res => cb(Ok(res)),
+    });
+}
+
+pub(super) fn read_file(path: CString, cb: impl FnOnce(io::Result<Vec<u8>>) + 'static) {
+    let open = Op::Open {
+        path,
+        flags: sys::O_RDONLY | sys::O_CLOEXEC,
+        mode: 0,
+    };
+    submit(open, ThreadPoolTaskKind::FileRead, move |res| match res {
+        Ok(fd) => read_to_end(fd.into_int().unwrap() as RawFd, vec![], cb),
+        Err(e) => cb(Err(e)),
+    });
+}
+
+fn read_to_end(fd: RawFd, mut data: Vec<u8>, cb: impl FnOnce(io::Result<Vec<u8>>) + 'static) {
+    let read = Op::Read {
+        fd,
+        len: READ_CHUNK,
+        offset: data.len() as u64,
+    };
+    submit(read, ThreadPoolTaskKind::FileRead, move |res| match res {
+        Ok(Js::Bytes(chunk)) if chunk.is_empty() => close(fd, Ok(data), cb),
+        Ok(chunk) => {
+            data.extend(chunk.into_bytes().unwrap());
+            read_to_end(fd, data, cb);
+        }
+        Err(e) => close(fd, Err(e), cb),
+    });
+}
+
+/// Creates the file if needed, then either truncates it or appends to it.
+pub(super) fn write_file(
+    path: CString,
+    data: Vec<u8>,
+    append: bool,
+    cb: impl FnOnce(io::Result<()>) + 'static,
+) {
+    let (mode, kind) = if append {
+        (sys::O_APPEND, ThreadPoolTaskKind::FileAppend)
+    } else {
+        (sys::O_TRUNC, ThreadPoolTaskKind::FileWrite)
+    };
+    let open = Op::Open {
+        path,
+        flags: sys::O_WRONLY | sys::O_CREAT | sys::O_CLOEXEC | mode,
+        mode: 0o666,
+    };
+    submit(open, kind, move |res| match res {
+        Ok(fd) => {
+            let fd = fd.into_int().unwrap() as RawFd;
+            // Appends ignore the offset.
+            write_all(fd, Rc::new(data), 0, 0, kind, move |res| close(fd, res, cb));
+        }
+        Err(e) => cb(Err(e)),
+    });
+}
+
+fn write_all(
+    fd: RawFd,
+    data: Rc<Vec<u8>>,
+    start: usize,
+    offset: u64,
+    kind: ThreadPoolTaskKind,
+    cb: impl FnOnce(io::Result<()>) + 'static,
+) {
+    if start == data.len() {
+        return cb(Ok(()));
+    }
+    let write = Op::Write {
+        fd,
+        data: data.clone(),
+        start,
+        offset,
+    };
+    submit(write, kind, move |res| match res {
+        Ok(Js::Int(0)) => cb(Err(io::ErrorKind::WriteZero.into())),
+        Ok(written) => {
+            let written = written.into_int().unwrap();
+            write_all(fd, data, start + written, offset + written as u64, kind, cb);
+        }
+        Err(e) => cb(Err(e)),
+    });
+}
+
+fn close<T: 'static>(fd: RawFd, res: io::Result<T>, cb: impl FnOnce(io::Result<T>) + 'static) {
+    submit(
+        Op::Close { fd },
+        ThreadPoolTaskKind::CloseFile,
+        move |closed| cb(res.and_then(|res| closed.map(|_| res))),
+    );
+}
+
+/// Reads until `len` bytes or the end of the file. `file` is kept open until
+/// the read is done.
+pub(super) fn read_at(
+    file: Arc<File>,
+    offset: u64,
+    len: usize,
+    cb: impl FnOnce(io::Result<Vec<u8>>) + 'static,
+) {
+    read_at_into(file, offset, len, vec![], cb);
+}
+
+fn read_at_into(
+    file: Arc<File>,
+    offset: u64,
+    len: usize,
+    mut data: Vec<u8>,
+    cb: impl FnOnce(io::Result<Vec<u8>>) + 'static,
+) {
+    let read = Op::Read {
+        fd: file.as_raw_fd(),
+        len: len - data.len(),
+        offset: offset + data.len() as u64,
+    };
+    submit(read, ThreadPoolTaskKind::FileRead, move |res| match res {
+        Ok(chunk) => {
+            let chunk = chunk.into_bytes().unwrap();
+            let eof = chunk.is_empty();
+            data.extend(chunk);
+            if eof || data.len() == len {
+                cb(Ok(data));
+            } else {
+                read_at_into(file, offset, len, data, cb);
+            }
+        }
+        Err(e) => cb(Err(e)),
+    });
+}
+
+pub(super) fn write_at(
+    file: Arc<File>,
+    offset: u64,
+    data: Vec<u8>,
+    cb: impl FnOnce(io::Result<()>) + 'static,
+) {
+    let fd = file.as_raw_fd();
+    let kind = ThreadPoolTaskKind::FileWrite;
+    write_all(fd, Rc::new(data), 0, offset, kind, move |res| {
+        drop(file);
+        cb(res)
+    });
+}
+
+pub(super) fn sync(file: Arc<File>, cb: impl FnOnce(io::Result<()>) + 'static) {
+    let fsync = Op::Fsync {
+        fd: file.as_raw_fd(),
+    };
+    submit(fsync, ThreadPoolTaskKind::Sync, move |res| {
+        drop(file);
+        cb(res.map(|_| ()))
+    });
+}
@@ -0,0 +1,339 @@
+use super::Fs;
+use crate::runtime::{Js, ThreadPoolTaskKind};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+/// Options for `Fs::walk`.
+///
+/// `include` and `exclude` are glob patterns matched against the path
+/// relative to the root, with `/` as separator. `*` and `?` match within one
+/// component and `**` matches any number of components, so `**/*.rs` is every
+/// Rust file and `**/target` every `target` directory. An entry is reported if
+/// it matches any `include` pattern (or `include` is empty) and no `exclude`
+/// pattern. Excluded directories are not descended into.
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    /// Entries directly in the root are at depth 1.
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: bool,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub batch_size: usize,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        WalkOptions {
+            max_depth: None,
+            follow_symlinks: false,
+            include: vec![],
+            exclude: vec![],
+            batch_size: 256,
+        }
+    }
+}
+
+/// State shared by the pool tasks of one walk. Each task continues where the
+/// previous one stopped and returns at most `batch_size` entries.
+struct WalkState {
+    root: PathBuf,
+    options: WalkOptions,
+    /// Directories still to read, with their depth. The top is read next.
+    stack: Vec<(PathBuf, usize)>,
+    ready: VecDeque<Js>,
+    /// Device and inode of every directory entered, so a symlink back to
+    /// one of them isn't followed.
+    visited: HashSet<(u64, u64)>,
+}
+
+pub(crate) fn walk(root: PathBuf, options: WalkOptions, cb: impl Fn(Js) + 'static) {
+    let state = WalkState {
+        stack: vec![(root.clone(), 0)],
+        root,
+        options,
+        ready: VecDeque::new(),
+        visited: HashSet::new(),
+    };
+    next_batch(Arc::new(Mutex::new(state)), Rc::new(cb));
+}
+
+fn next_batch(state: Arc<Mutex<WalkState>>, cb: Rc<dyn Fn(Js)>) {
+    let work_state = state.clone();
+    let work = move || work_state.lock().unwrap().next_batch().map(Js::Array);
+    Fs::run(ThreadPoolTaskKind::Walk, work, move |res| match res {
+        Js::Array(batch) if batch.is_empty() => cb(Js::Undefined),
+        Js::Array(batch) => {
+            cb(Js::Array(batch));
+            next_batch(state.clone(), cb.clone());
+        }
+        res => cb(res),
+    });
+}
+
+impl WalkState {
+    fn next_batch(&mut self) -> io::Result<Vec<Js>> {
+        let batch_size = self.options.batch_size.max(1);
+        while self.ready.len() < batch_size {
+            match self.stack.pop() {
+                Some((dir, depth)) => self.read_dir(&dir, depth + 1)?,
+                None => break,
+            }
+        }
+        let n = batch_size.min(self.ready.len());
+        Ok(self.ready.drain(..n).collect())
+    }
+
+    fn read_dir(&mut self, dir: &Path, depth: usize) -> io::Result<()> {
+        if self.options.max_depth.is_some_and(|max| depth > max) {
+            return Ok(());
+        }
+        if depth == 1 {
+            let root = fs::metadata(dir)?;
+            self.visited.insert((root.dev(), root.ino()));
+        }
+
+        let entries = fs::read_dir(dir).and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<io::Result<Vec<_>>>()
+        });
+        let mut entries = match entries {
+            Ok(entries) => entries,
+            Err(e) if depth == 1 => return Err(e),
+            // One directory that can't be read doesn't end the walk. It's
+            // reported, and skipped.
+            Err(e) => {
+                self.ready.push_back(error_entry(dir, e));
+                return Ok(());
+            }
+        };
+        entries.sort();
+
+        let mut subdirs = vec![];
+        for path in entries {
+            self.visit(path, &mut subdirs);
+        }
+        for subdir in subdirs.into_iter().rev() {
+            self.stack.push((subdir, depth));
+        }
+        Ok(())
+    }
+
+    /// Reports the entry at `path`, and adds it to `subdirs` if it's a
+    /// directory to descend into.
+    fn visit(&mut self, path: PathBuf, subdirs: &mut Vec<PathBuf>) {
+        let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+        let relative = relative.to_string_lossy();
+        if self
+            .options
+            .exclude
+            .iter()
+            .any(|p| glob_match(p, &relative))
+        {
+            return;
+        }
+
+        // It may be gone already, which is no reason to end the walk
+        // either.
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(e) => return self.ready.push_back(error_entry(&path, e)),
+        };
+        let mut kind = if meta.is_file() {
+            "file"
+        } else if meta.is_dir() {
+            "directory"
+        } else if meta.file_type().is_symlink() {
+            "symlink"
+        } else {
+            "other"
+        };
+
+        if meta.is_dir() {
+            if self.visited.insert((meta.dev(), meta.ino())) {
+                subdirs.push(path.clone());
+            }
+        } else if kind == "symlink" && self.options.follow_symlinks {
+            // A dangling link is reported as a symlink.
+            if let Ok(target) = fs::metadata(&path) {
+                if target.is_dir() {
+                    kind = "directory";
+                    if self.visited.insert((target.dev(), target.ino())) {
+                        subdirs.push(path.clone());
+                    }
+                } else if target.is_file() {
+                    kind = "file";
+                }
+            }
+        }
+
+        let include = &self.options.include;
+        if include.is_empty() || include.iter().any(|p| glob_match(p, &relative)) {
+            let mut entry = BTreeMap::new();
+            let path = path.to_string_lossy().into_owned();
+            entry.insert("path".to_string(), Js::String(path));
+            entry.insert("kind".to_string(), Js::String(kind.to_string()));
+            self.ready.push_back(Js::Object(entry));
+        }
+    }
+}
+
+fn error_entry(path: &Path, e: io::Error) -> Js {
+    let mut entry = BTreeMap::new();
+    let path = path.to_string_lossy().into_owned();
+    entry.insert("path".to_string(), Js::String(path));
+    entry.insert("kind".to_string(), Js::String("error".to_string()));
+    entry.insert("error".to_string(), Js::Error(e));
+    Js::Object(entry)
+}
+
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    match_components(&pattern, &path)
+}
+
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| match_components(rest, &path[i..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((component, tail)) => {
+                let pattern: Vec<char> = first.chars().collect();
+                let component: Vec<char> = component.chars().collect();
+                match_component(&pattern, &component) && match_components(rest, tail)
+            }
+            None => false,
+        },
+    }
+}
+
+fn match_component(pattern: &[char], s: &[char]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some(('*', rest)) => (0..=s.len()).any(|i| match_component(rest, &s[i..])),
+        Some(('?', rest)) => !s.is_empty() && match_component(rest, &s[1..]),
+        Some((c, rest)) => s.first() == Some(c) && match_component(rest, &s[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(root: &Path, follow_symlinks: bool) -> WalkState {
+        WalkState {
+            stack: vec![(root.to_path_buf(), 0)],
+            root: root.to_path_buf(),
+            options: WalkOptions {
+                follow_symlinks,
+                ..WalkOptions::default()
+            },
+            ready: VecDeque::new(),
+            visited: HashSet::new(),
+        }
+    }
+
+    /// The path relative to `root` and kind of every entry of a batch.
+    fn entries(root: &Path, batch: Vec<Js>) -> Vec<(String, String)> {
+        let entries = batch.into_iter().map(|entry| {
+            let mut entry = entry.into_object().unwrap();
+            let path = entry.remove("path").unwrap().into_string().unwrap();
+            let path = Path::new(&path).strip_prefix(root).unwrap();
+            let kind = entry.remove("kind").unwrap().into_string().unwrap();
+            (path.to_string_lossy().into_owned(), kind)
+        });
+        entries.collect()
+    }
+
+    #[test]
+    fn test_symlink_cycles() {
+        let root = std::env::temp_dir().join(format!("adven-walk-cycle-{}", std::process::id()));
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        std::os::unix::fs::symlink(&root, root.join("a/b/to_root")).unwrap();
+        std::os::unix::fs::symlink(root.join("a"), root.join("to_a")).unwrap();
+
+        // Neither link is walked into: they point back up the tree.
+        let batch = state(&root, true).next_batch().unwrap();
+        let entry = |path: &str| (path.to_string(), "directory".to_string());
+        assert_eq!(
+            entries(&root, batch),
+            [
+                entry("a"),
+                entry("to_a"),
+                entry("a/b"),
+                entry("a/b/to_root")
+            ]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_unreadable_directory() {
+        let root = std::env::temp_dir().join(format!("adven-walk-error-{}", std::process::id()));
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("b"), "").unwrap();
+
+        let mut walk = state(&root, false);
+        walk.options.batch_size = 2;
+        let batch = walk.next_batch().unwrap();
+        assert_eq!(entries(&root, batch).len(), 2);
+        // As if `a` had been found but couldn't be read.
+        fs::remove_dir(root.join("a")).unwrap();
+        let mut batch = walk.next_batch().unwrap();
+        assert_eq!(batch.len(), 1);
+        let mut entry = batch.remove(0).into_object().unwrap();
+        assert_eq!(
+            entry.remove("kind").unwrap().into_string().unwrap(),
+            "error"
+        );
+        let e = entry.remove("error").unwrap().into_error().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(walk.next_batch().unwrap().is_empty());
+
+        // The root itself still has to be there.
+        fs::remove_dir_all(&root).unwrap();
+        assert!(state(&root, false).next_batch().is_err());
+    }
+
+    #[test]
+    fn test_vanished_entry() {
+        let root = std::env::temp_dir().join(format!("adven-walk-gone-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a"), "").unwrap();
+
+        // As if `gone` had been listed, but deleted before it was looked at.
+        let mut walk = state(&root, false);
+        let mut subdirs = vec![];
+        walk.visit(root.join("gone"), &mut subdirs);
+        assert!(subdirs.is_empty());
+        let entry = |path: &str, kind: &str| (path.to_string(), kind.to_string());
+        assert_eq!(
+            entries(&root, walk.next_batch().unwrap()),
+            [entry("gone", "error"), entry("a", "file")]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("**/*.rs", "main.rs"));
+        assert!(glob_match("**/*.rs", "src/fs/walk.rs"));
+        assert!(!glob_match("**/*.rs", "src/fs/walk.rsx"));
+        assert!(glob_match("src/*.rs", "src/fs.rs"));
+        assert!(!glob_match("src/*.rs", "src/fs/walk.rs"));
+        assert!(glob_match("**/target", "target"));
+        assert!(glob_match("**/target", "a/b/target"));
+        assert!(glob_match("a/**/c", "a/c"));
+        assert!(glob_match("a/**/c", "a/b/b/c"));
+        assert!(glob_match("?.txt", "a.txt"));
+        assert!(!glob_match("?.txt", "ab.txt"));
+        assert!(glob_match("*", "anything"));
+        assert!(!glob_match("*", "a/b"));
+    }
+}
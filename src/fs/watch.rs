@@ -0,0 +1,267 @@
+use crate::poll::Interests;
+use crate::runtime::{runtime, Js};
+use crate::sys::{self, Inotify, InotifyEvent};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+const MASK: u32 = sys::IN_CREATE
+    | sys::IN_MODIFY
+    | sys::IN_DELETE
+    | sys::IN_MOVED_FROM
+    | sys::IN_MOVED_TO
+    | sys::IN_DELETE_SELF
+    | sys::IN_MOVE_SELF;
+
+/// Watches a file or directory with inotify. The inotify fd is registered
+/// with the runtime's epoll thread, so no pool thread is tied up waiting.
+///
+/// Every change reaches the callback as `{ kind, path }` where `kind` is
+/// `"create"`, `"modify"`, `"delete"` or `"rename"`. Renames inside the
+/// watched tree also carry `from`; things moved in or out of it show up as
+/// `"create"` and `"delete"`. If the watched path itself is deleted or
+/// moved, that comes as `"delete"` or `"rename"` of the path, without
+/// `from`. The watcher keeps the loop alive until `close`.
+#[derive(Clone)]
+pub struct Watcher {
+    state: Rc<RefCell<WatchState>>,
+}
+
+struct WatchState {
+    inotify: Inotify,
+    token: usize,
+    recursive: bool,
+    /// The watch of the path `Watcher::new` got.
+    root: i32,
+    dirs: HashMap<i32, PathBuf>,
+    cb: Rc<dyn Fn(Js)>,
+    closed: bool,
+}
+
+impl Watcher {
+    pub(crate) fn new(
+        path: PathBuf,
+        recursive: bool,
+        cb: impl Fn(Js) + 'static,
+    ) -> io::Result<Self> {
+        let inotify = Inotify::new()?;
+        let mut dirs = HashMap::new();
+        let root = add_watches(&inotify, &path, recursive, &mut dirs)?;
+
+        let rt = runtime();
+        let token = rt.generate_cb_identity();
+        rt.epoll_registrator
+            .register(&inotify, token, Interests::READABLE)?;
+
+        let state = WatchState {
+            inotify,
+            token,
+            recursive,
+            root,
+            dirs,
+            cb: Rc::new(cb),
+            closed: false,
+        };
+        let watcher = Watcher {
+            state: Rc::new(RefCell::new(state)),
+        };
+        watcher.wait_for_events();
+        Ok(watcher)
+    }
+
+    /// Stops watching. No callbacks are made after this returns.
+    pub fn close(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.closed {
+            return;
+        }
+        state.closed = true;
+
+        let rt = runtime();
+        let _ = rt.epoll_registrator.deregister(&state.inotify);
+        rt.deregister_event_epoll(state.token);
+    }
+
+    fn wait_for_events(&self) {
+        let token = self.state.borrow().token;
+        let watcher = self.clone();
+        runtime().register_event_epoll(token, move |_| watcher.on_readable());
+    }
+
+    fn on_readable(&self) {
+        let (changes, cb) = {
+            let mut state = self.state.borrow_mut();
+            if state.closed {
+                return;
+            }
+            let changes = match state.inotify.read_events() {
+                Ok(events) => state.translate(events),
+                Err(e) => vec![Js::Error(e)],
+            };
+            (changes, state.cb.clone())
+        };
+
+        for change in changes {
+            cb(change);
+            if self.state.borrow().closed {
+                return;
+            }
+        }
+
+        let state = self.state.borrow();
+        runtime()
+            .epoll_registrator
+            .reregister(&state.inotify, state.token, Interests::READABLE)
+            .expect("re-arm inotify");
+        drop(state);
+        self.wait_for_events();
+    }
+}
+
+impl WatchState {
+    fn translate(&mut self, events: Vec<InotifyEvent>) -> Vec<Js> {
+        let mut changes = vec![];
+        let mut moved_from: HashMap<u32, PathBuf> = HashMap::new();
+
+        for event in events {
+            let dir = match self.dirs.get(&event.wd) {
+                Some(dir) => dir.clone(),
+                None => continue,
+            };
+            let path = match &event.name {
+                Some(name) => dir.join(name),
+                None => dir,
+            };
+            let is_dir = event.mask & sys::IN_ISDIR != 0;
+
+            if event.mask & sys::IN_IGNORED != 0 {
+                // The watch is gone, and its wd may be handed out again.
+                self.dirs.remove(&event.wd);
+            } else if event.mask & (sys::IN_DELETE_SELF | sys::IN_MOVE_SELF) != 0 {
+                // Subdirectories are reported by their parent's watch.
+                if event.wd == self.root {
+                    let deleted = event.mask & sys::IN_DELETE_SELF != 0;
+                    let kind = if deleted { "delete" } else { "rename" };
+                    changes.push(change(kind, &path, None));
+                }
+            } else if event.mask & sys::IN_CREATE != 0 {
+                if self.recursive && is_dir {
+                    let _ = add_watches(&self.inotify, &path, true, &mut self.dirs);
+                }
+                changes.push(change("create", &path, None));
+            } else if event.mask & sys::IN_MODIFY != 0 {
+                changes.push(change("modify", &path, None));
+            } else if event.mask & sys::IN_DELETE != 0 {
+                changes.push(change("delete", &path, None));
+            } else if event.mask & sys::IN_MOVED_FROM != 0 {
+                moved_from.insert(event.cookie, path);
+            } else if event.mask & sys::IN_MOVED_TO != 0 {
+                match moved_from.remove(&event.cookie) {
+                    Some(from) => {
+                        if is_dir {
+                            self.rename_dirs(&from, &path);
+                        }
+                        changes.push(change("rename", &path, Some(&from)));
+                    }
+                    None => changes.push(change("create", &path, None)),
+                }
+            }
+        }
+
+        // Moved somewhere we don't watch.
+        for (_, from) in moved_from {
+            changes.push(change("delete", &from, None));
+        }
+        changes
+    }
+
+    fn rename_dirs(&mut self, from: &Path, to: &Path) {
+        for dir in self.dirs.values_mut() {
+            if let Ok(rest) = dir.strip_prefix(from) {
+                *dir = to.join(rest);
+            }
+        }
+    }
+}
+
+fn change(kind: &str, path: &Path, from: Option<&Path>) -> Js {
+    let mut change = BTreeMap::new();
+    change.insert("kind".to_string(), Js::String(kind.to_string()));
+    let path = path.to_string_lossy().into_owned();
+    change.insert("path".to_string(), Js::String(path));
+    if let Some(from) = from {
+        let from = from.to_string_lossy().into_owned();
+        change.insert("from".to_string(), Js::String(from));
+    }
+    Js::Object(change)
+}
+
+/// Returns the wd of `path` itself.
+fn add_watches(
+    inotify: &Inotify,
+    path: &Path,
+    recursive: bool,
+    dirs: &mut HashMap<i32, PathBuf>,
+) -> io::Result<i32> {
+    let wd = inotify.add_watch(path, MASK)?;
+    dirs.insert(wd, path.to_path_buf());
+
+    if recursive && fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                add_watches(inotify, &entry.path(), true, dirs)?;
+            }
+        }
+    }
+    Ok(wd)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::{set_timeout, Runtime};
+
+    #[test]
+    fn test_watched_directory_removed() {
+        let root = std::env::temp_dir().join(format!("adven-watch-self-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let changes = Rc::new(RefCell::new(vec![]));
+        let watches = Rc::new(RefCell::new(None));
+
+        let (r, c, w) = (root.clone(), changes.clone(), watches.clone());
+        Runtime::new()
+            .run(move || {
+                let watcher = Watcher::new(r.clone(), true, move |change| {
+                    let mut change = change.into_object().unwrap();
+                    let kind = change.remove("kind").unwrap().into_string().unwrap();
+                    let path = change.remove("path").unwrap().into_string().unwrap();
+                    c.borrow_mut().push((kind, path));
+                })
+                .unwrap();
+                assert_eq!(watcher.state.borrow().dirs.len(), 2);
+
+                let root = r.clone();
+                set_timeout(0, move |_| fs::remove_dir_all(&root).unwrap());
+                set_timeout(100, move |_| {
+                    *w.borrow_mut() = Some(watcher.state.borrow().dirs.len());
+                    watcher.close();
+                });
+            })
+            .unwrap();
+
+        let path = |p: &Path| p.to_string_lossy().into_owned();
+        assert_eq!(
+            *changes.borrow(),
+            [
+                ("delete".to_string(), path(&root.join("sub"))),
+                ("delete".to_string(), path(&root)),
+            ]
+        );
+        // Both watches went with their directories.
+        assert_eq!(*watches.borrow(), Some(0));
+    }
+}
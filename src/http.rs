@@ -1,39 +1,605 @@
-use crate::runtime::{Js, Runtime, RUNTIME};
-use std::io::{Read, Write};
+mod client;
+mod pool;
+mod request;
+mod response;
+mod server;
+mod url;
+
+pub(crate) use pool::Pool;
+pub use pool::PoolOptions;
+pub use request::{Method, Request};
+pub use response::{Headers, Redirect, Response};
+pub use server::{ResponseWriter, Server, ServerOptions, ServerRequest};
+pub use url::Url;
+
+use crate::runtime::{queue_microtask, runtime, Js};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Options for `Http::request`.
+#[derive(Debug, Clone)]
+pub struct RequestOptions {
+    /// How long establishing the connection may take. `None` leaves it to
+    /// the OS, which can take minutes.
+    pub connect_timeout: Option<Duration>,
+    /// Fails the request with `TimedOut` if the whole response hasn't
+    /// arrived this long after it was made, including any time spent
+    /// waiting for a connection.
+    pub timeout: Option<Duration>,
+    /// Follows up to this many redirects and fails with an error after
+    /// that. Each one is a new request, with its own timeouts. `None`
+    /// hands redirects back like any other response.
+    pub follow_redirects: Option<usize>,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        RequestOptions {
+            connect_timeout: Some(Duration::from_secs(10)),
+            timeout: None,
+            follow_redirects: None,
+        }
+    }
+}
 
 pub struct Http;
 impl Http {
-    pub fn http_get_slow(url: &str, delay_ms: u32, cb: impl Fn(Js) + 'static + Clone) {
-        let rt: &mut Runtime = unsafe { &mut *RUNTIME };
-        let adr = "slowwly.robertomurray.co.uk:80";
-        let mut stream = minimio::TcpStream::connect(adr).unwrap();
-
-        let request = format!(
-            "GET /delay/{}/url/http://{} HTTP/1.1\r\n\
-             Host: slowwly.robertomurray.co.uk\r\n\
-             Connection: close\r\n\
-             \r\n",
-            delay_ms, url
+    /// Sends `request` and calls back with the response as a `Js::Object`,
+    /// see `Response::into_js`. Bad URLs, connection failures and malformed
+    /// responses give a `Js::Error`. Only `http://` URLs are supported.
+    pub fn request(request: Request, options: RequestOptions, cb: impl Fn(Js) + 'static) {
+        let cb: Rc<dyn Fn(Js)> = Rc::new(cb);
+        if let Err(e) = client::send(request, options, cb.clone()) {
+            queue_microtask(move || cb(Js::Error(e)));
+        }
+    }
+
+    /// A server that calls `handler` for every request once it `listen`s.
+    /// The handler answers through the `ResponseWriter`, right away or
+    /// from later callbacks.
+    pub fn create_server(handler: impl Fn(ServerRequest, ResponseWriter) + 'static) -> Server {
+        Server::new(Rc::new(handler))
+    }
+
+    /// Changes how the current runtime keeps connections around between
+    /// requests. Connections already open stay as they are.
+    pub fn configure_pool(options: PoolOptions) {
+        runtime().http_pool.options = options;
+    }
+
+    /// Shorthand for a plain GET, see `Http::request`.
+    pub fn get(url: &str, options: RequestOptions, cb: impl Fn(Js) + 'static) {
+        Http::request(Request::get(url), options, cb)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::{set_timeout, Runtime};
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// The head and body of a request a test server got.
+    type Received = (String, Vec<u8>);
+
+    /// Answers one connection with `response` and returns the request it
+    /// got.
+    fn serve_once(response: &'static str) -> (String, thread::JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request(&mut stream);
+            stream.write_all(closing(response).as_bytes()).unwrap();
+            request
+        });
+        (url, server)
+    }
+
+    /// Answers a connection with each of `responses` in turn.
+    fn serve(responses: Vec<String>) -> (String, thread::JoinHandle<Vec<Received>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                requests.push(read_request(&mut stream));
+                stream.write_all(closing(&response).as_bytes()).unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    /// The test servers answer one request per connection, so they tell
+    /// the client not to keep it.
+    fn closing(response: &str) -> String {
+        response.replacen("\r\n", "\r\nConnection: close\r\n", 1)
+    }
+
+    fn read_head(stream: &mut TcpStream) -> String {
+        read_request(stream).0
+    }
+
+    /// Reads byte by byte so nothing past the request is consumed. Chunked
+    /// bodies are decoded.
+    fn read_request(stream: &mut TcpStream) -> (String, Vec<u8>) {
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(read_byte(stream));
+        }
+        let head = String::from_utf8(head).unwrap();
+
+        let mut body = vec![];
+        if head.contains("Transfer-Encoding: chunked\r\n") {
+            loop {
+                let mut size = String::new();
+                while !size.ends_with("\r\n") {
+                    size.push(read_byte(stream) as char);
+                }
+                let size = usize::from_str_radix(size.trim_end(), 16).unwrap();
+                let mut chunk = vec![0; size + 2];
+                stream.read_exact(&mut chunk).unwrap();
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        } else if let Some(line) = head.lines().find(|l| l.starts_with("Content-Length: ")) {
+            body.resize(line[16..].parse().unwrap(), 0);
+            stream.read_exact(&mut body).unwrap();
+        }
+        (head, body)
+    }
+
+    fn read_byte(stream: &mut TcpStream) -> u8 {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    #[test]
+    fn test_get_from_local_server() {
+        let (url, server) = serve_once(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nX-Id: 7\r\n\r\n2\r\nhi\r\n0\r\n\r\n",
         );
+        let port = url.rsplit(':').next().unwrap().to_string();
+
+        Runtime::new()
+            .run(move || {
+                Http::get(
+                    &format!("{}/a?b=c#d", url),
+                    RequestOptions::default(),
+                    |res| {
+                        let mut response = res.into_object().unwrap();
+                        assert!(matches!(response.get("status"), Some(Js::Int(200))));
+                        let body = response.remove("body").unwrap().into_bytes().unwrap();
+                        assert_eq!(body, b"hi");
+                        let mut headers =
+                            response.remove("headers").unwrap().into_object().unwrap();
+                        let mut ids = headers.remove("x-id").unwrap().into_array().unwrap();
+                        assert_eq!(ids.pop().unwrap().into_string().unwrap(), "7");
+                    },
+                );
+            })
+            .unwrap();
+
+        let (request, _) = server.join().unwrap();
+        assert!(request.starts_with("GET /a?b=c HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("Host: 127.0.0.1:{}\r\n", port)));
+    }
+
+    /// The server dribbles the response out and keeps the connection open,
+    /// so the client has to read it in pieces and stop at `Content-Length`.
+    #[test]
+    fn test_get_reads_incrementally_without_blocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/slow", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_head(&mut stream);
+            for piece in &["HTTP/1.1 200 OK\r\nContent-", "Length: 5\r\n\r\nhel", "lo"] {
+                thread::sleep(Duration::from_millis(30));
+                stream.write_all(piece.as_bytes()).unwrap();
+            }
+            // Returns once the client hangs up.
+            stream.read(&mut [0; 1]).unwrap()
+        });
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let l = log.clone();
+        Runtime::new()
+            .run(move || {
+                let timer_log = l.clone();
+                set_timeout(10, move |_| {
+                    timer_log.borrow_mut().push("timer".to_string())
+                });
+                Http::get(&url, RequestOptions::default(), move |res| {
+                    let mut response = res.into_object().unwrap();
+                    let body = response.remove("body").unwrap().into_bytes().unwrap();
+                    l.borrow_mut().push(String::from_utf8(body).unwrap());
+                });
+            })
+            .unwrap();
+
+        let log = log.borrow();
+        assert_eq!(log[0], "timer");
+        assert_eq!(log[1], "hello");
+        assert_eq!(server.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_get_errors() {
+        // Nothing listens on the port once the listener is gone.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let refused = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+
+        // Accepts but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent = format!("http://{}/", listener.local_addr().unwrap());
+
+        Runtime::new()
+            .run(move || {
+                Http::get(&refused, RequestOptions::default(), |res| {
+                    let e = res.into_error().unwrap();
+                    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+                });
+                // Names are looked up on the pool first.
+                Http::get("http://localhost:1/", RequestOptions::default(), |res| {
+                    let e = res.into_error().unwrap();
+                    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+                });
+                Http::get("ftp://localhost/", RequestOptions::default(), |res| {
+                    let e = res.into_error().unwrap();
+                    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+                });
+                let options = RequestOptions {
+                    timeout: Some(Duration::from_millis(50)),
+                    ..RequestOptions::default()
+                };
+                Http::get(&silent, options, |res| {
+                    let e = res.into_error().unwrap();
+                    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                });
+            })
+            .unwrap();
+        drop(listener);
+    }
+
+    #[test]
+    fn test_request_with_bodies() {
+        const OK: &str = "HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok";
+        let (json_url, json_server) = serve_once(OK);
+        let (file_url, file_server) = serve_once(OK);
+        // No body follows even though the length says there is one.
+        let (head_url, head_server) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+
+        // Several chunks of the file get streamed.
+        let file = std::env::temp_dir().join(format!("adven-upload-{}", std::process::id()));
+        let content: Vec<u8> = (0..40_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&file, &content).unwrap();
+
+        let statuses = Rc::new(RefCell::new(vec![]));
+        let s = statuses.clone();
+        let upload = file.clone();
+        Runtime::new()
+            .run(move || {
+                let mut item = std::collections::BTreeMap::new();
+                item.insert("name".to_string(), Js::String("a \"b\"".to_string()));
+                let request = Request::post(&format!("{}/items", json_url))
+                    .query("dry run", "1")
+                    .basic_auth("user", Some("pass"))
+                    .json(&Js::Object(item));
+                for request in [
+                    request,
+                    Request::put(&format!("{}/upload", file_url)).body_file(&upload),
+                    Request::head(&head_url),
+                ] {
+                    let s = s.clone();
+                    Http::request(request, RequestOptions::default(), move |res| {
+                        let mut response = res.into_object().unwrap();
+                        let status = response.remove("status").unwrap().into_int().unwrap();
+                        s.borrow_mut().push(status);
+                    });
+                }
+            })
+            .unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        let mut statuses = statuses.borrow().clone();
+        statuses.sort_unstable();
+        assert_eq!(statuses, [200, 201, 201]);
+
+        let (head, body) = json_server.join().unwrap();
+        assert!(head.starts_with("POST /items?dry+run=1 HTTP/1.1\r\n"));
+        assert!(head.contains("Authorization: Basic dXNlcjpwYXNz\r\n"));
+        assert!(head.contains("Content-Type: application/json\r\n"));
+        assert_eq!(body, br#"{"name":"a \"b\""}"#);
+
+        let (head, body) = file_server.join().unwrap();
+        assert!(head.starts_with("PUT /upload HTTP/1.1\r\n"));
+        assert_eq!(body, content);
+
+        let (head, body) = head_server.join().unwrap();
+        assert!(head.starts_with("HEAD / HTTP/1.1\r\n"));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_follow_redirects() {
+        let (final_url, final_server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone".to_string(),
+        ]);
+        let (url, server) = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: next?x=1\r\nContent-Length: 5\r\n\r\nmoved".to_string(),
+            format!(
+                "HTTP/1.1 307 Temporary Redirect\r\nLocation: {}/final\r\nContent-Length: 0\r\n\r\n",
+                final_url
+            ),
+        ]);
+        let moved = "HTTP/1.1 301 Moved Permanently\r\nLocation: /\r\nContent-Length: 0\r\n\r\n";
+        let (loop_url, loop_server) = serve(vec![moved.to_string(), moved.to_string()]);
+        let (raw_url, raw_server) = serve_once(moved);
+
+        let expected_url = format!("{}/final", final_url);
+        let expected_redirects = format!("302 {0}/start/, 307 {0}/start/next?x=1", url);
+        let done = Rc::new(RefCell::new(0));
+        let d = done.clone();
+        Runtime::new()
+            .run(move || {
+                let follow = |max| RequestOptions {
+                    follow_redirects: Some(max),
+                    ..RequestOptions::default()
+                };
+                let request = Request::post(&format!("{}/start/", url))
+                    .basic_auth("user", None)
+                    .json(&Js::Int(1));
+                let done = d.clone();
+                Http::request(request, follow(5), move |res| {
+                    let mut response = res.into_object().unwrap();
+                    let mut field = |name: &str| response.remove(name).unwrap();
+                    assert_eq!(field("status").into_int().unwrap(), 200);
+                    assert_eq!(field("body").into_bytes().unwrap(), b"done");
+                    assert_eq!(field("url").into_string().unwrap(), expected_url);
+                    let redirects: Vec<String> = field("redirects")
+                        .into_array()
+                        .unwrap()
+                        .into_iter()
+                        .map(|hop| {
+                            let mut hop = hop.into_object().unwrap();
+                            let status = hop.remove("status").unwrap().into_int().unwrap();
+                            let url = hop.remove("url").unwrap().into_string().unwrap();
+                            format!("{} {}", status, url)
+                        })
+                        .collect();
+                    assert_eq!(redirects.join(", "), expected_redirects);
+                    *done.borrow_mut() += 1;
+                });
 
-        stream
-            .write_all(request.as_bytes())
-            .expect("Error writing to stream");
+                let done = d.clone();
+                Http::get(&loop_url, follow(1), move |res| {
+                    assert_eq!(
+                        res.into_error().unwrap().to_string(),
+                        "stopped after 1 redirects"
+                    );
+                    *done.borrow_mut() += 1;
+                });
 
-        let token = rt.generate_cb_identity();
-        rt.epoll_registrator
-            .register(&stream, token, minimio::Interests::READABLE)
+                let done = d.clone();
+                Http::get(&raw_url, RequestOptions::default(), move |res| {
+                    let mut response = res.into_object().unwrap();
+                    assert!(matches!(response.get("status"), Some(Js::Int(301))));
+                    let redirects = response.remove("redirects").unwrap();
+                    assert!(redirects.into_array().unwrap().is_empty());
+                    *done.borrow_mut() += 1;
+                });
+            })
             .unwrap();
+        assert_eq!(*done.borrow(), 3);
 
-        let wrapped = move |_n| {
-            let mut stream = stream;
-            let mut buffer = String::new();
-            stream
-                .read_to_string(&mut buffer)
-                .expect("Stream read error");
-            cb(Js::String(buffer));
-        };
+        let requests = server.join().unwrap();
+        assert!(requests[0].0.starts_with("POST /start/ HTTP/1.1\r\n"));
+        assert_eq!(requests[0].1, b"1");
+        // 302 turns the POST into a GET without a body.
+        let (head, body) = &requests[1];
+        assert!(head.starts_with("GET /start/next?x=1 HTTP/1.1\r\n"));
+        assert!(head.contains("Authorization: "));
+        assert!(!head.contains("Content-"));
+        assert!(body.is_empty());
+        // Credentials stay with the origin they were meant for.
+        let (head, _) = &final_server.join().unwrap()[0];
+        assert!(head.starts_with("GET /final HTTP/1.1\r\n"));
+        assert!(!head.contains("Authorization: "));
 
-        rt.register_event_epoll(token, wrapped);
+        assert_eq!(loop_server.join().unwrap().len(), 2);
+        raw_server.join().unwrap();
+    }
+
+    #[test]
+    fn test_keep_alive_pool() {
+        // Answers requests on one connection for as long as it's open, and
+        // counts them.
+        fn serve_keep_alive(listener: &TcpListener) -> usize {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1];
+            let mut served = 0;
+            while stream.peek(&mut buf).unwrap() > 0 {
+                read_request(&mut stream);
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n{}", served);
+                stream.write_all(response.as_bytes()).unwrap();
+                served += 1;
+            }
+            served
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            // Three requests share a connection, which gets closed once it
+            // has been idle a while. The fourth needs a new one.
+            vec![serve_keep_alive(&listener), serve_keep_alive(&listener)]
+        });
+
+        let bodies = Rc::new(RefCell::new(vec![]));
+        let b = bodies.clone();
+        Runtime::new()
+            .run(move || {
+                Http::configure_pool(PoolOptions {
+                    max_sockets_per_host: 1,
+                    idle_timeout: Duration::from_millis(100),
+                });
+                let get = move |bodies: Rc<RefCell<Vec<String>>>| {
+                    Http::get(&url, RequestOptions::default(), move |res| {
+                        let mut response = res.into_object().unwrap();
+                        let body = response.remove("body").unwrap().into_bytes().unwrap();
+                        bodies.borrow_mut().push(String::from_utf8(body).unwrap());
+                    })
+                };
+                // Over the limit, so they wait for the connection in turn.
+                for _ in 0..3 {
+                    get(b.clone());
+                }
+                let b = b.clone();
+                set_timeout(300, move |_| get(b.clone()));
+            })
+            .unwrap();
+
+        assert_eq!(*bodies.borrow(), ["0", "1", "2", "0"]);
+        assert_eq!(server.join().unwrap(), [3, 1]);
+
+        // The loop doesn't wait for idle connections to time out, and they
+        // get closed when it's done.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || serve_keep_alive(&listener));
+        let start = std::time::Instant::now();
+        Runtime::new()
+            .run(move || {
+                Http::configure_pool(PoolOptions {
+                    idle_timeout: Duration::from_secs(30),
+                    ..PoolOptions::default()
+                });
+                Http::get(&url, RequestOptions::default(), |res| {
+                    assert!(matches!(res, Js::Object(_)));
+                });
+            })
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(server.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_timeout_while_waiting_for_a_connection() {
+        // Takes the one connection allowed and never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request(&mut stream);
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest).unwrap();
+        });
+
+        let timings = Rc::new(RefCell::new(vec![]));
+        let t = timings.clone();
+        let start = std::time::Instant::now();
+        Runtime::new()
+            .run(move || {
+                Http::configure_pool(PoolOptions {
+                    max_sockets_per_host: 1,
+                    ..PoolOptions::default()
+                });
+                let options = RequestOptions {
+                    timeout: Some(Duration::from_millis(200)),
+                    ..RequestOptions::default()
+                };
+                for _ in 0..2 {
+                    let t = t.clone();
+                    Http::get(&url, options.clone(), move |res| {
+                        let e = res.into_error().unwrap();
+                        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                        t.borrow_mut().push(start.elapsed());
+                    });
+                }
+            })
+            .unwrap();
+
+        // Both time out together, not the second one after the first.
+        let timings = timings.borrow();
+        assert_eq!(timings.len(), 2);
+        assert!(timings[1] < Duration::from_millis(350), "{:?}", timings);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_retry_on_closed_idle_connection() {
+        // Answers one request and closes the connection once the next one
+        // is in, then answers on a new connection if `again`.
+        fn serve_then_close(again: bool) -> (String, thread::JoinHandle<Vec<String>>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut heads = vec![read_head(&mut stream)];
+                let response = "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na";
+                stream.write_all(response.as_bytes()).unwrap();
+                heads.push(read_head(&mut stream));
+                drop(stream);
+                if again {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    heads.push(read_head(&mut stream));
+                    let response = "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb";
+                    stream.write_all(closing(response).as_bytes()).unwrap();
+                }
+                heads
+            });
+            (url, server)
+        }
+        fn body(res: Js) -> String {
+            let mut response = res.into_object().unwrap();
+            let body = response.remove("body").unwrap().into_bytes().unwrap();
+            String::from_utf8(body).unwrap()
+        }
+
+        let (url, server) = serve_then_close(true);
+        let bodies = Rc::new(RefCell::new(vec![]));
+        let b = bodies.clone();
+        Runtime::new()
+            .run(move || {
+                Http::get(&url.clone(), RequestOptions::default(), move |res| {
+                    b.borrow_mut().push(body(res));
+                    let b = b.clone();
+                    Http::get(&url, RequestOptions::default(), move |res| {
+                        b.borrow_mut().push(body(res));
+                    });
+                });
+            })
+            .unwrap();
+        assert_eq!(*bodies.borrow(), ["a", "b"]);
+        assert_eq!(server.join().unwrap().len(), 3);
+
+        // A POST might have been acted on, so it's not sent again.
+        let (url, server) = serve_then_close(false);
+        let failed = Rc::new(RefCell::new(false));
+        let f = failed.clone();
+        Runtime::new()
+            .run(move || {
+                Http::get(&url.clone(), RequestOptions::default(), move |res| {
+                    assert_eq!(body(res), "a");
+                    let f = f.clone();
+                    let request = Request::post(&url).body("x");
+                    Http::request(request, RequestOptions::default(), move |res| {
+                        assert!(matches!(res, Js::Error(_)));
+                        *f.borrow_mut() = true;
+                    });
+                });
+            })
+            .unwrap();
+        assert!(*failed.borrow());
+        assert_eq!(server.join().unwrap().len(), 2);
     }
 }
@@ -0,0 +1,660 @@
+//! The client side of one request as a state machine driven by epoll. The
+//! connection comes from the runtime's pool if there's an idle one, and
+//! goes back to it afterwards if the server allows. Name lookup goes to the
+//! thread pool like in Node; connecting, sending and receiving happen on a
+//! non-blocking socket, a bit at a time whenever it's ready.
+use super::pool::{self, Key};
+use super::request::Body;
+use super::response::{Redirect, Response, ResponseParser};
+use super::{Request, RequestOptions, Url};
+use crate::fs::{Fs, ReadStream, HIGH_WATER_MARK};
+use crate::poll::Interests;
+use crate::runtime::{
+    clear_timeout, queue_microtask, runtime, set_timeout, Js, ThreadPoolTaskKind, TimerId,
+};
+use crate::sys;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const READ_CHUNK: usize = 16 * 1024;
+
+enum State {
+    /// Waiting for the socket to turn writable.
+    Connecting,
+    Writing,
+    Reading,
+}
+
+type OnResponse = Box<dyn FnOnce(io::Result<Response>)>;
+
+/// What has to happen before the exchange can go on.
+enum Progress {
+    Wait(Interests),
+    /// Everything queued is sent, but more of the body is still being read
+    /// from its file.
+    NeedBody,
+    Done(Response),
+}
+
+/// What it takes to send a request, again if need be.
+#[derive(Clone)]
+struct Attempt {
+    key: Key,
+    url: Url,
+    request: Request,
+    options: RequestOptions,
+    /// When `options.timeout` runs out, counted from when the request was
+    /// made.
+    deadline: Option<Instant>,
+}
+
+/// One request on a connection of its own until it's done. Shared by the
+/// epoll callback, the timer and the body's file stream; the first to fail
+/// or complete it finishes it.
+struct Exchange {
+    key: Key,
+    /// Taken when the exchange is finished.
+    stream: Option<TcpStream>,
+    /// The other addresses the host resolved to, tried in turn if
+    /// connecting fails.
+    addrs: VecDeque<SocketAddr>,
+    token: usize,
+    state: State,
+    /// Bytes to send, of which `written` have been.
+    out: Vec<u8>,
+    written: usize,
+    body: Option<ReadStream>,
+    body_done: bool,
+    /// Neither armed with epoll nor running, until more body arrives.
+    idle: bool,
+    parser: ResponseParser,
+    /// Whether the request lets the connection be reused.
+    keep_alive: bool,
+    /// Whether any of the response has come in.
+    received: bool,
+    /// Set for idempotent requests on a connection from the pool. The server
+    /// may have closed it just as it was handed out, so if it fails before
+    /// any response arrives, the request is sent once more on a new one.
+    retry: Option<Attempt>,
+    deadline: Option<Instant>,
+    timer: Option<TimerId>,
+    /// Taken by whichever finishes the exchange.
+    cb: Option<OnResponse>,
+}
+
+pub(super) fn send(
+    request: Request,
+    options: RequestOptions,
+    cb: Rc<dyn Fn(Js)>,
+) -> io::Result<()> {
+    let url = request.url()?;
+    request.check_headers()?;
+    follow(request, url, options, vec![], cb)
+}
+
+/// Sends `request` to `url`, and on a redirect, sends the rewritten request
+/// to where it points as long as `options` allow it. `redirects` are the
+/// ones followed so far.
+fn follow(
+    request: Request,
+    url: Url,
+    options: RequestOptions,
+    redirects: Vec<Redirect>,
+    cb: Rc<dyn Fn(Js)>,
+) -> io::Result<()> {
+    if url.scheme != "http" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not supported", url.scheme),
+        ));
+    }
+
+    let on_response: OnResponse = {
+        let (request, url, options, cb) =
+            (request.clone(), url.clone(), options.clone(), cb.clone());
+        Box::new(move |res| on_response(res, request, url, options, redirects, cb))
+    };
+    // The timeout includes waiting in line for a connection.
+    let attempt = Attempt {
+        key: (url.host.clone(), url.port),
+        deadline: options.timeout.map(|timeout| Instant::now() + timeout),
+        url,
+        request,
+        options,
+    };
+    let (key, timeout) = (attempt.key.clone(), attempt.options.timeout);
+    pool::acquire(
+        key,
+        timeout,
+        Box::new(move |idle| match idle {
+            Ok(idle) => connect(attempt, idle, on_response),
+            Err(e) => on_response(Err(e)),
+        }),
+    );
+    Ok(())
+}
+
+/// Sends the request on `idle`, or on a new connection if there is none.
+fn connect(attempt: Attempt, idle: Option<TcpStream>, cb: OnResponse) {
+    match idle {
+        Some(stream) => start(stream, false, VecDeque::new(), attempt, cb),
+        None => {
+            let (host, port) = attempt.key.clone();
+            lookup(host, port, move |addrs| match addrs {
+                Ok(addrs) => open(addrs, attempt, cb),
+                Err(e) => fail(&attempt.key, e, cb),
+            })
+        }
+    }
+}
+
+/// Sends the request on a new connection to the first of `addrs` that
+/// works. Like `localhost`, a host may resolve to an IPv6 address nothing
+/// listens on before an IPv4 one that does.
+fn open(mut addrs: VecDeque<SocketAddr>, attempt: Attempt, cb: OnResponse) {
+    match connect_any(&mut addrs) {
+        Ok(stream) => start(stream, true, addrs, attempt, cb),
+        Err(e) => fail(&attempt.key, e, cb),
+    }
+}
+
+/// Starts connecting to the first of `addrs` that doesn't fail right away,
+/// leaving the ones after it. Fails with the last error if none is left.
+fn connect_any(addrs: &mut VecDeque<SocketAddr>) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(io::ErrorKind::NotFound, "no addresses left");
+    while let Some(addr) = addrs.pop_front() {
+        match sys::connect_nonblocking(&addr) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Gives back the connection from `pool::acquire` and calls back with `e`.
+fn fail(key: &Key, e: io::Error, cb: OnResponse) {
+    pool::release(key, None);
+    // Never before `send` has returned.
+    queue_microtask(move || cb(Err(e)));
+}
+
+/// Calls back with the response, unless it's a redirect to follow.
+fn on_response(
+    res: io::Result<Response>,
+    request: Request,
+    url: Url,
+    options: RequestOptions,
+    mut redirects: Vec<Redirect>,
+    cb: Rc<dyn Fn(Js)>,
+) {
+    let mut response = match res {
+        Ok(response) => response,
+        Err(e) => return cb(Js::Error(e)),
+    };
+    let (max, location) = match (options.follow_redirects, response.headers.get("Location")) {
+        (Some(max), Some(location)) if is_redirect(response.status) => (max, location),
+        _ => {
+            response.url = url.to_string();
+            response.redirects = redirects;
+            return cb(response.into_js());
+        }
+    };
+
+    let next = if redirects.len() < max {
+        url.join(location)
+    } else {
+        Err(io::Error::other(format!("stopped after {} redirects", max)))
+    };
+    let res = next.and_then(|next| {
+        let request = request.redirect(response.status, &url, &next);
+        redirects.push(Redirect {
+            status: response.status,
+            url: url.to_string(),
+        });
+        follow(request, next, options, redirects, cb.clone())
+    });
+    if let Err(e) = res {
+        cb(Js::Error(e));
+    }
+}
+
+fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+/// IP addresses are used as they are. Names are resolved on the pool since
+/// `getaddrinfo` blocks.
+fn lookup(host: String, port: u16, cb: impl FnOnce(io::Result<VecDeque<SocketAddr>>) + 'static) {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return cb(Ok(VecDeque::from(vec![SocketAddr::new(ip, port)])));
+    }
+
+    let work = move || {
+        let addrs = (host.as_str(), port).to_socket_addrs().and_then(|addrs| {
+            let addrs: Vec<_> = addrs.map(|addr| Js::String(addr.to_string())).collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no addresses", host),
+                ));
+            }
+            Ok(addrs)
+        });
+        match addrs {
+            Ok(addrs) => Js::Array(addrs),
+            Err(e) => Js::Error(e),
+        }
+    };
+    let rt = runtime();
+    rt.register_event_threadpool(work, ThreadPoolTaskKind::Lookup, move |res| match res {
+        Js::Error(e) => cb(Err(e)),
+        addrs => {
+            let addrs = addrs.into_array().unwrap().into_iter();
+            cb(Ok(addrs
+                .map(|addr| addr.into_string().unwrap().parse().unwrap())
+                .collect()))
+        }
+    });
+}
+
+/// Sends the request on `stream`, which is still `connecting` unless it came
+/// from the pool. `addrs` are the ones to try next if connecting fails.
+fn start(
+    stream: TcpStream,
+    connecting: bool,
+    addrs: VecDeque<SocketAddr>,
+    attempt: Attempt,
+    cb: OnResponse,
+) {
+    let rt = runtime();
+    let token = rt.generate_cb_identity();
+    let registered = rt
+        .epoll_registrator
+        .register(&stream, token, Interests::WRITABLE);
+    if let Err(e) = registered {
+        return fail(&attempt.key, e, cb);
+    }
+
+    let retry = if !connecting && attempt.request.idempotent() {
+        Some(attempt.clone())
+    } else {
+        None
+    };
+    let Attempt {
+        key,
+        url,
+        request,
+        options,
+        deadline,
+    } = attempt;
+    let mut out = request.encode_head(&url);
+    let keep_alive = request.keep_alive();
+    let (body, body_done) = match request.body {
+        Body::Empty => (None, true),
+        Body::Bytes(bytes) => {
+            out.extend(bytes);
+            (None, true)
+        }
+        Body::File(path) => (Some(Fs::create_read_stream(path, HIGH_WATER_MARK)), false),
+    };
+    let exchange = Rc::new(RefCell::new(Exchange {
+        key,
+        stream: Some(stream),
+        addrs,
+        token,
+        state: if connecting {
+            State::Connecting
+        } else {
+            State::Writing
+        },
+        out,
+        written: 0,
+        body: body.clone(),
+        body_done,
+        idle: false,
+        parser: ResponseParser::new(request.method),
+        keep_alive,
+        received: false,
+        retry,
+        deadline,
+        timer: None,
+        cb: Some(cb),
+    }));
+    let connect_timeout = if connecting {
+        options.connect_timeout
+    } else {
+        None
+    };
+    arm_timer(&exchange, connect_timeout);
+    if let Some(body) = body {
+        stream_body(&exchange, &body);
+    }
+    wait(&exchange);
+}
+
+/// Queues the file in chunked encoding as it's read. Reading pauses while
+/// more than a chunk is waiting to be sent.
+fn stream_body(exchange: &Rc<RefCell<Exchange>>, body: &ReadStream) {
+    let ex = exchange.clone();
+    let reader = body.clone();
+    body.on_data(move |chunk| {
+        let chunk = chunk.into_bytes().unwrap();
+        let mut e = ex.borrow_mut();
+        e.out.extend(format!("{:x}\r\n", chunk.len()).into_bytes());
+        e.out.extend(chunk);
+        e.out.extend(b"\r\n");
+        if e.out.len() - e.written > HIGH_WATER_MARK {
+            reader.pause();
+        }
+        drop(e);
+        wake(&ex);
+    });
+    let ex = exchange.clone();
+    body.on_end(move |_| {
+        let mut e = ex.borrow_mut();
+        e.out.extend(b"0\r\n\r\n");
+        e.body_done = true;
+        drop(e);
+        wake(&ex);
+    });
+    let ex = exchange.clone();
+    body.on_error(move |e| finish(&ex, Err(e.into_error().unwrap())));
+}
+
+/// Goes on sending once more body is queued, if the exchange was waiting
+/// for it.
+fn wake(exchange: &Rc<RefCell<Exchange>>) {
+    let idle = std::mem::replace(&mut exchange.borrow_mut().idle, false);
+    if idle {
+        on_ready(exchange);
+    }
+}
+
+fn wait(exchange: &Rc<RefCell<Exchange>>) {
+    let token = exchange.borrow().token;
+    let ex = exchange.clone();
+    runtime().register_event_epoll(token, move |_| on_ready(&ex));
+}
+
+fn on_ready(exchange: &Rc<RefCell<Exchange>>) {
+    if exchange.borrow().cb.is_none() {
+        return;
+    }
+    let was_connecting = matches!(exchange.borrow().state, State::Connecting);
+    let progress = exchange.borrow_mut().advance();
+    if was_connecting && progress.is_ok() {
+        arm_timer(exchange, None);
+    }
+
+    match progress {
+        Ok(Progress::Wait(interests)) => {
+            let ex = exchange.borrow();
+            let stream = ex.stream.as_ref().unwrap();
+            let rearm = runtime()
+                .epoll_registrator
+                .reregister(stream, ex.token, interests);
+            drop(ex);
+            match rearm {
+                Ok(()) => wait(exchange),
+                Err(e) => finish(exchange, Err(e)),
+            }
+        }
+        Ok(Progress::NeedBody) => {
+            let body = {
+                let mut ex = exchange.borrow_mut();
+                ex.idle = true;
+                ex.body.clone()
+            };
+            if let Some(body) = body {
+                body.resume();
+            }
+        }
+        Ok(Progress::Done(response)) => finish(exchange, Ok(response)),
+        Err(_) if exchange.borrow().can_fall_back() => reconnect(exchange),
+        Err(e) => {
+            let retry = {
+                let mut ex = exchange.borrow_mut();
+                if ex.received {
+                    None
+                } else {
+                    ex.retry.take()
+                }
+            };
+            match retry {
+                Some(attempt) => resend(exchange, attempt),
+                None => finish(exchange, Err(e)),
+            }
+        }
+    }
+}
+
+impl Exchange {
+    /// Whether connecting failed, but there are other addresses to try.
+    fn can_fall_back(&self) -> bool {
+        matches!(self.state, State::Connecting) && !self.addrs.is_empty()
+    }
+
+    /// Does as much as the socket allows without blocking.
+    fn advance(&mut self) -> io::Result<Progress> {
+        let mut stream = self.stream.as_ref().unwrap();
+        loop {
+            match self.state {
+                State::Connecting => {
+                    if let Some(e) = stream.take_error()? {
+                        return Err(e);
+                    }
+                    self.state = State::Writing;
+                }
+                State::Writing => {
+                    while self.written < self.out.len() {
+                        match stream.write(&self.out[self.written..]) {
+                            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                            Ok(n) => self.written += n,
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                return Ok(Progress::Wait(Interests::WRITABLE));
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    self.out.clear();
+                    self.written = 0;
+                    if !self.body_done {
+                        return Ok(Progress::NeedBody);
+                    }
+                    self.state = State::Reading;
+                }
+                State::Reading => {
+                    let mut chunk = vec![0; READ_CHUNK];
+                    loop {
+                        match stream.read(&mut chunk) {
+                            Ok(0) => return self.parser.finish().map(Progress::Done),
+                            Ok(n) => {
+                                self.received = true;
+                                if let Some(response) = self.parser.feed(&chunk[..n])? {
+                                    return Ok(Progress::Done(response));
+                                }
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                return Ok(Progress::Wait(Interests::READABLE));
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Replaces the connection that couldn't be established with one to the
+/// next address that works, under the same token.
+fn reconnect(exchange: &Rc<RefCell<Exchange>>) {
+    let mut ex = exchange.borrow_mut();
+    let rt = runtime();
+    let token = ex.token;
+    let next = connect_any(&mut ex.addrs).and_then(|stream| {
+        rt.epoll_registrator
+            .register(&stream, token, Interests::WRITABLE)?;
+        Ok(stream)
+    });
+    match next {
+        Ok(stream) => {
+            let failed = ex.stream.replace(stream).unwrap();
+            let _ = rt.epoll_registrator.deregister(&failed);
+            rt.register_close_callback(move |_| drop(failed));
+            drop(ex);
+            wait(exchange);
+        }
+        Err(e) => {
+            drop(ex);
+            finish(exchange, Err(e));
+        }
+    }
+}
+
+/// Fails the exchange at the request's deadline, or once `connect_timeout`
+/// has passed if that's sooner.
+fn arm_timer(exchange: &Rc<RefCell<Exchange>>, connect_timeout: Option<Duration>) {
+    let deadline = exchange.borrow().deadline;
+    let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    match connect_timeout {
+        Some(connect) if left.is_none_or(|left| connect < left) => {
+            restart_timer(exchange, Some(connect), "connecting timed out")
+        }
+        _ => restart_timer(exchange, left, "request timed out"),
+    }
+}
+
+/// Replaces the running timer, if any, with one that fails the exchange
+/// after `timeout`.
+fn restart_timer(exchange: &Rc<RefCell<Exchange>>, timeout: Option<Duration>, msg: &'static str) {
+    if let Some(timer) = exchange.borrow_mut().timer.take() {
+        clear_timeout(timer);
+    }
+    if let Some(timeout) = timeout {
+        let ex = exchange.clone();
+        let timer = set_timeout(timeout.as_millis() as u64, move |_| {
+            let timed_out = io::Error::new(io::ErrorKind::TimedOut, msg);
+            finish(&ex, Err(timed_out));
+        });
+        exchange.borrow_mut().timer = Some(timer);
+    }
+}
+
+/// Hands the connection back to the pool and calls back. A connection that
+/// can't be reused is closed in the close phase. Only the first call does
+/// anything.
+fn finish(exchange: &Rc<RefCell<Exchange>>, res: io::Result<Response>) {
+    if let Some((cb, key, stream, reuse)) = close(exchange, res.is_ok()) {
+        if reuse {
+            pool::release(&key, Some(stream));
+        } else {
+            pool::release(&key, None);
+            runtime().register_close_callback(move |_| drop(stream));
+        }
+        cb(res);
+    }
+}
+
+/// Sends the request again on a new connection, which takes the place of
+/// this one in the pool.
+fn resend(exchange: &Rc<RefCell<Exchange>>, attempt: Attempt) {
+    if let Some((cb, _, stream, _)) = close(exchange, false) {
+        runtime().register_close_callback(move |_| drop(stream));
+        connect(attempt, None, cb);
+    }
+}
+
+/// Cancels whatever is still waiting and takes the callback and connection,
+/// with whether the connection can be reused after it went `ok`. Only the
+/// first call gets them.
+fn close(exchange: &Rc<RefCell<Exchange>>, ok: bool) -> Option<(OnResponse, Key, TcpStream, bool)> {
+    let (cb, body, key, stream, reuse) = {
+        let mut ex = exchange.borrow_mut();
+        let cb = ex.cb.take()?;
+
+        if let Some(timer) = ex.timer.take() {
+            clear_timeout(timer);
+        }
+        let stream = ex.stream.take().unwrap();
+        let rt = runtime();
+        let _ = rt.epoll_registrator.deregister(&stream);
+        rt.deregister_event_epoll(ex.token);
+
+        // All of the request went out and all of the response came in.
+        let reuse =
+            ok && ex.keep_alive && ex.parser.keep_alive() && ex.body_done && ex.out.is_empty();
+        (cb, ex.body.take(), ex.key.clone(), stream, reuse)
+    };
+    // Its listeners hold on to the exchange.
+    if let Some(body) = body {
+        body.destroy();
+    }
+    let exchange = exchange.clone();
+    runtime().register_close_callback(move |_| drop(exchange));
+    Some((cb, key, stream, reuse))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::Runtime;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_fall_back_to_next_address() {
+        // Nothing listens there anymore, or there's no IPv6 at all.
+        let refused = TcpListener::bind("[::1]:0")
+            .map(|listener| listener.local_addr().unwrap())
+            .unwrap_or_else(|_| "[::1]:1".parse().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = vec![];
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        let status = Rc::new(RefCell::new(None));
+        let s = status.clone();
+        Runtime::new()
+            .run(move || {
+                let request = Request::get(&format!("http://localhost:{}/", addr.port()));
+                let attempt = Attempt {
+                    key: ("localhost".to_string(), addr.port()),
+                    url: request.url().unwrap(),
+                    request,
+                    options: RequestOptions::default(),
+                    deadline: None,
+                };
+                // Unreachable, so connecting fails right away.
+                let nowhere = "[fe80::1%999]:1".parse().unwrap();
+                let addrs = VecDeque::from(vec![nowhere, refused, nowhere, refused, addr]);
+                let cb: OnResponse = Box::new(move |res| {
+                    *s.borrow_mut() = Some(res.unwrap().status);
+                });
+                pool::acquire(
+                    attempt.key.clone(),
+                    None,
+                    Box::new(move |_| open(addrs, attempt, cb)),
+                );
+            })
+            .unwrap();
+
+        assert_eq!(*status.borrow(), Some(200));
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+//! Keeps connections open between requests to the same host, like Node's
+//! `http.Agent` with `keepAlive`. Each runtime owns one pool. Requests over
+//! the per-host limit wait in line for a connection to come back.
+use crate::runtime::{clear_timeout, runtime, set_timeout, unref_timer, TimerId};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Host and port.
+pub(super) type Key = (String, u16);
+
+/// Called once the request may go ahead, with an idle connection to use if
+/// there is one. Otherwise it opens a new one. Gets an error instead if the
+/// request timed out while it waited.
+pub(super) type Start = Box<dyn FnOnce(io::Result<Option<TcpStream>>)>;
+
+/// Set with `Http::configure_pool`.
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    /// How many connections to a host may be in use at once.
+    pub max_sockets_per_host: usize,
+    /// Idle connections are closed after this long.
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            max_sockets_per_host: 6,
+            // A bit less than the 5 s Node servers keep them open for.
+            idle_timeout: Duration::from_secs(4),
+        }
+    }
+}
+
+pub(crate) struct Pool {
+    pub(super) options: PoolOptions,
+    hosts: HashMap<Key, Host>,
+}
+
+#[derive(Default)]
+struct Host {
+    /// Handed out by `acquire`, including ones still connecting.
+    active: usize,
+    /// The most recently used last.
+    idle: Vec<Idle>,
+    waiting: VecDeque<Waiting>,
+}
+
+struct Waiting {
+    id: usize,
+    start: Start,
+    timer: Option<TimerId>,
+}
+
+struct Idle {
+    id: usize,
+    stream: TcpStream,
+    timer: TimerId,
+}
+
+impl Pool {
+    pub(crate) fn new() -> Self {
+        Pool {
+            options: PoolOptions::default(),
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+/// Runs `start` as soon as a connection to `key` is free, or with an error
+/// once `timeout` has passed without one. Every `acquire` that gets a
+/// connection has to be matched by a `release`.
+pub(super) fn acquire(key: Key, timeout: Option<Duration>, start: Start) {
+    let id = runtime().generate_cb_identity();
+    let timer = timeout.map(|timeout| {
+        let expired = key.clone();
+        set_timeout(timeout.as_millis() as u64, move |_| time_out(&expired, id))
+    });
+    let pool = &mut runtime().http_pool;
+    let host = pool.hosts.entry(key.clone()).or_default();
+    host.waiting.push_back(Waiting { id, start, timer });
+    dispatch(&key);
+}
+
+/// Gives back a connection from `acquire`. It's kept for the next request
+/// if it's passed in, and closed otherwise.
+pub(super) fn release(key: &Key, stream: Option<TcpStream>) {
+    let pool = &mut runtime().http_pool;
+    let idle_timeout = pool.options.idle_timeout;
+    let host = pool.hosts.get_mut(key).expect("unknown host");
+    host.active -= 1;
+
+    if let Some(stream) = stream {
+        let id = runtime().generate_cb_identity();
+        let evicted = key.clone();
+        let timer = set_timeout(idle_timeout.as_millis() as u64, move |_| {
+            evict(&evicted, id)
+        });
+        // Idle connections don't keep the loop alive.
+        unref_timer(timer);
+        host.idle.push(Idle { id, stream, timer });
+    }
+    dispatch(key);
+}
+
+/// Starts waiting requests while there are connections for them.
+fn dispatch(key: &Key) {
+    loop {
+        let pool = &mut runtime().http_pool;
+        let max_sockets = pool.options.max_sockets_per_host;
+        let host = match pool.hosts.get_mut(key) {
+            Some(host) => host,
+            None => return,
+        };
+        if host.waiting.is_empty() {
+            if host.active == 0 && host.idle.is_empty() {
+                pool.hosts.remove(key);
+            }
+            return;
+        }
+
+        let stream = match host.idle.pop() {
+            Some(idle) => {
+                clear_timeout(idle.timer);
+                if !is_open(&idle.stream) {
+                    continue;
+                }
+                Some(idle.stream)
+            }
+            None if host.active < max_sockets => None,
+            None => return,
+        };
+        host.active += 1;
+        let waiting = host.waiting.pop_front().unwrap();
+        if let Some(timer) = waiting.timer {
+            clear_timeout(timer);
+        }
+        (waiting.start)(Ok(stream));
+    }
+}
+
+/// Gives up on a request that is still waiting for a connection.
+fn time_out(key: &Key, id: usize) {
+    let pool = &mut runtime().http_pool;
+    let host = match pool.hosts.get_mut(key) {
+        Some(host) => host,
+        None => return,
+    };
+    let waiting = match host.waiting.iter().position(|w| w.id == id) {
+        Some(i) => host.waiting.remove(i).unwrap(),
+        None => return,
+    };
+    if host.active == 0 && host.idle.is_empty() && host.waiting.is_empty() {
+        pool.hosts.remove(key);
+    }
+    let timed_out = io::Error::new(io::ErrorKind::TimedOut, "request timed out");
+    (waiting.start)(Err(timed_out));
+}
+
+fn evict(key: &Key, id: usize) {
+    let pool = &mut runtime().http_pool;
+    if let Some(host) = pool.hosts.get_mut(key) {
+        host.idle.retain(|idle| idle.id != id);
+        if host.active == 0 && host.idle.is_empty() && host.waiting.is_empty() {
+            pool.hosts.remove(key);
+        }
+    }
+}
+
+/// The server may have closed an idle connection, or sent something nobody
+/// asked for. Either way it's no use for another request.
+fn is_open(stream: &TcpStream) -> bool {
+    matches!(stream.peek(&mut [0]), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
@@ -0,0 +1,520 @@
+use super::Method;
+use crate::runtime::Js;
+use std::collections::BTreeMap;
+use std::io;
+
+/// Responses with a bigger head than this are rejected rather than buffered.
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+/// Header fields in the order they arrived. Lookups ignore case, and a name
+/// may appear more than once.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers(vec![])
+    }
+
+    /// Adds a field, keeping any that are already there under that name.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    /// The first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether a list-valued field like `Connection` has `token` in any of
+    /// its values.
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Removes every field called `name`.
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+/// A redirect that was followed on the way to the response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub status: u16,
+    /// The URL that answered with the redirect.
+    pub url: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Where the response came from, after any redirects.
+    pub url: String,
+    /// In the order they were followed.
+    pub redirects: Vec<Redirect>,
+}
+
+impl Response {
+    /// `{ status, reason, headers, body, url, redirects }` with `status` a
+    /// `Js::Int` and `body` `Js::Bytes`. As in Node, `headers` has lowercase
+    /// names, so lookups don't depend on how the server spelled them; each
+    /// maps to an array of every value sent under it. `redirects` is an
+    /// array of `{ status, url }`.
+    pub fn into_js(self) -> Js {
+        let mut headers: BTreeMap<String, Js> = BTreeMap::new();
+        for (name, value) in self.headers.0 {
+            let values = headers
+                .entry(name.to_ascii_lowercase())
+                .or_insert_with(|| Js::Array(vec![]));
+            if let Js::Array(values) = values {
+                values.push(Js::String(value));
+            }
+        }
+
+        let mut response = BTreeMap::new();
+        response.insert("status".to_string(), Js::Int(self.status as usize));
+        response.insert("reason".to_string(), Js::String(self.reason));
+        response.insert("headers".to_string(), Js::Object(headers));
+        response.insert("body".to_string(), Js::Bytes(self.body));
+        response.insert("url".to_string(), Js::String(self.url));
+        let redirects = self.redirects.into_iter().map(|redirect| {
+            let mut hop = BTreeMap::new();
+            hop.insert("status".to_string(), Js::Int(redirect.status as usize));
+            hop.insert("url".to_string(), Js::String(redirect.url));
+            Js::Object(hop)
+        });
+        response.insert("redirects".to_string(), Js::Array(redirects.collect()));
+        Js::Object(response)
+    }
+}
+
+/// How the end of the body is found.
+pub(super) enum Framing {
+    Length(usize),
+    Chunked(Chunk),
+    /// Everything until the server closes the connection.
+    Close,
+}
+
+pub(super) enum Chunk {
+    Size,
+    Data(usize),
+    /// The CRLF after the data.
+    DataEnd,
+    Trailers,
+}
+
+/// Builds a `Response` from bytes as they arrive off the socket.
+pub(crate) struct ResponseParser {
+    /// Responses to HEAD have no body, whatever their head says.
+    head_request: bool,
+    /// Whether the server lets the connection be used for another request.
+    keep_alive: bool,
+    buf: Vec<u8>,
+    head: Option<(u16, String, Headers)>,
+    framing: Framing,
+    body: Vec<u8>,
+}
+
+impl ResponseParser {
+    pub fn new(method: Method) -> Self {
+        ResponseParser {
+            head_request: method == Method::Head,
+            keep_alive: false,
+            buf: vec![],
+            head: None,
+            framing: Framing::Close,
+            body: vec![],
+        }
+    }
+
+    /// Takes the next bytes of the response. Returns it once it's complete.
+    pub fn feed(&mut self, data: &[u8]) -> io::Result<Option<Response>> {
+        self.buf.extend_from_slice(data);
+        while self.head.is_none() {
+            if !self.parse_head()? {
+                return Ok(None);
+            }
+        }
+        if self.parse_body()? {
+            return Ok(Some(self.take()));
+        }
+        Ok(None)
+    }
+
+    /// Whether another request can be sent on the connection once `feed`
+    /// returned the response: the server didn't ask to close it, the body
+    /// has its own framing and nothing else came after it.
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive && self.buf.is_empty()
+    }
+
+    /// The server closed the connection. That only ends bodies that aren't
+    /// framed otherwise.
+    pub fn finish(&mut self) -> io::Result<Response> {
+        match (&self.head, &self.framing) {
+            (Some(_), Framing::Close) => {
+                let rest = std::mem::take(&mut self.buf);
+                self.body.extend(rest);
+                Ok(self.take())
+            }
+            _ => Err(malformed(
+                "connection closed before the response was complete",
+            )),
+        }
+    }
+
+    fn take(&mut self) -> Response {
+        let (status, reason, headers) = self.head.take().unwrap();
+        Response {
+            status,
+            reason,
+            headers,
+            body: std::mem::take(&mut self.body),
+            // The client fills these in.
+            url: String::new(),
+            redirects: vec![],
+        }
+    }
+
+    /// Returns whether a whole head was parsed. Interim 1xx responses are
+    /// parsed and dropped.
+    fn parse_head(&mut self) -> io::Result<bool> {
+        let (status_line, headers) = match take_head(&mut self.buf).map_err(|e| malformed(&e))? {
+            Some(head) => head,
+            None => return Ok(false),
+        };
+        let (version, status, reason) = parse_status_line(&status_line)
+            .ok_or_else(|| malformed_line("status line", &status_line))?;
+        let http_11 = version == "HTTP/1.1";
+
+        if (100..200).contains(&status) {
+            return Ok(true);
+        }
+        self.framing = if self.head_request {
+            Framing::Length(0)
+        } else {
+            framing(status, &headers).map_err(|e| malformed(&e))?
+        };
+        // HTTP/1.1 connections stay open unless one side says otherwise,
+        // 1.0 ones only if the server says so.
+        self.keep_alive = !matches!(self.framing, Framing::Close)
+            && if http_11 {
+                !headers.contains_token("connection", "close")
+            } else {
+                headers.contains_token("connection", "keep-alive")
+            };
+        self.head = Some((status, reason, headers));
+        Ok(true)
+    }
+
+    /// Moves what's buffered into the body. Returns whether it's complete.
+    fn parse_body(&mut self) -> io::Result<bool> {
+        self.framing
+            .decode(&mut self.buf, &mut self.body)
+            .map_err(|e| malformed(&e))
+    }
+}
+
+impl Framing {
+    /// Moves the body bytes at the front of `buf` to `body`. Returns whether
+    /// the body is complete; anything after it stays in `buf`.
+    pub(super) fn decode(&mut self, buf: &mut Vec<u8>, body: &mut Vec<u8>) -> Result<bool, String> {
+        loop {
+            match self {
+                Framing::Close => {
+                    body.append(buf);
+                    return Ok(false);
+                }
+                Framing::Length(rest) => {
+                    let n = (*rest).min(buf.len());
+                    body.extend(buf.drain(..n));
+                    *rest -= n;
+                    return Ok(*rest == 0);
+                }
+                Framing::Chunked(Chunk::Data(rest)) => {
+                    let n = (*rest).min(buf.len());
+                    body.extend(buf.drain(..n));
+                    *rest -= n;
+                    if *rest > 0 {
+                        return Ok(false);
+                    }
+                    *self = Framing::Chunked(Chunk::DataEnd);
+                }
+                Framing::Chunked(chunk) => {
+                    let line = match find(buf, b"\r\n") {
+                        Some(end) => {
+                            let line = buf[..end].to_vec();
+                            buf.drain(..end + 2);
+                            line
+                        }
+                        None if buf.len() > MAX_HEAD_LEN => {
+                            return Err("chunk line too long".to_string());
+                        }
+                        None => return Ok(false),
+                    };
+                    let line = String::from_utf8_lossy(&line);
+                    *chunk = match chunk {
+                        Chunk::Size => match parse_chunk_size(&line) {
+                            Some(0) => Chunk::Trailers,
+                            Some(size) => Chunk::Data(size),
+                            None => return Err(bad_line("chunk size", &line)),
+                        },
+                        Chunk::DataEnd if line.is_empty() => Chunk::Size,
+                        Chunk::DataEnd => return Err("chunk data longer than its size".to_string()),
+                        // Trailer fields are dropped.
+                        Chunk::Trailers if line.is_empty() => return Ok(true),
+                        Chunk::Trailers => Chunk::Trailers,
+                        Chunk::Data(_) => unreachable!(),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Splits a complete head off the front of `buf`: the start line and the
+/// header fields. `None` until all of it has arrived.
+pub(super) fn take_head(buf: &mut Vec<u8>) -> Result<Option<(String, Headers)>, String> {
+    let end = match find(buf, b"\r\n\r\n") {
+        Some(end) if end + 4 <= MAX_HEAD_LEN => end,
+        Some(_) => return Err("head too large".to_string()),
+        None if buf.len() > MAX_HEAD_LEN => return Err("head too large".to_string()),
+        None => return Ok(None),
+    };
+    let head =
+        std::str::from_utf8(&buf[..end]).map_err(|_| "head is not valid UTF-8".to_string())?;
+    let mut lines = head.split("\r\n");
+
+    let start_line = lines.next().unwrap_or_default().to_string();
+    let mut headers = Headers::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .filter(|(name, _)| is_token(name))
+            .ok_or_else(|| bad_line("header", line))?;
+        headers.append(name, value.trim());
+    }
+    buf.drain(..end + 4);
+    Ok(Some((start_line, headers)))
+}
+
+/// `HTTP/1.1 200 OK`. The reason may be empty.
+fn parse_status_line(line: &str) -> Option<(&str, u16, String)> {
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next()?;
+    if !matches!(version, "HTTP/1.0" | "HTTP/1.1") {
+        return None;
+    }
+    let code = parts.next()?;
+    if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let reason = parts.next().unwrap_or_default();
+    Some((version, code.parse().ok()?, reason.to_string()))
+}
+
+/// RFC 7230, section 3.3.3.
+fn framing(status: u16, headers: &Headers) -> Result<Framing, String> {
+    if status == 204 || status == 304 {
+        return Ok(Framing::Length(0));
+    }
+    if let Some(chunked) = chunked(headers) {
+        return Ok(if chunked {
+            Framing::Chunked(Chunk::Size)
+        } else {
+            Framing::Close
+        });
+    }
+    Ok(match content_length(headers)? {
+        Some(len) => Framing::Length(len),
+        None => Framing::Close,
+    })
+}
+
+/// Whether `chunked` is the last transfer coding. `None` without any.
+pub(super) fn chunked(headers: &Headers) -> Option<bool> {
+    let coding = headers.get_all("transfer-encoding").last()?;
+    let last = coding.rsplit(',').next().unwrap_or_default().trim();
+    Some(last.eq_ignore_ascii_case("chunked"))
+}
+
+/// `Content-Length` may be repeated, as long as it's the same every time.
+pub(super) fn content_length(headers: &Headers) -> Result<Option<usize>, String> {
+    let mut lengths = headers
+        .get_all("content-length")
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().parse::<usize>());
+    match lengths.next() {
+        None => Ok(None),
+        Some(Ok(len)) if lengths.all(|other| other.as_ref() == Ok(&len)) => Ok(Some(len)),
+        Some(_) => Err("invalid Content-Length".to_string()),
+    }
+}
+
+fn parse_chunk_size(line: &str) -> Option<usize> {
+    let size = line.split(';').next()?.trim();
+    if size.is_empty() {
+        return None;
+    }
+    usize::from_str_radix(size, 16).ok()
+}
+
+/// A header name: visible ASCII without separators.
+pub(super) fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"\"(),/:;<=>?@[\\]{}".contains(&b))
+}
+
+pub(super) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn malformed(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed HTTP response: {}", msg),
+    )
+}
+
+fn malformed_line(what: &str, line: &str) -> io::Error {
+    malformed(&bad_line(what, line))
+}
+
+pub(super) fn bad_line(what: &str, line: &str) -> String {
+    format!("bad {} {:?}", what, line)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Feeds `raw` a few bytes at a time, then reports EOF if the parser
+    /// still wants more.
+    fn parse(raw: &[u8]) -> io::Result<Response> {
+        let mut parser = ResponseParser::new(Method::Get);
+        for piece in raw.chunks(3) {
+            if let Some(response) = parser.feed(piece)? {
+                return Ok(response);
+            }
+        }
+        parser.finish()
+    }
+
+    #[test]
+    fn test_content_length() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\
+                    Set-Cookie: a=1\r\nset-cookie: b=2\r\nContent-Length: 5\r\n\r\nhello";
+        let response = parse(raw).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.reason, "OK");
+        assert_eq!(response.body, b"hello");
+        assert_eq!(response.headers.get("content-type"), Some("text/plain"));
+        assert_eq!(response.headers.get("CONTENT-LENGTH"), Some("5"));
+        let cookies: Vec<_> = response.headers.get_all("Set-Cookie").collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+
+        let js = response.into_js().into_object().unwrap();
+        let mut headers = match js.get("headers") {
+            Some(Js::Object(headers)) => headers.keys().cloned().collect::<Vec<_>>(),
+            _ => panic!("no headers"),
+        };
+        headers.sort();
+        assert_eq!(headers, ["content-length", "content-type", "set-cookie"]);
+
+        // Anything after the body is left alone.
+        let mut parser = ResponseParser::new(Method::Get);
+        let raw = b"HTTP/1.1 204 No Content\r\n\r\nHTTP/1.1";
+        assert_eq!(parser.feed(raw).unwrap().unwrap().body, b"");
+        let mut parser = ResponseParser::new(Method::Head);
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(parser.feed(raw).unwrap().unwrap().body, b"");
+    }
+
+    #[test]
+    fn test_chunked() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+                    5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n";
+        let response = parse(raw).unwrap();
+        assert_eq!(response.body, b"hello, world");
+
+        let e = parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("chunk size"));
+        let truncated = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel";
+        assert!(parse(truncated).is_err());
+    }
+
+    #[test]
+    fn test_close_delimited_and_interim() {
+        let raw = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.0 404 Not Found\r\n\r\nno such page";
+        let response = parse(raw).unwrap();
+        assert_eq!(
+            (response.status, response.reason.as_str()),
+            (404, "Not Found")
+        );
+        assert_eq!(response.body, b"no such page");
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let keep_alive = |raw: &[u8]| {
+            let mut parser = ResponseParser::new(Method::Get);
+            parser.feed(raw).unwrap().unwrap();
+            parser.keep_alive()
+        };
+        assert!(keep_alive(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"));
+        assert!(keep_alive(
+            b"HTTP/1.0 200 OK\r\nConnection: Keep-Alive\r\nContent-Length: 0\r\n\r\n"
+        ));
+        assert!(keep_alive(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"
+        ));
+        assert!(!keep_alive(b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n"));
+        assert!(!keep_alive(
+            b"HTTP/1.1 200 OK\r\nConnection: foo, close\r\nContent-Length: 0\r\n\r\n"
+        ));
+        // Whatever that is, it can't be the answer to the next request.
+        assert!(!keep_alive(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\nHTTP"
+        ));
+
+        let mut parser = ResponseParser::new(Method::Get);
+        parser.feed(b"HTTP/1.1 200 OK\r\n\r\nuntil closed").unwrap();
+        parser.finish().unwrap();
+        assert!(!parser.keep_alive());
+    }
+
+    #[test]
+    fn test_malformed() {
+        let error = |raw: &[u8]| parse(raw).unwrap_err().to_string();
+        assert!(error(b"SSH-2.0-OpenSSH\r\n\r\n").contains("status line"));
+        assert!(error(b"HTTP/1.1 20 OK\r\n\r\n").contains("status line"));
+        assert!(error(b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n").contains("header"));
+        assert!(error(b"HTTP/1.1 200 OK\r\nContent-Length: 1, 2\r\n\r\n").contains("Length"));
+        assert!(error(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhi").contains("closed"));
+        assert!(error(b"HTTP/1.1 200 OK\r\n").contains("closed"));
+    }
+}
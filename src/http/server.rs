@@ -0,0 +1,1206 @@
+//! An HTTP/1.1 server on the event loop, like Node's `http.createServer`.
+//! The listener and every connection are registered with epoll. Requests
+//! are parsed as their bytes come in, and responses go out as fast as the
+//! socket takes them.
+use super::response::{
+    bad_line, chunked, content_length, find, is_token, take_head, Chunk, Framing,
+};
+use super::Headers;
+use crate::fs::HIGH_WATER_MARK;
+use crate::poll::Interests;
+use crate::runtime::{clear_timeout, print, runtime, set_timeout, Js, TimerId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const READ_CHUNK: usize = 16 * 1024;
+
+type Handler = Rc<dyn Fn(ServerRequest, ResponseWriter)>;
+type Listener = Option<Rc<dyn Fn(Js)>>;
+
+/// Set with `Server::set_options`.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Requests whose line and header fields take more bytes than this get
+    /// a 431.
+    pub max_header_size: usize,
+    /// Requests with a longer body get a 413.
+    pub max_body_size: usize,
+    /// Connections waiting for another request are closed after this long.
+    pub keep_alive_timeout: Duration,
+    /// How long the line and header fields of a request may take to arrive,
+    /// from its first byte. Slower requests get a 408.
+    pub headers_timeout: Duration,
+    /// The same for all of the request, including its body.
+    pub request_timeout: Duration,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            // Node's `maxHeaderSize`.
+            max_header_size: 16 * 1024,
+            max_body_size: 1024 * 1024,
+            // The rest as in Node.
+            keep_alive_timeout: Duration::from_secs(5),
+            headers_timeout: Duration::from_secs(60),
+            request_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// A request as the handler gets it, after all of the body has arrived.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerRequest {
+    /// As sent, e.g. `GET`.
+    pub method: String,
+    pub path: String,
+    /// Without the leading `?`.
+    pub query: Option<String>,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+/// Returned by `Http::create_server`. Clones refer to the same server.
+#[derive(Clone)]
+pub struct Server {
+    state: Rc<RefCell<ServerState>>,
+}
+
+struct ServerState {
+    handler: Handler,
+    options: ServerOptions,
+    listener: Option<(TcpListener, usize)>,
+    connections: HashMap<usize, Rc<RefCell<Conn>>>,
+    closed: bool,
+}
+
+impl Server {
+    pub(super) fn new(handler: Handler) -> Self {
+        let state = ServerState {
+            handler,
+            options: ServerOptions::default(),
+            listener: None,
+            connections: HashMap::new(),
+            closed: false,
+        };
+        Server {
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// Applies to connections accepted from now on.
+    pub fn set_options(&self, options: ServerOptions) {
+        self.state.borrow_mut().options = options;
+    }
+
+    /// Starts accepting connections on `addr`, e.g. `"127.0.0.1:8080"`, and
+    /// returns the address it's bound to, which tells which port was picked
+    /// for port 0. The loop stays alive until `close` is called.
+    pub fn listen(&self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        {
+            let state = self.state.borrow();
+            if state.listener.is_some() || state.closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the server can only listen once",
+                ));
+            }
+        }
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+
+        let rt = runtime();
+        let token = rt.generate_cb_identity();
+        rt.epoll_registrator
+            .register(&listener, token, Interests::READABLE)?;
+        self.state.borrow_mut().listener = Some((listener, token));
+        self.wait_for_connections(token);
+        Ok(local_addr)
+    }
+
+    /// Stops accepting connections. Idle ones are closed right away, the
+    /// others once their current response has gone out.
+    pub fn close(&self) {
+        let connections: Vec<_> = {
+            let mut state = self.state.borrow_mut();
+            if state.closed {
+                return;
+            }
+            state.closed = true;
+            if let Some((listener, token)) = state.listener.take() {
+                let rt = runtime();
+                let _ = rt.epoll_registrator.deregister(&listener);
+                rt.deregister_event_epoll(token);
+                rt.register_close_callback(move |_| drop(listener));
+            }
+            state.connections.values().cloned().collect()
+        };
+
+        for conn in connections {
+            let idle = {
+                let mut c = conn.borrow_mut();
+                c.closing = true;
+                c.response.is_none() && c.out.is_empty() && !c.busy
+            };
+            if idle {
+                close(&conn);
+            }
+        }
+    }
+
+    fn wait_for_connections(&self, token: usize) {
+        let server = self.clone();
+        runtime().register_event_epoll(token, move |_| server.accept());
+    }
+
+    fn accept(&self) {
+        let token = loop {
+            let accepted = match &self.state.borrow().listener {
+                Some((listener, token)) => listener.accept().map_err(|e| (e, *token)),
+                None => return,
+            };
+            match accepted {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.open(stream) {
+                        print(format!("dropped a connection: {}", e));
+                    }
+                }
+                Err((e, token)) if e.kind() == io::ErrorKind::WouldBlock => break token,
+                Err((e, _)) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err((e, token)) => {
+                    print(format!("accepting a connection failed: {}", e));
+                    break token;
+                }
+            }
+        };
+
+        let rearm = match &self.state.borrow().listener {
+            Some((listener, _)) => {
+                runtime()
+                    .epoll_registrator
+                    .reregister(listener, token, Interests::READABLE)
+            }
+            None => return,
+        };
+        match rearm {
+            Ok(()) => self.wait_for_connections(token),
+            Err(e) => print(format!("no longer accepting connections: {}", e)),
+        }
+    }
+
+    fn open(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        let rt = runtime();
+        let token = rt.generate_cb_identity();
+        rt.epoll_registrator
+            .register(&stream, token, Interests::READABLE)?;
+
+        let options = self.state.borrow().options.clone();
+        let conn = Rc::new(RefCell::new(Conn {
+            stream,
+            token,
+            server: self.clone(),
+            armed: false,
+            busy: false,
+            parser: RequestParser::new(&options),
+            options,
+            out: vec![],
+            written: 0,
+            response: None,
+            seq: 0,
+            closing: false,
+            closed: false,
+            started: None,
+            timer: None,
+        }));
+        self.state
+            .borrow_mut()
+            .connections
+            .insert(token, conn.clone());
+        wait(&conn);
+        start_timer(&conn);
+        Ok(())
+    }
+}
+
+/// One accepted connection. It handles a request at a time: the next one
+/// is only read once the response to the last one has gone out.
+struct Conn {
+    stream: TcpStream,
+    token: usize,
+    server: Server,
+    /// Waiting for epoll to call back.
+    armed: bool,
+    /// `on_ready` is running, so it'll pick up whatever is written.
+    busy: bool,
+    parser: RequestParser,
+    options: ServerOptions,
+    /// Bytes to send, of which `written` have been.
+    out: Vec<u8>,
+    written: usize,
+    /// The response the handler is working on.
+    response: Option<ResponseState>,
+    /// Counts requests, so writers of earlier responses are ignored.
+    seq: usize,
+    /// Close once the current response is out.
+    closing: bool,
+    closed: bool,
+    /// When the first byte of the request being read arrived.
+    started: Option<Instant>,
+    /// Closes the connection if no request arrives in time, and when.
+    timer: Option<(TimerId, Instant)>,
+}
+
+/// What `on_ready` has to do next.
+enum Step {
+    /// For epoll, and while waiting for a request, for the idle timer.
+    Wait(Interests),
+    /// The handler is still working on the response.
+    Respond,
+    Drained(Rc<dyn Fn(Js)>),
+    Handle(ServerRequest),
+    Close,
+}
+
+impl Conn {
+    /// Does as much as the socket allows without blocking.
+    fn advance(&mut self) -> io::Result<Step> {
+        let mut stream = &self.stream;
+        while self.written < self.out.len() {
+            match stream.write(&self.out[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Step::Wait(Interests::WRITABLE));
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.out.clear();
+        self.written = 0;
+
+        if let Some(response) = &mut self.response {
+            if std::mem::replace(&mut response.needs_drain, false) {
+                if let Some(on_drain) = response.on_drain.clone() {
+                    return Ok(Step::Drained(on_drain));
+                }
+            }
+            if !response.ended {
+                return Ok(Step::Respond);
+            }
+            let keep_alive = response.keep_alive;
+            self.response = None;
+            if !keep_alive {
+                return Ok(Step::Close);
+            }
+        }
+
+        if self.closing {
+            return Ok(Step::Close);
+        }
+        let mut chunk = vec![0; READ_CHUNK];
+        loop {
+            match self.parser.parse() {
+                Ok(Some((request, keep_alive, http_11))) => {
+                    self.seq += 1;
+                    self.started = None;
+                    let head_request = request.method == "HEAD";
+                    self.response = Some(ResponseState::new(keep_alive, http_11, head_request));
+                    return Ok(Step::Handle(request));
+                }
+                Ok(None) => {}
+                Err((status, e)) => {
+                    print(format!("bad request: {}", e));
+                    self.out.extend(rejection(status));
+                    self.parser.clear();
+                    self.closing = true;
+                    return self.advance();
+                }
+            }
+
+            match stream.read(&mut chunk) {
+                Ok(0) => return Ok(Step::Close),
+                Ok(n) => self.parser.push(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Step::Wait(Interests::READABLE));
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Queues a piece of the body, after the head if it hasn't gone out
+    /// yet. `last` ends the response.
+    fn send(&mut self, chunk: Vec<u8>, last: bool) {
+        let closing = self.closing;
+        let response = self.response.as_mut().unwrap();
+        if !response.head_sent {
+            let len = if last { Some(chunk.len()) } else { None };
+            let head = response.encode_head(len, closing);
+            self.out.extend(head);
+        }
+        if !response.no_body {
+            if !response.chunked {
+                self.out.extend(chunk);
+            } else if !chunk.is_empty() {
+                self.out
+                    .extend(format!("{:x}\r\n", chunk.len()).into_bytes());
+                self.out.extend(chunk);
+                self.out.extend(b"\r\n");
+            }
+            if last && response.chunked {
+                self.out.extend(b"0\r\n\r\n");
+            }
+        }
+        if last {
+            response.ended = true;
+            // Its listener may hold on to the writer.
+            response.on_drain = None;
+        }
+    }
+}
+
+fn on_ready(conn: &Rc<RefCell<Conn>>) {
+    loop {
+        let step = {
+            let mut c = conn.borrow_mut();
+            if c.closed {
+                return;
+            }
+            c.busy = true;
+            c.advance()
+        };
+        conn.borrow_mut().busy = matches!(step, Ok(Step::Drained(_)) | Ok(Step::Handle(_)));
+
+        match step {
+            Ok(Step::Wait(interests)) => {
+                let rearm = {
+                    let c = conn.borrow();
+                    runtime()
+                        .epoll_registrator
+                        .reregister(&c.stream, c.token, interests)
+                };
+                match rearm {
+                    Ok(()) => wait(conn),
+                    Err(e) => {
+                        print(format!("closing a connection: {}", e));
+                        return close(conn);
+                    }
+                }
+                if interests.is_readable() {
+                    start_timer(conn);
+                }
+                return;
+            }
+            Ok(Step::Respond) => return,
+            Ok(Step::Drained(on_drain)) => on_drain(Js::Undefined),
+            Ok(Step::Handle(request)) => {
+                stop_timer(conn);
+                let (handler, seq) = {
+                    let c = conn.borrow();
+                    let handler = c.server.state.borrow().handler.clone();
+                    (handler, c.seq)
+                };
+                let writer = ResponseWriter {
+                    conn: conn.clone(),
+                    seq,
+                };
+                handler(request, writer);
+            }
+            Ok(Step::Close) => return close(conn),
+            Err(e) => {
+                print(format!("closing a connection: {}", e));
+                return close(conn);
+            }
+        }
+    }
+}
+
+fn wait(conn: &Rc<RefCell<Conn>>) {
+    let token = {
+        let mut c = conn.borrow_mut();
+        c.armed = true;
+        c.token
+    };
+    let conn = conn.clone();
+    runtime().register_event_epoll(token, move |_| {
+        conn.borrow_mut().armed = false;
+        on_ready(&conn);
+    });
+}
+
+/// Runs `on_ready` unless it's running or waiting for epoll already.
+fn wake(conn: &Rc<RefCell<Conn>>) {
+    let idle = {
+        let c = conn.borrow();
+        !c.armed && !c.busy && !c.closed
+    };
+    if idle {
+        on_ready(conn);
+    }
+}
+
+/// Closes the connection unless the next request arrives in time: its
+/// first byte within the keep-alive timeout, then its head and all of it
+/// within the headers and request timeouts.
+fn start_timer(conn: &Rc<RefCell<Conn>>) {
+    let deadline = {
+        let mut c = conn.borrow_mut();
+        let now = Instant::now();
+        if c.started.is_none() && !c.parser.is_empty() {
+            c.started = Some(now);
+        }
+        let options = &c.options;
+        match c.started {
+            Some(started) if c.parser.has_head() => started + options.request_timeout,
+            Some(started) => started + options.headers_timeout.min(options.request_timeout),
+            // Idle since the timer was started.
+            None if c.timer.is_some() => return,
+            None => now + options.keep_alive_timeout,
+        }
+    };
+    if matches!(conn.borrow().timer, Some((_, current)) if current == deadline) {
+        return;
+    }
+    stop_timer(conn);
+
+    let c = conn.clone();
+    // Rounded up, so it doesn't fire before the deadline.
+    let left = deadline.saturating_duration_since(Instant::now());
+    let timer = set_timeout(left.as_micros().div_ceil(1000) as u64, move |_| {
+        let started = {
+            let mut c = c.borrow_mut();
+            c.timer = None;
+            c.started.is_some()
+        };
+        // An idle connection just goes away. A client that's too slow gets
+        // told, as far as the socket takes it.
+        if started {
+            let _ = (&c.borrow().stream).write(&rejection(408));
+        }
+        close(&c);
+    });
+    conn.borrow_mut().timer = Some((timer, deadline));
+}
+
+fn stop_timer(conn: &Rc<RefCell<Conn>>) {
+    if let Some((timer, _)) = conn.borrow_mut().timer.take() {
+        clear_timeout(timer);
+    }
+}
+
+/// Stops everything still waiting and closes the socket. Writers that are
+/// still around are ignored from now on.
+fn close(conn: &Rc<RefCell<Conn>>) {
+    stop_timer(conn);
+    let mut c = conn.borrow_mut();
+    if c.closed {
+        return;
+    }
+    c.closed = true;
+    c.response = None;
+
+    let rt = runtime();
+    let _ = rt.epoll_registrator.deregister(&c.stream);
+    if std::mem::replace(&mut c.armed, false) {
+        rt.deregister_event_epoll(c.token);
+    }
+    let _ = c.stream.shutdown(Shutdown::Both);
+    let (token, server) = (c.token, c.server.clone());
+    drop(c);
+
+    server.state.borrow_mut().connections.remove(&token);
+    let conn = conn.clone();
+    rt.register_close_callback(move |_| drop(conn));
+}
+
+struct ResponseState {
+    status: u16,
+    headers: Headers,
+    head_sent: bool,
+    /// Whether the body goes out in chunked encoding. Decided once the head
+    /// is sent.
+    chunked: bool,
+    /// Responses to HEAD, 204 and 304 have none.
+    no_body: bool,
+    head_request: bool,
+    http_11: bool,
+    /// Whether the connection stays open afterwards.
+    keep_alive: bool,
+    ended: bool,
+    needs_drain: bool,
+    on_drain: Listener,
+}
+
+impl ResponseState {
+    fn new(keep_alive: bool, http_11: bool, head_request: bool) -> Self {
+        ResponseState {
+            status: 200,
+            headers: Headers::new(),
+            head_sent: false,
+            chunked: false,
+            no_body: head_request,
+            head_request,
+            http_11,
+            keep_alive,
+            ended: false,
+            needs_drain: false,
+            on_drain: None,
+        }
+    }
+
+    /// The status line and header fields. `len` is the length of the whole
+    /// body if it's known; otherwise it's sent chunked, or to HTTP/1.0
+    /// clients, until the connection closes.
+    fn encode_head(&mut self, len: Option<usize>, closing: bool) -> Vec<u8> {
+        self.head_sent = true;
+        let mut headers = Headers::new();
+        let bodyless =
+            (100..200).contains(&self.status) || self.status == 204 || self.status == 304;
+        if bodyless {
+            self.no_body = true;
+        } else if let Some(chunked) = chunked(&self.headers) {
+            self.chunked = chunked;
+        } else if self.headers.get("Content-Length").is_none() {
+            match len {
+                Some(len) => headers.append("Content-Length", len.to_string()),
+                None if self.http_11 => {
+                    headers.append("Transfer-Encoding", "chunked");
+                    self.chunked = true;
+                }
+                // Unless the handler knows better, which it had better.
+                None if !self.head_request => self.keep_alive = false,
+                None => {}
+            }
+        }
+
+        self.keep_alive &= !closing && !self.headers.contains_token("Connection", "close");
+        if self.headers.get("Connection").is_none() {
+            if !self.keep_alive {
+                headers.append("Connection", "close");
+            } else if !self.http_11 {
+                headers.append("Connection", "keep-alive");
+            }
+        }
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in self.headers.iter().chain(headers.iter()) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+/// Handed to the handler along with the request. Clones write the same
+/// response, so it can be answered later from other callbacks. Nothing
+/// happens once it has ended, or once the connection is gone.
+#[derive(Clone)]
+pub struct ResponseWriter {
+    conn: Rc<RefCell<Conn>>,
+    /// Which of the connection's requests this answers.
+    seq: usize,
+}
+
+impl ResponseWriter {
+    /// 200 unless set. Has to come before the first `write`.
+    pub fn set_status(&self, status: u16) {
+        self.with_response(|response| {
+            if !response.head_sent {
+                response.status = status;
+            }
+        });
+    }
+
+    /// Adds a header field. Has to come before the first `write`. Unless
+    /// it's set here, `Content-Length` or `Transfer-Encoding: chunked` is
+    /// added as needed. A field whose name isn't a token, or whose value
+    /// has a line break or NUL in it, is dropped, since it could end the
+    /// head early or add fields of its own.
+    pub fn set_header(&self, name: &str, value: &str) {
+        if !is_token(name) || value.contains(['\r', '\n', '\0']) {
+            return print(format!("dropped header field {:?}", name));
+        }
+        self.with_response(|response| {
+            if !response.head_sent {
+                response.headers.append(name, value);
+            }
+        });
+    }
+
+    /// Sends a piece of the body, and before that the head if it hasn't
+    /// gone out yet. Returns `false` once more than `HIGH_WATER_MARK` bytes
+    /// are waiting to be sent; wait for `on_drain` before writing more.
+    pub fn write(&self, chunk: impl Into<Vec<u8>>) -> bool {
+        let chunk = chunk.into();
+        let below_mark = {
+            let mut c = self.conn.borrow_mut();
+            if !c.is_current(self.seq) {
+                return false;
+            }
+            c.send(chunk, false);
+            let below_mark = c.out.len() - c.written < HIGH_WATER_MARK;
+            c.response.as_mut().unwrap().needs_drain |= !below_mark;
+            below_mark
+        };
+        wake(&self.conn);
+        below_mark
+    }
+
+    /// Called once everything written has been sent after `write` returned
+    /// `false`.
+    pub fn on_drain(&self, cb: impl Fn(Js) + 'static) {
+        self.with_response(|response| response.on_drain = Some(Rc::new(cb)));
+    }
+
+    /// Finishes the response with a last piece of the body, which may be
+    /// empty.
+    pub fn end(&self, chunk: impl Into<Vec<u8>>) {
+        {
+            let mut c = self.conn.borrow_mut();
+            if !c.is_current(self.seq) {
+                return;
+            }
+            c.send(chunk.into(), true);
+        }
+        wake(&self.conn);
+    }
+
+    fn with_response(&self, f: impl FnOnce(&mut ResponseState)) {
+        let mut c = self.conn.borrow_mut();
+        if c.is_current(self.seq) {
+            f(c.response.as_mut().unwrap());
+        }
+    }
+}
+
+impl Conn {
+    /// Whether the response to request `seq` is still being written.
+    fn is_current(&self, seq: usize) -> bool {
+        !self.closed
+            && self.seq == seq
+            && matches!(&self.response, Some(response) if !response.ended)
+    }
+}
+
+/// A response that turns a request down and closes the connection.
+fn rejection(status: u16) -> Vec<u8> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status,
+        reason(status)
+    );
+    head.into_bytes()
+}
+
+/// A request, whether to keep the connection open and whether it's HTTP/1.1.
+type Parsed = (ServerRequest, bool, bool);
+
+/// Builds requests from bytes as they arrive off the socket.
+struct RequestParser {
+    max_header_size: usize,
+    max_body_size: usize,
+    buf: Vec<u8>,
+    /// The request whose body is arriving, and whether it's HTTP/1.1.
+    head: Option<(ServerRequest, bool)>,
+    framing: Framing,
+}
+
+impl RequestParser {
+    fn new(options: &ServerOptions) -> Self {
+        RequestParser {
+            max_header_size: options.max_header_size,
+            max_body_size: options.max_body_size,
+            buf: vec![],
+            head: None,
+            framing: Framing::Length(0),
+        }
+    }
+
+    /// Drops whatever has arrived.
+    fn clear(&mut self) {
+        self.buf.clear();
+        self.head = None;
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Nothing of the next request has arrived yet.
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty() && self.head.is_none()
+    }
+
+    /// The line and header fields of the next request have arrived.
+    fn has_head(&self) -> bool {
+        self.head.is_some()
+    }
+
+    /// The next request once all of it is there, along with whether the
+    /// client wants to keep the connection open and whether it speaks
+    /// HTTP/1.1. Fails with the status to answer with.
+    fn parse(&mut self) -> Result<Option<Parsed>, (u16, String)> {
+        let bad = |e| (400, e);
+        if self.head.is_none() {
+            let head_len = find(&self.buf, b"\r\n\r\n").map(|end| end + 4);
+            if head_len.unwrap_or(self.buf.len()) > self.max_header_size {
+                return Err((431, "head too large".to_string()));
+            }
+            let (request_line, headers) = match take_head(&mut self.buf).map_err(bad)? {
+                Some(head) => head,
+                None => return Ok(None),
+            };
+            let (method, target, http_11) = parse_request_line(&request_line)
+                .ok_or_else(|| bad(bad_line("request line", &request_line)))?;
+            // RFC 7230, section 3.3.3. Requests without either have no body.
+            self.framing = match chunked(&headers) {
+                Some(true) => Framing::Chunked(Chunk::Size),
+                Some(false) => return Err(bad("unsupported transfer coding".to_string())),
+                None => match content_length(&headers).map_err(bad)? {
+                    Some(len) if len > self.max_body_size => {
+                        return Err((413, "body too large".to_string()));
+                    }
+                    len => Framing::Length(len.unwrap_or(0)),
+                },
+            };
+            let (path, query) = match target.split_once('?') {
+                Some((path, query)) => (path.to_string(), Some(query.to_string())),
+                None => (target, None),
+            };
+            let request = ServerRequest {
+                method,
+                path,
+                query,
+                headers,
+                body: vec![],
+            };
+            self.head = Some((request, http_11));
+        }
+
+        let (request, _) = self.head.as_mut().unwrap();
+        let done = self.framing.decode(&mut self.buf, &mut request.body);
+        // Chunked bodies only tell their length as they arrive.
+        if request.body.len() > self.max_body_size {
+            return Err((413, "body too large".to_string()));
+        }
+        if !done.map_err(bad)? {
+            return Ok(None);
+        }
+        let (request, http_11) = self.head.take().unwrap();
+        let keep_alive = if http_11 {
+            !request.headers.contains_token("connection", "close")
+        } else {
+            request.headers.contains_token("connection", "keep-alive")
+        };
+        Ok(Some((request, keep_alive, http_11)))
+    }
+}
+
+/// `GET /path HTTP/1.1`. Returns the method, the target and whether it's
+/// HTTP/1.1.
+fn parse_request_line(line: &str) -> Option<(String, String, bool)> {
+    let mut parts = line.split(' ');
+    let (method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || target.is_empty() {
+        return None;
+    }
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+        return None;
+    }
+    let http_11 = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return None,
+    };
+    Some((method.to_string(), target.to_string(), http_11))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::{Http, Request, RequestOptions};
+    use crate::runtime::Runtime;
+    use std::thread;
+
+    /// Echoes the request, except that `/stream` is answered piece by piece
+    /// and `/quit` closes the server.
+    fn echo(server: Rc<RefCell<Option<Server>>>) -> impl Fn(ServerRequest, ResponseWriter) {
+        move |req, res| match req.path.as_str() {
+            "/stream" => {
+                res.write("a");
+                let res = res.clone();
+                set_timeout(10, move |_| {
+                    res.write("b");
+                    let res = res.clone();
+                    set_timeout(10, move |_| res.end("c"));
+                });
+            }
+            "/quit" => {
+                if let Some(server) = server.borrow_mut().take() {
+                    server.close();
+                }
+                res.end("bye");
+            }
+            _ => {
+                res.set_status(201);
+                res.set_header("X-Query", req.query.as_deref().unwrap_or_default());
+                let body = String::from_utf8(req.body).unwrap();
+                res.end(format!("{} {} {}", req.method, req.path, body));
+            }
+        }
+    }
+
+    fn start(handler: impl FnOnce(Rc<RefCell<Option<Server>>>) -> Server) -> SocketAddr {
+        let server = Rc::new(RefCell::new(None));
+        let created = handler(server.clone());
+        let addr = created.listen("127.0.0.1:0").unwrap();
+        *server.borrow_mut() = Some(created);
+        addr
+    }
+
+    /// The value of a header field of a response from `Http`, and its body.
+    fn header_and_body(res: Js, name: &str) -> (String, String) {
+        let mut response = res.into_object().unwrap();
+        let mut headers = response.remove("headers").unwrap().into_object().unwrap();
+        let mut values = headers.remove(name).unwrap().into_array().unwrap();
+        let value = values.remove(0).into_string().unwrap();
+        let body = response.remove("body").unwrap().into_bytes().unwrap();
+        (value, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn test_serve_http_client() {
+        let bodies = Rc::new(RefCell::new(vec![]));
+        let b = bodies.clone();
+        Runtime::new()
+            .run(move || {
+                let server = Rc::new(RefCell::new(None));
+                let created = Http::create_server(echo(server.clone()));
+                let url = format!("http://{}", created.listen("127.0.0.1:0").unwrap());
+                *server.borrow_mut() = Some(created.clone());
+
+                let request = Request::post(&format!("{}/items?x=1", url)).body("hi");
+                Http::request(request, RequestOptions::default(), move |res| {
+                    b.borrow_mut().push(header_and_body(res, "x-query"));
+
+                    // Streamed over the same connection.
+                    let b = b.clone();
+                    let server = server.clone();
+                    let created = created.clone();
+                    let url = format!("{}/stream", url);
+                    Http::get(&url, RequestOptions::default(), move |res| {
+                        b.borrow_mut()
+                            .push(header_and_body(res, "transfer-encoding"));
+                        assert_eq!(created.state.borrow().connections.len(), 1);
+                        server.borrow_mut().take().unwrap().close();
+                    });
+                });
+            })
+            .unwrap();
+
+        assert_eq!(
+            *bodies.borrow(),
+            [("x=1", "POST /items hi"), ("chunked", "abc")]
+                .map(|(header, body)| (header.to_string(), body.to_string()))
+        );
+    }
+
+    /// Sends `request` and reads until the server closes the connection.
+    fn exchange(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve_raw_requests() {
+        let responses = Rc::new(RefCell::new(None));
+        let r = responses.clone();
+        Runtime::new()
+            .run(move || {
+                let addr = start(|server| Http::create_server(echo(server)));
+                *r.borrow_mut() = Some(thread::spawn(move || {
+                    [
+                        // Pipelined, the second one with a chunked body.
+                        "GET /a HTTP/1.1\r\n\r\n\
+                         POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+                         Connection: close\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+                        "NOT HTTP\r\n\r\n",
+                        "PUT / HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n",
+                        // The length isn't known, so the body ends when
+                        // the connection does.
+                        "GET /stream HTTP/1.0\r\n\r\n",
+                        "HEAD /stream HTTP/1.1\r\nConnection: close\r\n\r\n",
+                        "GET /quit HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+                    ]
+                    .iter()
+                    .map(|request| exchange(addr, request))
+                    .collect::<Vec<_>>()
+                }));
+            })
+            .unwrap();
+
+        let responses = responses.borrow_mut().take().unwrap().join().unwrap();
+        assert_eq!(
+            responses,
+            [
+                "HTTP/1.1 201 Created\r\nX-Query: \r\nContent-Length: 7\r\n\r\nGET /a \
+                 HTTP/1.1 201 Created\r\nX-Query: \r\nContent-Length: 11\r\n\
+                 Connection: close\r\n\r\nPOST /b abc",
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nabc",
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+                // Closing the server closes the connection too.
+                "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nbye",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_response_headers() {
+        let response = Rc::new(RefCell::new(None));
+        let r = response.clone();
+        Runtime::new()
+            .run(move || {
+                let addr = start(|server| {
+                    Http::create_server(move |_, res| {
+                        res.set_header("X-Echo", "a\r\nX-Injected: 1");
+                        res.set_header("X-Echo", "a\0");
+                        res.set_header("X Echo", "a");
+                        res.set_header("X-Echo", "ok");
+                        server.borrow_mut().take().unwrap().close();
+                        res.end("");
+                    })
+                });
+                *r.borrow_mut() = Some(thread::spawn(move || {
+                    exchange(addr, "GET / HTTP/1.1\r\n\r\n")
+                }));
+            })
+            .unwrap();
+
+        let response = response.borrow_mut().take().unwrap().join().unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nX-Echo: ok\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_timeouts() {
+        // Sends `head` and then `rest` a byte at a time every `every` ms, for
+        // as long as the server keeps reading. Returns what came back and
+        // how long it took.
+        fn trickle(addr: SocketAddr, head: &str, rest: &str, every: u64) -> (String, u64) {
+            let start = std::time::Instant::now();
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(head.as_bytes()).unwrap();
+            for byte in rest.bytes() {
+                if stream.write_all(&[byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(every));
+            }
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response);
+            (response, start.elapsed().as_millis() as u64)
+        }
+
+        let responses = Rc::new(RefCell::new(None));
+        let r = responses.clone();
+        Runtime::new()
+            .run(move || {
+                let addr = start(|server| {
+                    let created = Http::create_server(echo(server));
+                    created.set_options(ServerOptions {
+                        keep_alive_timeout: Duration::from_millis(100),
+                        headers_timeout: Duration::from_millis(300),
+                        request_timeout: Duration::from_millis(600),
+                        ..ServerOptions::default()
+                    });
+                    created
+                });
+                *r.borrow_mut() = Some(thread::spawn(move || {
+                    let slow = "x".repeat(100);
+                    vec![
+                        trickle(addr, "", "", 0),
+                        trickle(addr, "GET / HTTP/1.1\r\nX-Slow: ", &slow, 20),
+                        trickle(
+                            addr,
+                            "PUT / HTTP/1.1\r\nContent-Length: 100\r\n\r\n",
+                            &slow,
+                            20,
+                        ),
+                        // Slow, but in time.
+                        trickle(addr, "", "GET /quit HTTP/1.1\r\n\r\n", 10),
+                    ]
+                }));
+            })
+            .unwrap();
+
+        let responses = responses.borrow_mut().take().unwrap().join().unwrap();
+        let timeout =
+            "HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        let (idle, took) = &responses[0];
+        assert_eq!((idle.as_str(), *took >= 100), ("", true));
+        let (head, took) = &responses[1];
+        assert_eq!(
+            (head.as_str(), *took >= 300 && *took < 600),
+            (timeout, true)
+        );
+        let (body, took) = &responses[2];
+        assert_eq!((body.as_str(), *took >= 600), (timeout, true));
+        assert!(responses[3].0.ends_with("bye"));
+    }
+
+    #[test]
+    fn test_write_backpressure() {
+        const CHUNKS: usize = 64;
+        let result = Rc::new(RefCell::new((0, 0)));
+        let r = result.clone();
+        Runtime::new()
+            .run(move || {
+                let drained = Rc::new(RefCell::new(0));
+                let d = drained.clone();
+                let server = Http::create_server(move |_, res| {
+                    // Writes until it's told to wait, then goes on once
+                    // everything has been sent.
+                    let written = Rc::new(RefCell::new(0));
+                    let d = d.clone();
+                    let writer = res.clone();
+                    let write = move |_| loop {
+                        let n = *written.borrow();
+                        if n == CHUNKS {
+                            return writer.end("");
+                        }
+                        *written.borrow_mut() += 1;
+                        if !writer.write(vec![b'x'; 16 * 1024]) {
+                            *d.borrow_mut() += 1;
+                            return;
+                        }
+                    };
+                    write(Js::Undefined);
+                    res.on_drain(write);
+                });
+                let url = format!("http://{}", server.listen("127.0.0.1:0").unwrap());
+                Http::get(&url, RequestOptions::default(), move |res| {
+                    let (_, body) = header_and_body(res, "transfer-encoding");
+                    *r.borrow_mut() = (body.len(), *drained.borrow());
+                    server.close();
+                });
+            })
+            .unwrap();
+
+        let (len, drained) = *result.borrow();
+        assert_eq!(len, CHUNKS * 16 * 1024);
+        assert!(drained > 0);
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let mut parser = RequestParser::new(&ServerOptions::default());
+        assert!(parser.is_empty());
+        parser.push(b"PUT /x?y HTTP/1.1\r\nContent-Length: 3\r\n\r\nab");
+        assert_eq!(parser.parse(), Ok(None));
+        assert!(!parser.is_empty());
+        parser.push(b"cGET / HTTP/1.0\r\n\r\n");
+
+        let (request, keep_alive, http_11) = parser.parse().unwrap().unwrap();
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("PUT", "/x")
+        );
+        assert_eq!(
+            (request.query.as_deref(), request.body.as_slice()),
+            (Some("y"), &b"abc"[..])
+        );
+        assert!(keep_alive && http_11);
+        let (_, keep_alive, http_11) = parser.parse().unwrap().unwrap();
+        assert!(!keep_alive && !http_11);
+
+        for bad in &[
+            "GET /\r\n\r\n",
+            "get / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/2\r\n\r\n",
+            "GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n",
+            "GET / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+        ] {
+            let mut parser = RequestParser::new(&ServerOptions::default());
+            parser.push(bad.as_bytes());
+            assert_eq!(parser.parse().unwrap_err().0, 400, "{}", bad);
+        }
+
+        let options = ServerOptions {
+            max_header_size: 60,
+            max_body_size: 4,
+            ..ServerOptions::default()
+        };
+        for (request, status) in &[
+            (
+                "GET / HTTP/1.1\r\nX-Long: 012345678901234567890123456789012345678901\r\n\r\n",
+                431,
+            ),
+            // Even before all of it has arrived.
+            (
+                "GET / HTTP/1.1\r\nX-Long: 012345678901234567890123456789012345678901",
+                431,
+            ),
+            ("PUT / HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n", 413),
+            (
+                "PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n01234",
+                413,
+            ),
+        ] {
+            let mut parser = RequestParser::new(&options);
+            parser.push(request.as_bytes());
+            assert_eq!(parser.parse().unwrap_err().0, *status, "{}", request);
+        }
+        let mut parser = RequestParser::new(&options);
+        parser.push(b"PUT / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd");
+        assert!(parser.parse().unwrap().is_some());
+    }
+}
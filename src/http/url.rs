@@ -0,0 +1,271 @@
+use std::fmt;
+use std::io;
+
+/// An absolute `http://` or `https://` URL, split into what a request
+/// needs. The fragment is dropped since it never goes over the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    /// Lowercase, without `://`.
+    pub scheme: String,
+    /// IPv6 addresses are kept without their brackets.
+    pub host: String,
+    pub port: u16,
+    /// Starts with `/`.
+    pub path: String,
+    /// Without the leading `?`.
+    pub query: Option<String>,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> io::Result<Url> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| invalid(url, "missing scheme"))?;
+        let scheme = scheme.to_ascii_lowercase();
+        let default_port = default_port(&scheme).ok_or_else(|| invalid(url, "unknown scheme"))?;
+
+        let rest = rest.split('#').next().unwrap_or_default();
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, target) = rest.split_at(authority_end);
+        if authority.contains('@') {
+            return Err(invalid(url, "credentials in URLs are not supported"));
+        }
+
+        let (host, port) = if let Some(v6) = authority.strip_prefix('[') {
+            let (host, port) = v6
+                .split_once(']')
+                .ok_or_else(|| invalid(url, "unclosed IPv6 address"))?;
+            match port {
+                "" => (host, None),
+                port => match port.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(invalid(url, "junk after IPv6 address")),
+                },
+            }
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(invalid(url, "missing host"));
+        }
+        let port = match port {
+            None | Some("") => default_port,
+            Some(port) => port.parse().map_err(|_| invalid(url, "bad port"))?,
+        };
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+        let path = if path.is_empty() { "/" } else { path };
+
+        let parsed = Url {
+            scheme,
+            host: host.to_ascii_lowercase(),
+            port,
+            path: path.to_string(),
+            query,
+        };
+        parsed.check(url)?;
+        Ok(parsed)
+    }
+
+    /// Resolves `reference` against this URL like a browser resolves a link,
+    /// which is how `Location` headers are meant to be read: it may be
+    /// absolute, start with `//` or `/`, be just a query, or be relative
+    /// to the current path.
+    pub fn join(&self, reference: &str) -> io::Result<Url> {
+        let reference = reference.trim().split('#').next().unwrap_or_default();
+        let head = reference.split(['/', '?']).next().unwrap_or_default();
+        if head.contains(':') {
+            return Url::parse(reference);
+        }
+        if reference.starts_with("//") {
+            return Url::parse(&format!("{}:{}", self.scheme, reference));
+        }
+
+        let (path, query) = match reference.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (reference, None),
+        };
+        let mut url = self.clone();
+        if path.is_empty() {
+            // Only a query, or nothing at all.
+            url.query = query.or(url.query);
+            url.check(reference)?;
+            return Ok(url);
+        }
+        let path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            let dir = &self.path[..self.path.rfind('/').unwrap_or(0) + 1];
+            format!("{}{}", dir, path)
+        };
+        url.path = remove_dot_segments(&path);
+        url.query = query;
+        url.check(reference)?;
+        Ok(url)
+    }
+
+    /// Everything but the scheme and port ends up verbatim in the request
+    /// line or the `Host` header, so a space or line break in them could
+    /// smuggle in a request of its own.
+    fn check(&self, url: &str) -> io::Result<()> {
+        let parts = [Some(&self.host), Some(&self.path), self.query.as_ref()];
+        let unsafe_char = |c: char| c.is_whitespace() || c.is_control();
+        if parts
+            .iter()
+            .flatten()
+            .any(|part| part.contains(unsafe_char))
+        {
+            return Err(invalid(url, "whitespace or control characters"));
+        }
+        Ok(())
+    }
+
+    /// The path and query, as they go in the request line.
+    pub fn request_target(&self) -> String {
+        match &self.query {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        }
+    }
+
+    /// The value of the `Host` header. The port is left out if it's the
+    /// scheme's default.
+    pub fn authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        if default_port(&self.scheme) == Some(self.port) {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}://{}{}",
+            self.scheme,
+            self.authority(),
+            self.request_target()
+        )
+    }
+}
+
+/// Applies `.` and `..` in an absolute path, without going above the root.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    let mut parts = path.split('/').skip(1).peekable();
+    while let Some(part) = parts.next() {
+        let last = parts.peek().is_none();
+        match part {
+            "." | ".." => {
+                if part == ".." {
+                    segments.pop();
+                }
+                // `/a/b/..` means the directory `/a/`.
+                if last {
+                    segments.push("");
+                }
+            }
+            part => segments.push(part),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+fn invalid(url: &str, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid URL {:?}: {}", url, msg),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let url = Url::parse("HTTP://Example.com:8080/a/b?x=1&y=2#frag").unwrap();
+        assert_eq!(url.scheme, "http");
+        assert_eq!(url.host, "example.com");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/a/b");
+        assert_eq!(url.query.as_deref(), Some("x=1&y=2"));
+        assert_eq!(url.request_target(), "/a/b?x=1&y=2");
+        assert_eq!(url.to_string(), "http://example.com:8080/a/b?x=1&y=2");
+
+        let url = Url::parse("http://example.com?q").unwrap();
+        assert_eq!((url.port, url.path.as_str()), (80, "/"));
+        assert_eq!(url.authority(), "example.com");
+        assert_eq!(url.request_target(), "/?q");
+
+        let url = Url::parse("https://[::1]:8443/").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 8443));
+        assert_eq!(url.authority(), "[::1]:8443");
+        assert_eq!(Url::parse("https://[::1]").unwrap().port, 443);
+
+        for bad in &[
+            "example.com/path",
+            "ftp://example.com/",
+            "http:///path",
+            "http://example.com:http/",
+            "http://example.com:99999/",
+            "http://user:pw@example.com/",
+            "http://[::1/",
+            "http://example.com/a b",
+            "http://exa mple.com/",
+            "http://example.com/\r\nX-Injected: 1",
+            "http://example.com/?a\nb",
+            "http://example.com\t/",
+        ] {
+            let e = Url::parse(bad).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_join() {
+        let base = Url::parse("http://example.com:8080/a/b/c?x=1").unwrap();
+        for (reference, expected) in &[
+            ("https://other.org/d", "https://other.org/d"),
+            ("//other.org/d?y", "http://other.org/d?y"),
+            ("/d/e", "http://example.com:8080/d/e"),
+            ("d", "http://example.com:8080/a/b/d"),
+            ("./d/", "http://example.com:8080/a/b/d/"),
+            ("../d?y=2#frag", "http://example.com:8080/a/d?y=2"),
+            ("../../../../d", "http://example.com:8080/d"),
+            ("..", "http://example.com:8080/a/"),
+            ("?y=2", "http://example.com:8080/a/b/c?y=2"),
+            ("#frag", "http://example.com:8080/a/b/c?x=1"),
+            ("/a/./b/../c", "http://example.com:8080/a/c"),
+        ] {
+            let url = base.join(reference).unwrap();
+            assert_eq!(url.to_string(), *expected, "{}", reference);
+        }
+        assert!(base.join("ftp://example.com/").is_err());
+        for bad in &["/a b", "x\r\nHost: evil", "?q=\0", "//evil .com/"] {
+            let e = base.join(bad).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,11 @@
+//! A tiny Node.js-like runtime: a thread pool for blocking work, an epoll
+//! thread for network I/O and a single-threaded event loop tying them
+//! together. The library target lets other programs embed the loop.
+pub mod crypto;
+pub mod encoding;
+pub mod fs;
+pub mod http;
+pub mod poll;
+pub mod runtime;
+mod sys;
+mod uring;
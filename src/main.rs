@@ -1,25 +1,29 @@
-mod crypto;
-mod fs;
-mod http;
-mod runtime;
+use adven_async_ous::crypto::Crypto;
+use adven_async_ous::fs::Fs;
+use adven_async_ous::http::{Http, RequestOptions};
+use adven_async_ous::runtime::{
+    current, get_context, print, queue_microtask, run_with_context, set_immediate, set_timeout, Js,
+    Runtime,
+};
+use std::time::Duration;
 
-use crypto::Crypto;
-use fs::Fs;
-use http::Http;
-use runtime::{current, print, set_timeout, Runtime};
+struct TraceId(&'static str);
 
 fn javascript() {
     print("First call to read test.txt");
-    Fs::read("test.txt", |result| {
-        let text = result.into_string().unwrap();
-        let len = text.len();
-        print(format!("First count: {} characters.", len));
+    run_with_context(TraceId("request-1"), || {
+        Fs::read("test.txt", |result| {
+            let text = result.into_string().unwrap();
+            let len = text.len();
+            print(format!("First count: {} characters.", len));
 
-        print(r#"I want to create a "magic" number based on the text."#);
-        Crypto::encrypt(text.len(), |result| {
-            let n = result.into_int().unwrap();
-            print(format!(r#""Encrypted" number is: {}"#, n));
-        })
+            print(r#"I want to create a "magic" number based on the text."#);
+            Crypto::encrypt(text.len(), |result| {
+                let n = result.into_int().unwrap();
+                let trace_id = get_context::<TraceId>().unwrap();
+                print(format!(r#""Encrypted" number is: {} ({})"#, n, trace_id.0));
+            })
+        });
     });
 
     print("Registering immediate timeout 1");
@@ -31,6 +35,14 @@ fn javascript() {
         print("Immediate2 timed out");
     });
 
+    print("Registering a set_immediate and a microtask");
+    set_immediate(|_res| {
+        print("set_immediate ran in the check phase");
+    });
+    queue_microtask(|| {
+        print("Microtask ran right after the main script");
+    });
+
     print("Second call to read test.txt");
     Fs::read("test.txt", |result| {
         let text = result.into_string().unwrap();
@@ -58,15 +70,80 @@ fn javascript() {
     });
 
     print("Registering http get request to google.com");
-    Http::http_get_slow("http//www.google.com", 2000, |result| {
-        let result = result.into_string().unwrap();
-        print_content(result.trim(), "web call");
+    let options = RequestOptions {
+        follow_redirects: Some(5),
+        ..RequestOptions::default()
+    };
+    Http::get("http://www.google.com/", options, |result| match result {
+        Js::Error(e) => print(format!("web call failed: {}", e)),
+        result => print_content(summarize(result), "web call"),
     });
+
+    print("Starting a local http server and calling it");
+    let server = Http::create_server(|req, res| {
+        res.set_header("Content-Type", "text/plain");
+        res.end(format!("hello from {} {}", req.method, req.path));
+    });
+    match server.listen("127.0.0.1:0") {
+        Ok(addr) => {
+            let url = format!("http://{}/greeting", addr);
+            Http::get(&url, RequestOptions::default(), move |result| {
+                match result {
+                    Js::Error(e) => print(format!("local call failed: {}", e)),
+                    result => {
+                        let mut response = result.into_object().unwrap();
+                        let body = response.remove("body").unwrap().into_bytes().unwrap();
+                        print_content(String::from_utf8_lossy(&body), "local server");
+                    }
+                }
+                server.close();
+            });
+        }
+        Err(e) => print(format!("local server failed: {}", e)),
+    }
+}
+
+/// The status line and where it came from, with any redirects on the way.
+fn summarize(response: Js) -> String {
+    let mut response = response.into_object().unwrap();
+    let mut field = |name: &str| response.remove(name).unwrap();
+    let status = field("status").into_int().unwrap();
+    let reason = field("reason").into_string().unwrap();
+    let url = field("url").into_string().unwrap();
+    let redirects: Vec<String> = field("redirects")
+        .into_array()
+        .unwrap()
+        .into_iter()
+        .map(|hop| {
+            let mut hop = hop.into_object().unwrap();
+            let status = hop.remove("status").unwrap().into_int().unwrap();
+            let url = hop.remove("url").unwrap().into_string().unwrap();
+            format!("{} {}", status, url)
+        })
+        .collect();
+
+    if redirects.is_empty() {
+        format!("{} {} from {}", status, reason, url)
+    } else {
+        format!(
+            "{} {} from {}\nRedirected by: {}",
+            status,
+            reason,
+            url,
+            redirects.join(", ")
+        )
+    }
 }
 
 fn main() {
+    // Pretend the disk is slow so the output shows the callbacks interleave.
+    Fs::set_simulated_latency(Some(Duration::from_secs(1)));
+
     let rt = Runtime::new();
-    rt.run(javascript);
+    if let Err(e) = rt.run(javascript) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
 
 fn print_content(t: impl std::fmt::Display, descr: &str) {
@@ -79,21 +156,7 @@ fn print_content(t: impl std::fmt::Display, descr: &str) {
     let content = format!("{}", t);
     let lines = content.lines().take(2);
     let main_cont: String = lines.map(|l| format!("{}\n", l)).collect();
-    let opt_location = content.find("Location");
-
-    let opt_location = opt_location.map(|loc| {
-        content[loc..]
-            .lines()
-            .next()
-            .map(|l| format!("{}\n", l))
-            .unwrap_or_default()
-    });
-
-    println!(
-        "{}{}... [Note: Abbreviated for display] ...",
-        main_cont,
-        opt_location.unwrap_or_default()
-    );
+    println!("{}... [Note: Abbreviated for display] ...", main_cont);
 
     println!("===== END CONTENT =====\n");
 }
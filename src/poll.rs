@@ -0,0 +1,192 @@
+//! A small epoll reactor with the same shape as minimio's `Poll` and
+//! `Registrator`. Unlike minimio it takes any fd, not only `TcpStream`s, can
+//! wait for writability and can re-arm a registration once it has fired.
+use crate::sys::{self, Epoll, EpollEvent, EventFd};
+use std::io;
+use std::ops::BitOr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub type Token = usize;
+
+/// Registered for the eventfd `close_loop` uses to wake up the poller.
+const CLOSE_TOKEN: Token = usize::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interests(u32);
+
+impl Interests {
+    pub const READABLE: Interests = Interests(sys::EPOLLIN);
+    pub const WRITABLE: Interests = Interests(sys::EPOLLOUT);
+
+    pub fn is_readable(self) -> bool {
+        self.0 & sys::EPOLLIN != 0
+    }
+
+    pub fn is_writable(self) -> bool {
+        self.0 & sys::EPOLLOUT != 0
+    }
+}
+
+impl BitOr for Interests {
+    type Output = Interests;
+
+    fn bitor(self, other: Interests) -> Interests {
+        Interests(self.0 | other.0)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Event(EpollEvent);
+
+impl Event {
+    pub fn id(&self) -> Token {
+        self.0.data as Token
+    }
+
+    /// Errors and hang-ups count as readable so the reader gets to see them.
+    pub fn is_readable(&self) -> bool {
+        self.0.events & (sys::EPOLLIN | sys::EPOLLERR | sys::EPOLLHUP) != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.0.events & (sys::EPOLLOUT | sys::EPOLLERR | sys::EPOLLHUP) != 0
+    }
+}
+
+pub struct Events {
+    inner: Vec<EpollEvent>,
+}
+
+impl Events {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Events {
+            inner: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn get(&self, i: usize) -> Option<Event> {
+        self.inner.get(i).cloned().map(Event)
+    }
+}
+
+pub struct Poll {
+    epoll: Arc<Epoll>,
+    close: Arc<EventFd>,
+    is_poll_dead: Arc<AtomicBool>,
+}
+
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        let epoll = Epoll::new()?;
+        let close = EventFd::new()?;
+        sys::epoll_control(
+            epoll.fd(),
+            sys::EPOLL_CTL_ADD,
+            close.as_raw_fd(),
+            sys::EPOLLIN,
+            CLOSE_TOKEN,
+        )?;
+
+        Ok(Poll {
+            epoll: Arc::new(epoll),
+            close: Arc::new(close),
+            is_poll_dead: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn registrator(&self) -> Registrator {
+        Registrator {
+            epoll: self.epoll.clone(),
+            close: self.close.clone(),
+            is_poll_dead: self.is_poll_dead.clone(),
+        }
+    }
+
+    /// Blocks until events are ready or `timeout_ms` passed. Returns an
+    /// `Interrupted` error once `Registrator::close_loop` was called.
+    pub fn poll(&mut self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<usize> {
+        let n = loop {
+            match self.epoll.wait(&mut events.inner, timeout_ms.unwrap_or(-1)) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => break res?,
+            }
+        };
+
+        let closed = events.inner.iter().any(|e| e.data as Token == CLOSE_TOKEN);
+        if closed || self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// Registrations are oneshot, like in minimio: after an fd has been reported
+/// once it has to be re-armed with `reregister` to be reported again.
+#[derive(Clone)]
+pub struct Registrator {
+    epoll: Arc<Epoll>,
+    close: Arc<EventFd>,
+    is_poll_dead: Arc<AtomicBool>,
+}
+
+impl Registrator {
+    pub fn register(
+        &self,
+        source: &impl AsRawFd,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        self.ctl(sys::EPOLL_CTL_ADD, source.as_raw_fd(), token, interests)
+    }
+
+    pub fn reregister(
+        &self,
+        source: &impl AsRawFd,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        self.ctl(sys::EPOLL_CTL_MOD, source.as_raw_fd(), token, interests)
+    }
+
+    pub fn deregister(&self, source: &impl AsRawFd) -> io::Result<()> {
+        self.check_alive()?;
+        sys::epoll_control(
+            self.epoll.fd(),
+            sys::EPOLL_CTL_DEL,
+            source.as_raw_fd(),
+            0,
+            0,
+        )
+    }
+
+    pub fn close_loop(&self) -> io::Result<()> {
+        if self.is_poll_dead.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        self.close.notify()
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, token: Token, interests: Interests) -> io::Result<()> {
+        self.check_alive()?;
+        let events = interests.0 | sys::EPOLLONESHOT;
+        sys::epoll_control(self.epoll.fd(), op, fd, events, token)
+    }
+
+    fn check_alive(&self) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        Ok(())
+    }
+}
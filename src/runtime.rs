@@ -1,9 +1,19 @@
+use crate::http::Pool;
+use crate::poll::{Events, Interests, Poll, Registrator};
+use crate::sys::EventFd;
+use crate::uring::{Op, Uring};
 use std::{
-    collections::{BTreeMap, HashMap},
+    any::{Any, TypeId},
+    cell::Cell,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fmt::{self, Display},
+    fs::File,
     io,
-    sync::mpsc::{channel, Receiver, Sender},
-    sync::{Arc, Mutex},
+    os::unix::io::{AsRawFd, RawFd},
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    sync::{Arc, Weak},
     thread,
     thread::JoinHandle,
     time::{Duration, Instant},
@@ -18,38 +28,152 @@ pub fn print(t: impl std::fmt::Display) {
 }
 
 const NUM_THREADS: usize = 4;
+/// How many pool threads CPU-bound tasks may take at once. The rest stay
+/// free for file I/O, like libuv does for its slow work.
+const MAX_CPU_BOUND_TASKS: usize = NUM_THREADS / 2;
+const URING_ENTRIES: u32 = 64;
+/// The epoll token of the io_uring completion eventfd.
+const URING_TOKEN: usize = usize::MAX - 1;
 
-pub static mut RUNTIME: *mut Runtime = std::ptr::null_mut();
+thread_local! {
+    static RUNTIME: Cell<*mut Runtime> = const { Cell::new(std::ptr::null_mut()) };
+}
+
+/// The runtime driving the current thread. Every thread (e.g. a `Worker`)
+/// can run a runtime of its own.
+pub fn runtime() -> &'static mut Runtime {
+    let rt = RUNTIME.with(|rt| rt.get());
+    assert!(!rt.is_null(), "No runtime on thread {}", current());
+    unsafe { &mut *rt }
+}
+
+pub fn set_timeout(ms: u64, cb: impl Fn(Js) + 'static) -> TimerId {
+    let rt = runtime();
+    rt.set_timeout(ms, cb)
+}
+
+/// Cancels a timer that hasn't fired yet. Clearing one that already fired
+/// does nothing.
+pub fn clear_timeout(timer: TimerId) {
+    let rt = runtime();
+    rt.clear_timeout(timer);
+}
+
+/// Like Node's `timeout.unref()`: the timer still fires, but the loop
+/// doesn't wait for it if nothing else is left to do.
+pub fn unref_timer(timer: TimerId) {
+    let rt = runtime();
+    rt.unref_timer(timer);
+}
+
+pub fn set_immediate(cb: impl Fn(Js) + 'static) {
+    let rt = runtime();
+    rt.set_immediate(cb);
+}
+
+pub fn queue_microtask(cb: impl FnOnce() + 'static) {
+    let rt = runtime();
+    rt.queue_microtask(cb);
+}
+
+/// Runs `f` with `store` as the context value of type `T`. Callbacks
+/// registered while `f` runs, and the callbacks they register in turn, see
+/// the same value through `get_context`.
+pub fn run_with_context<T: 'static>(store: T, f: impl FnOnce()) {
+    /// Puts the previous context back even if `f` panics.
+    struct Restore(Context);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            runtime().context = std::mem::take(&mut self.0);
+        }
+    }
+
+    let rt = runtime();
+    let mut stores = (*rt.context.0).clone();
+    stores.insert(TypeId::of::<T>(), Rc::new(store));
+
+    let _restore = Restore(std::mem::replace(&mut rt.context, Context(Rc::new(stores))));
+    f();
+}
+
+/// Installs `hook` on the current runtime. See `Runtime::on_uncaught_exception`.
+pub fn on_uncaught_exception(hook: impl Fn(UncaughtException) + 'static) {
+    let rt = runtime();
+    rt.on_uncaught_exception(hook);
+}
 
-pub fn set_timeout(ms: u64, cb: impl Fn(Js) + 'static) {
-    let rt = unsafe { &mut *(RUNTIME as *mut Runtime) };
-    rt.set_timeout(ms, cb);
+/// The `T` set by the closest `run_with_context` this callback descends from.
+pub fn get_context<T: 'static>() -> Option<Rc<T>> {
+    let rt = runtime();
+    let store = rt.context.0.get(&TypeId::of::<T>())?.clone();
+    store.downcast::<T>().ok()
 }
 
+/// Every tick of the loop goes through the same phases as libuv:
+///
+/// 1. **timers** - callbacks of expired `set_timeout`s, oldest deadline first.
+/// 2. **pending** - completions that were queued outside of the poll phase.
+/// 3. **poll** - waits for thread pool and epoll events and runs their
+///    callbacks in the order they arrived. Doesn't block if there are
+///    immediates or close callbacks waiting.
+/// 4. **check** - `set_immediate` callbacks. Immediates scheduled while this
+///    phase runs are deferred to the next tick.
+/// 5. **close** - close callbacks of handles that were torn down.
+///
+/// The microtask queue (`queue_microtask`) is drained after every single
+/// callback, no matter which phase it ran in.
+///
+/// `run` drives the loop until there's no work left. Hosts with a loop of
+/// their own can instead call `enter` once and then `run_once` whenever
+/// `backend_fd` is readable or `backend_timeout` has passed.
 pub struct Runtime {
     available_threads: Vec<usize>,
-    callbacks_to_run: Vec<(usize, Js)>,
-    callback_queue: HashMap<usize, Box<dyn FnOnce(Js)>>,
+    callbacks_to_run: VecDeque<(usize, Js)>,
+    callback_queue: HashMap<usize, RegisteredCallback>,
+    close_callbacks: VecDeque<usize>,
+    context: Context,
+    cpu_bound_threads: Vec<usize>,
     epoll_pending_events: usize,
-    pub epoll_registrator: minimio::Registrator,
+    pub epoll_registrator: Registrator,
     epoll_thread: thread::JoinHandle<()>,
-    epoll_timeout: Arc<Mutex<Option<i32>>>, // fix
+    message_listeners: HashMap<usize, Rc<dyn Fn(Js)>>,
+    /// Messages that arrived before their port had a listener.
+    pending_messages: HashMap<usize, VecDeque<Js>>,
     event_receiver: Receiver<PollEvent>,
+    event_sender: Sender<PollEvent>,
+    pub(crate) http_pool: Pool,
+    /// Files opened with `Fs::open`, by handle id. Whatever is still open
+    /// is closed along with the runtime.
+    pub(crate) file_handles: HashMap<usize, Arc<File>>,
     identity_token: usize,
+    immediates: VecDeque<usize>,
+    microtasks: VecDeque<(Box<dyn FnOnce()>, Context)>,
     pending_events: usize,
+    pending_tasks: VecDeque<Task>,
+    remote_handles: Weak<KeepAlive>,
     thread_pool: Vec<NodeThread>,
-    timers: BTreeMap<Instant, usize>,
+    ticks: usize,
+    timers: BTreeMap<Instant, Vec<usize>>,
+    /// Callbacks that don't count towards `pending_events`.
+    unrefed_callbacks: HashSet<usize>,
     timers_to_remove: Vec<Instant>,
+    uncaught_exception: Option<UncaughtException>,
+    uncaught_exception_hook: Option<Rc<dyn Fn(UncaughtException)>>,
+    uring: Option<Uring>,
+    waker: Arc<EventFd>,
 }
 
 impl Runtime {
     pub fn new() -> Self {
         let (event_sender, event_receiver) = channel::<PollEvent>();
+        let waker = Arc::new(EventFd::new().expect("Error creating eventfd"));
         let mut threads = Vec::with_capacity(NUM_THREADS);
 
         for i in 0..NUM_THREADS {
             let (evt_sender, evt_receiver) = channel::<Task>();
             let event_sender = event_sender.clone();
+            let waker = waker.clone();
 
             let handle = thread::Builder::new()
                 .name(format!("pool{}", i))
@@ -61,11 +185,14 @@ impl Runtime {
                             break;
                         };
 
-                        let res = (task.task)();
+                        // A panicking task mustn't take the thread down with
+                        // it: the loop would wait for its callback forever.
+                        let res = panic::catch_unwind(AssertUnwindSafe(|| (task.task)()));
                         print(format!("finished running a task of type: {}.", task.kind));
 
                         let event = PollEvent::ThreadPool((i, task.callback_id, res));
                         event_sender.send(event).expect("threadpool");
+                        waker.notify().expect("threadpool wakeup");
                     }
                 })
                 .expect("Couldn't initialize thread pool.");
@@ -79,36 +206,36 @@ impl Runtime {
         }
 
         // ===== EPOLL THREAD =====
-        let mut poll = minimio::Poll::new().expect("Error creating epoll queue");
+        let mut poll = Poll::new().expect("Error creating epoll queue");
         let registrator = poll.registrator();
-        let epoll_timeout = Arc::new(Mutex::new(None));
-        let epoll_timeout_clone = epoll_timeout.clone();
+        let epoll_sender = event_sender.clone();
+        let epoll_waker = waker.clone();
 
         let epoll_thread = thread::Builder::new()
             .name("epoll".to_string())
             .spawn(move || {
-                let mut events = minimio::Events::with_capacity(1024);
+                let mut events = Events::with_capacity(1024);
 
                 loop {
-                    let epoll_timeout_handle = epoll_timeout_clone.lock().unwrap();
-                    let timeout = *epoll_timeout_handle;
-                    drop(epoll_timeout_handle);
-
-                    match poll.poll(&mut events, timeout) {
+                    // Timers are handled by the loop thread itself, so we only
+                    // wake up when there is I/O to report.
+                    match poll.poll(&mut events, None) {
                         Ok(v) if v > 0 => {
                             for i in 0..v {
-                                let event = events.get_mut(i).expect("No events in event list.");
+                                let event = events.get(i).expect("No events in event list.");
                                 print(format!("epoll event {} is ready", event.id()));
 
                                 let event = PollEvent::Epoll(event.id());
-                                event_sender.send(event).expect("epoll event");
+                                epoll_sender.send(event).expect("epoll event");
                             }
+                            epoll_waker.notify().expect("epoll wakeup");
                         }
-                        Ok(v) if v == 0 => {
+                        Ok(0) => {
                             print("epoll event timeout is ready");
-                            event_sender
+                            epoll_sender
                                 .send(PollEvent::Timeout)
                                 .expect("epoll timeout");
+                            epoll_waker.notify().expect("epoll wakeup");
                         }
                         Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
                             print("received event of type: Close");
@@ -121,63 +248,174 @@ impl Runtime {
             })
             .expect("Error creating epoll thread");
 
+        // File I/O falls back to the thread pool without io_uring.
+        let uring = Uring::new(URING_ENTRIES).and_then(|uring| {
+            let eventfd = uring.eventfd();
+            registrator.register(eventfd, URING_TOKEN, Interests::READABLE)?;
+            Ok(uring)
+        });
+        if let Err(ref e) = uring {
+            print(format!("io_uring not available: {}", e));
+        }
+
         Runtime {
             available_threads: (0..4).collect(),
-            callbacks_to_run: vec![],
+            callbacks_to_run: VecDeque::new(),
             callback_queue: HashMap::new(),
+            close_callbacks: VecDeque::new(),
+            context: Context::default(),
+            cpu_bound_threads: vec![],
             epoll_pending_events: 0,
             epoll_registrator: registrator,
             epoll_thread,
-            epoll_timeout,
+            message_listeners: HashMap::new(),
+            pending_messages: HashMap::new(),
             event_receiver,
+            event_sender,
+            http_pool: Pool::new(),
+            file_handles: HashMap::new(),
             identity_token: 0,
+            immediates: VecDeque::new(),
+            microtasks: VecDeque::new(),
             pending_events: 0,
+            pending_tasks: VecDeque::new(),
+            remote_handles: Weak::new(),
             thread_pool: threads,
+            ticks: 0,
             timers: BTreeMap::new(),
             timers_to_remove: vec![],
+            unrefed_callbacks: HashSet::new(),
+            uncaught_exception: None,
+            uncaught_exception_hook: None,
+            uring: uring.ok(),
+            waker,
         }
     }
 
-    pub fn run(mut self, f: impl Fn()) {
-        let rt_ptr: *mut Runtime = &mut self;
-        unsafe { RUNTIME = rt_ptr };
-        let mut ticks = 0;
+    /// Runs `f` and then the loop until there's no work left. A panicking
+    /// callback stops the loop and is returned as an error unless a hook was
+    /// installed with `on_uncaught_exception`. The thread pool and the epoll
+    /// thread are shut down either way.
+    pub fn run(mut self, f: impl FnOnce()) -> Result<(), UncaughtException> {
+        let mut result = self.enter(f);
 
-        f();
+        while result.is_ok() && self.is_alive() {
+            result = self.run_once(None).map(|_| ());
+        }
 
-        while self.pending_events > 0 {
-            ticks += 1;
-            print(format!("===== TICK {} =====", ticks));
-            self.process_expired_timers();
-            self.run_callback();
-            if self.pending_events == 0 {
-                break;
-            }
-            let next_timeout = self.get_next_timeout();
-            let mut epoll_timeout_lock = self.epoll_timeout.lock().unwrap();
-            *epoll_timeout_lock = next_timeout;
+        self.shutdown();
+        result
+    }
 
-            drop(epoll_timeout_lock);
+    /// Runs `f` with this runtime as the current one, e.g. the main script
+    /// when driving the loop with `run_once`.
+    pub fn enter(&mut self, f: impl FnOnce()) -> Result<(), UncaughtException> {
+        let rt_ptr: *mut Runtime = self;
+        RUNTIME.with(|rt| rt.set(rt_ptr));
 
-            if let Ok(event) = self.event_receiver.recv() {
-                match event {
-                    PollEvent::Timeout => (),
-                    PollEvent::ThreadPool((thread_id, callback_id, data)) => {
-                        self.process_threadpool_events(thread_id, callback_id, data);
-                    }
-                    PollEvent::Epoll(event_id) => {
-                        self.process_epoll_events(event_id);
-                    }
-                }
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            self.handle_uncaught_exception(payload, CallbackOrigin::Script);
+        }
+        self.run_microtasks();
+
+        match self.uncaught_exception.take() {
+            Some(exception) => Err(exception),
+            None => Ok(()),
+        }
+    }
+
+    /// Processes a single tick. The poll phase waits for at most `timeout`
+    /// (`None` waits until something happens). Returns `true` if there is
+    /// still work left.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> Result<bool, UncaughtException> {
+        let rt_ptr: *mut Runtime = self;
+        RUNTIME.with(|rt| rt.set(rt_ptr));
+
+        self.ticks += 1;
+        print(format!("===== TICK {} =====", self.ticks));
+        self.process_expired_timers();
+        self.run_callbacks();
+        self.poll(timeout);
+        self.process_immediates();
+        self.process_close_callbacks();
+
+        match self.uncaught_exception.take() {
+            Some(exception) => Err(exception),
+            None => Ok(self.is_alive()),
+        }
+    }
+
+    /// Once a hook is installed a panicking callback no longer stops the
+    /// loop. The hook gets the panic payload and where the callback came
+    /// from, and the loop carries on with the next callback.
+    pub fn on_uncaught_exception(&mut self, hook: impl Fn(UncaughtException) + 'static) {
+        self.uncaught_exception_hook = Some(Rc::new(hook));
+    }
+
+    fn handle_uncaught_exception(&mut self, payload: Box<dyn Any + Send>, origin: CallbackOrigin) {
+        let exception = UncaughtException { payload, origin };
+        print(format!("uncaught exception: {}", exception));
+
+        match self.uncaught_exception_hook.clone() {
+            Some(hook) => hook(exception),
+            None => self.uncaught_exception = Some(exception),
+        }
+    }
+
+    /// The loop is kept alive by pending callbacks and by any `RemoteHandle`
+    /// that's still around.
+    fn is_alive(&self) -> bool {
+        self.pending_events > 0 || self.remote_handles.strong_count() > 0
+    }
+
+    /// Returns a handle other threads can use to hand work to this runtime.
+    /// The loop won't exit while a clone of it is alive.
+    pub fn remote_handle(&mut self) -> RemoteHandle {
+        let keep_alive = match self.remote_handles.upgrade() {
+            Some(keep_alive) => keep_alive,
+            None => {
+                let keep_alive = Arc::new(KeepAlive {
+                    sender: self.event_sender.clone(),
+                    waker: self.waker.clone(),
+                });
+                self.remote_handles = Arc::downgrade(&keep_alive);
+                keep_alive
             }
-            self.run_callback();
+        };
+
+        RemoteHandle {
+            sender: self.event_sender.clone(),
+            waker: self.waker.clone(),
+            _keep_alive: keep_alive,
+        }
+    }
+
+    /// A fd that becomes readable when thread pool or epoll events are
+    /// waiting to be processed by `run_once`.
+    pub fn backend_fd(&self) -> RawFd {
+        self.waker.as_raw_fd()
+    }
+
+    /// How long a host may sleep before calling `run_once` even if
+    /// `backend_fd` didn't become readable. `None` means no timers are due.
+    pub fn backend_timeout(&self) -> Option<Duration> {
+        let has_ready_work = !self.callbacks_to_run.is_empty()
+            || !self.immediates.is_empty()
+            || !self.close_callbacks.is_empty();
+
+        if has_ready_work {
+            Some(Duration::from_millis(0))
+        } else {
+            self.get_next_timeout()
         }
+    }
+
+    /// Stops the thread pool and the epoll thread.
+    pub fn shutdown(self) {
         for thread in self.thread_pool.into_iter() {
-            thread
-                .sender
-                .send(Task::close())
-                .expect("threadpool cleanup");
-            thread.handle.join().unwrap();
+            // A thread that died some other way has nothing left to stop.
+            let _ = thread.sender.send(Task::close());
+            let _ = thread.handle.join();
         }
 
         self.epoll_registrator.close_loop().unwrap();
@@ -193,50 +431,239 @@ impl Runtime {
             .range(..=Instant::now())
             .for_each(|(k, _)| timers_to_remove.push(*k));
 
-        while let Some(key) = self.timers_to_remove.pop() {
-            let callback_id = self.timers.remove(&key).unwrap();
-            self.callbacks_to_run.push((callback_id, Js::Undefined));
+        let timers = &mut self.timers;
+        let expired: Vec<usize> = self
+            .timers_to_remove
+            .drain(..)
+            .flat_map(|key| timers.remove(&key).unwrap())
+            .collect();
+
+        for callback_id in expired {
+            self.run_callback(callback_id, Js::Undefined);
         }
     }
 
-    fn get_next_timeout(&self) -> Option<i32> {
-        self.timers.iter().next().map(|(&instant, _)| {
-            let mut tim_to_next_timeout = instant - Instant::now();
-            if tim_to_next_timeout < Duration::new(0, 0) {
-                tim_to_next_timeout = Duration::new(0, 0);
+    fn get_next_timeout(&self) -> Option<Duration> {
+        self.timers
+            .keys()
+            .next()
+            .map(|&instant| instant.saturating_duration_since(Instant::now()))
+    }
+
+    fn poll(&mut self, max_wait: Option<Duration>) {
+        if !self.is_alive() || self.uncaught_exception.is_some() {
+            return;
+        }
+
+        let timeout = match (self.backend_timeout(), max_wait) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        // Clear before draining so a wakeup for an event we don't get to see
+        // here leaves the fd readable.
+        self.waker.clear().expect("clear eventfd");
+
+        let first = match timeout {
+            Some(timeout) => match self.event_receiver.recv_timeout(timeout) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => panic!("event channel closed"),
+            },
+            None => self.event_receiver.recv().ok(),
+        };
+
+        let mut next = first;
+        while let Some(event) = next {
+            match event {
+                PollEvent::Timeout => (),
+                PollEvent::ThreadPool((thread_id, callback_id, data)) => {
+                    self.process_threadpool_events(thread_id, callback_id, data);
+                }
+                PollEvent::Epoll(URING_TOKEN) => self.process_uring_events(),
+                PollEvent::Epoll(event_id) => {
+                    self.process_epoll_events(event_id);
+                }
+                PollEvent::Remote(task) => task(self),
             }
-            tim_to_next_timeout.as_millis() as i32
-        })
+            next = self.event_receiver.try_recv().ok();
+        }
+
+        self.run_callbacks();
+    }
+
+    fn process_immediates(&mut self) {
+        let immediates: Vec<usize> = self.immediates.drain(..).collect();
+        for callback_id in immediates {
+            self.run_callback(callback_id, Js::Undefined);
+        }
+    }
+
+    fn process_close_callbacks(&mut self) {
+        while let Some(callback_id) = self.close_callbacks.pop_front() {
+            self.run_callback(callback_id, Js::Undefined);
+        }
+    }
+
+    fn run_callbacks(&mut self) {
+        while let Some((callback_id, data)) = self.callbacks_to_run.pop_front() {
+            self.run_callback(callback_id, data);
+        }
     }
 
-    fn run_callback(&mut self) {
-        while let Some((callback_id, data)) = self.callbacks_to_run.pop() {
-            let cb = self.callback_queue.remove(&callback_id).unwrap();
-            cb(data);
+    fn run_callback(&mut self, callback_id: usize, data: Js) {
+        // Once the loop is going down nothing else gets to run.
+        if self.uncaught_exception.is_some() {
+            return;
+        }
+
+        // Cancelled callbacks are gone from the queue already.
+        let RegisteredCallback {
+            cb,
+            context,
+            origin,
+        } = match self.callback_queue.remove(&callback_id) {
+            Some(callback) => callback,
+            None => return,
+        };
+        let previous = std::mem::replace(&mut self.context, context);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cb(data)));
+        self.context = previous;
+        if !self.unrefed_callbacks.remove(&callback_id) {
             self.pending_events -= 1;
         }
+
+        if let Err(payload) = result {
+            self.handle_uncaught_exception(payload, origin);
+        }
+        self.run_microtasks();
+    }
+
+    fn run_microtasks(&mut self) {
+        while self.uncaught_exception.is_none() {
+            let (task, context) = match self.microtasks.pop_front() {
+                Some(microtask) => microtask,
+                None => break,
+            };
+            let previous = std::mem::replace(&mut self.context, context);
+            let result = panic::catch_unwind(AssertUnwindSafe(task));
+            self.context = previous;
+
+            if let Err(payload) = result {
+                self.handle_uncaught_exception(payload, CallbackOrigin::Microtask);
+            }
+        }
     }
 
-    fn process_threadpool_events(&mut self, thread_id: usize, callback_id: usize, data: Js) {
-        // fix
-        self.callbacks_to_run.push((callback_id, data));
+    fn process_threadpool_events(
+        &mut self,
+        thread_id: usize,
+        callback_id: usize,
+        data: thread::Result<Js>,
+    ) {
+        match data {
+            Ok(data) => self.callbacks_to_run.push_back((callback_id, data)),
+            // The callback has nothing to go on, so the panic is reported
+            // in its place.
+            Err(payload) => {
+                if let Some(callback) = self.callback_queue.remove(&callback_id) {
+                    if !self.unrefed_callbacks.remove(&callback_id) {
+                        self.pending_events -= 1;
+                    }
+                    self.handle_uncaught_exception(payload, callback.origin);
+                }
+            }
+        }
         self.available_threads.push(thread_id);
+        self.cpu_bound_threads.retain(|&id| id != thread_id);
+        self.dispatch_pending_tasks();
     }
 
     fn process_epoll_events(&mut self, event_id: usize) {
-        self.callbacks_to_run.push((event_id, Js::Undefined));
+        // The event may have raced with `deregister_event_epoll`.
+        if !self.callback_queue.contains_key(&event_id) {
+            return;
+        }
+        self.callbacks_to_run.push_back((event_id, Js::Undefined));
         self.epoll_pending_events -= 1;
     }
 
-    fn get_available_thread(&mut self) -> usize {
-        match self.available_threads.pop() {
-            Some(thread_id) => thread_id,
-            // We would normally return None and not panic!
-            None => panic!("Out of threads."),
+    fn process_uring_events(&mut self) {
+        let uring = match self.uring.as_mut() {
+            Some(uring) => uring,
+            None => return,
+        };
+        // Clear before reaping so a completion posted meanwhile re-triggers.
+        uring.eventfd().clear().expect("clear io_uring eventfd");
+        let done = uring.complete();
+        self.epoll_registrator
+            .reregister(uring.eventfd(), URING_TOKEN, Interests::READABLE)
+            .expect("re-arm io_uring eventfd");
+
+        for (callback_id, data) in done {
+            print(format!("io_uring event {} is ready", callback_id));
+            self.callbacks_to_run
+                .push_back((callback_id as usize, data));
         }
     }
 
-    fn generate_identity(&mut self) -> usize {
+    /// Hands `task` to an idle thread, or queues it until one frees up.
+    fn dispatch_task(&mut self, task: Task) {
+        self.pending_tasks.push_back(task);
+        self.dispatch_pending_tasks();
+    }
+
+    /// Starts queued tasks in order while there are idle threads. A
+    /// CPU-bound task that would go over `MAX_CPU_BOUND_TASKS` stays queued,
+    /// and the tasks behind it may overtake it.
+    fn dispatch_pending_tasks(&mut self) {
+        while !self.available_threads.is_empty() {
+            let cpu_bound_full = self.cpu_bound_threads.len() >= MAX_CPU_BOUND_TASKS;
+            let next = self
+                .pending_tasks
+                .iter()
+                .position(|task| !(cpu_bound_full && task.kind.is_cpu_bound()));
+            let task = match next.and_then(|i| self.pending_tasks.remove(i)) {
+                Some(task) => task,
+                None => break,
+            };
+
+            let thread_id = self.available_threads.pop().unwrap();
+            if task.kind.is_cpu_bound() {
+                self.cpu_bound_threads.push(thread_id);
+            }
+            self.thread_pool[thread_id]
+                .sender
+                .send(task)
+                .expect("register work");
+        }
+    }
+
+    fn deliver_message(&mut self, port: usize, msg: Js) {
+        match self.message_listeners.get(&port).cloned() {
+            Some(listener) => {
+                let callback_id = self.register_event_remote(move |msg| listener(msg));
+                self.callbacks_to_run.push_back((callback_id, msg));
+            }
+            // Kept for the listener, like Node's MessagePort does.
+            None => self
+                .pending_messages
+                .entry(port)
+                .or_default()
+                .push_back(msg),
+        }
+    }
+
+    /// Sets the listener of `port`, which gets whatever arrived before it
+    /// first.
+    fn set_message_listener(&mut self, port: usize, cb: Rc<dyn Fn(Js)>) {
+        self.message_listeners.insert(port, cb);
+        for msg in self.pending_messages.remove(&port).unwrap_or_default() {
+            self.deliver_message(port, msg);
+        }
+    }
+
+    pub(crate) fn generate_identity(&mut self) -> usize {
         self.identity_token = self.identity_token.wrapping_add(1);
         self.identity_token
     }
@@ -257,19 +684,55 @@ impl Runtime {
         }
     }
 
-    fn add_callback(&mut self, ident: usize, cb: impl FnOnce(Js) + 'static) {
-        let boxed_cb = Box::new(cb);
-        self.callback_queue.insert(ident, boxed_cb);
+    fn add_callback(
+        &mut self,
+        ident: usize,
+        origin: CallbackOrigin,
+        cb: impl FnOnce(Js) + 'static,
+    ) {
+        let callback = RegisteredCallback {
+            cb: Box::new(cb),
+            context: self.context.clone(),
+            origin,
+        };
+        self.callback_queue.insert(ident, callback);
     }
 
     pub fn register_event_epoll(&mut self, token: usize, cb: impl FnOnce(Js) + 'static) {
-        self.add_callback(token, cb);
+        self.add_callback(token, CallbackOrigin::Epoll, cb);
 
         print(format!("Event with id: {} registered.", token));
         self.pending_events += 1;
         self.epoll_pending_events += 1;
     }
 
+    /// Drops the callback registered for `token` without running it. The fd
+    /// itself has to be deregistered from `epoll_registrator` by the caller.
+    pub fn deregister_event_epoll(&mut self, token: usize) {
+        if self.callback_queue.remove(&token).is_some() {
+            self.pending_events -= 1;
+            self.epoll_pending_events -= 1;
+        }
+    }
+
+    /// Registers a callback that a `RemoteHandle` will later `resolve` from
+    /// another thread. Returns the id to resolve it with.
+    pub fn register_event_remote(&mut self, cb: impl FnOnce(Js) + 'static) -> usize {
+        let callback_id = self.generate_cb_identity();
+        self.add_callback(callback_id, CallbackOrigin::Remote, cb);
+        self.pending_events += 1;
+        callback_id
+    }
+
+    /// Runs `cb` in the close phase of the current tick. Handles use this to
+    /// report that they've been torn down.
+    pub fn register_close_callback(&mut self, cb: impl FnOnce(Js) + 'static) {
+        let callback_id = self.generate_cb_identity();
+        self.add_callback(callback_id, CallbackOrigin::Close, cb);
+        self.close_callbacks.push_back(callback_id);
+        self.pending_events += 1;
+    }
+
     pub fn register_event_threadpool(
         &mut self,
         task: impl Fn() -> Js + Send + 'static,
@@ -277,7 +740,7 @@ impl Runtime {
         cb: impl FnOnce(Js) + 'static,
     ) {
         let callback_id = self.generate_cb_identity();
-        self.add_callback(callback_id, cb);
+        self.add_callback(callback_id, CallbackOrigin::ThreadPool(kind), cb);
 
         let event = Task {
             task: Box::new(task),
@@ -285,25 +748,99 @@ impl Runtime {
             kind,
         };
 
-        let available = self.get_available_thread();
-        self.thread_pool[available]
-            .sender
-            .send(event)
-            .expect("register work");
+        self.dispatch_task(event);
+        self.pending_events += 1;
+    }
+
+    /// Whether `register_event_uring` can be used.
+    pub(crate) fn has_uring(&self) -> bool {
+        self.uring.is_some()
+    }
+
+    /// Submits `op` to io_uring and calls `cb` with its result, like a thread
+    /// pool task of type `kind` would. Only call this if `has_uring`.
+    pub(crate) fn register_event_uring(
+        &mut self,
+        op: Op,
+        kind: ThreadPoolTaskKind,
+        cb: impl FnOnce(Js) + 'static,
+    ) {
+        let callback_id = self.generate_cb_identity();
+        self.add_callback(callback_id, CallbackOrigin::IoUring(kind), cb);
         self.pending_events += 1;
+
+        let uring = self.uring.as_mut().expect("io_uring not available");
+        // Including ops from earlier that were waiting for room.
+        for (callback_id, data) in uring.submit(callback_id as u64, op) {
+            self.callbacks_to_run
+                .push_back((callback_id as usize, data));
+        }
     }
 
-    fn set_timeout(&mut self, ms: u64, cb: impl Fn(Js) + 'static) {
-        let now = Instant::now();
+    fn set_timeout(&mut self, ms: u64, cb: impl FnOnce(Js) + 'static) -> TimerId {
+        let timeout = Instant::now() + Duration::from_millis(ms);
+        self.add_timer(timeout, cb)
+    }
+
+    fn add_timer(&mut self, timeout: Instant, cb: impl FnOnce(Js) + 'static) -> TimerId {
         let cb_id = self.generate_cb_identity();
-        self.add_callback(cb_id, cb);
-        let timeout = now + Duration::from_millis(ms);
-        self.timers.insert(timeout, cb_id);
+        self.add_callback(cb_id, CallbackOrigin::Timer, cb);
+        self.timers.entry(timeout).or_default().push(cb_id);
         self.pending_events += 1;
         print(format!("Registered timer event id: {}", cb_id));
+        TimerId(cb_id)
+    }
+
+    fn clear_timeout(&mut self, TimerId(cb_id): TimerId) {
+        if self.callback_queue.remove(&cb_id).is_none() {
+            return;
+        }
+        if !self.unrefed_callbacks.remove(&cb_id) {
+            self.pending_events -= 1;
+        }
+        self.timers.retain(|_, ids| {
+            ids.retain(|&id| id != cb_id);
+            !ids.is_empty()
+        });
+    }
+
+    fn unref_timer(&mut self, TimerId(cb_id): TimerId) {
+        if self.callback_queue.contains_key(&cb_id) && self.unrefed_callbacks.insert(cb_id) {
+            self.pending_events -= 1;
+        }
     }
+
+    fn set_immediate(&mut self, cb: impl Fn(Js) + 'static) {
+        let cb_id = self.generate_cb_identity();
+        self.add_callback(cb_id, CallbackOrigin::Immediate, cb);
+        self.immediates.push_back(cb_id);
+        self.pending_events += 1;
+        print(format!("Registered immediate event id: {}", cb_id));
+    }
+
+    fn queue_microtask(&mut self, cb: impl FnOnce() + 'static) {
+        self.microtasks
+            .push_back((Box::new(cb), self.context.clone()));
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Runtime::new()
+    }
+}
+
+struct RegisteredCallback {
+    cb: Box<dyn FnOnce(Js)>,
+    context: Context,
+    origin: CallbackOrigin,
 }
 
+/// The values set with `run_with_context`, keyed by their type. Captured
+/// when a callback is registered and restored while it runs.
+#[derive(Clone, Default)]
+struct Context(Rc<HashMap<TypeId, Rc<dyn Any>>>);
+
 struct Task {
     task: Box<dyn Fn() -> Js + Send + 'static>,
     callback_id: usize,
@@ -325,27 +862,224 @@ struct NodeThread {
     sender: Sender<Task>,
 }
 
+/// A long-lived OS thread running a runtime of its own. Unlike a `Task` on a
+/// `NodeThread` it doesn't run to completion; it talks to its parent with
+/// messages until both sides let go.
+///
+/// The worker keeps running while the parent holds on to its `Worker`, and
+/// the parent's loop stays alive until the worker has exited.
+pub struct Worker {
+    id: usize,
+    worker: RemoteHandle,
+}
+
+impl Worker {
+    pub fn spawn(script: impl FnOnce(ParentPort) + Send + 'static) -> Worker {
+        let rt = runtime();
+        let id = rt.generate_identity();
+        let parent = rt.remote_handle();
+        let (handle_sender, handle_receiver) = channel::<RemoteHandle>();
+
+        thread::Builder::new()
+            .name(format!("worker{}", id))
+            .spawn(move || {
+                let mut worker_rt = Runtime::new();
+                handle_sender
+                    .send(worker_rt.remote_handle())
+                    .expect("worker handle");
+
+                let port = ParentPort {
+                    id,
+                    parent: parent.clone(),
+                };
+                if let Err(e) = worker_rt.run(move || script(port)) {
+                    print(format!("worker exited with: {}", e));
+                }
+
+                parent.send(move |rt| {
+                    rt.message_listeners.remove(&id);
+                });
+            })
+            .expect("Error creating worker thread");
+
+        let worker = handle_receiver.recv().expect("worker failed to start");
+        Worker { id, worker }
+    }
+
+    pub fn post_message(&self, msg: Js) {
+        let id = self.id;
+        self.worker.send(move |rt| rt.deliver_message(id, msg));
+    }
+
+    /// Called on the parent's loop for every message the worker posts.
+    pub fn on_message(&self, cb: impl Fn(Js) + 'static) {
+        runtime().set_message_listener(self.id, Rc::new(cb));
+    }
+}
+
+/// The worker's end of the channel to its parent.
+#[derive(Clone)]
+pub struct ParentPort {
+    id: usize,
+    parent: RemoteHandle,
+}
+
+impl ParentPort {
+    pub fn post_message(&self, msg: Js) {
+        let id = self.id;
+        self.parent.send(move |rt| rt.deliver_message(id, msg));
+    }
+
+    /// Called on the worker's loop for every message the parent posts.
+    pub fn on_message(&self, cb: impl Fn(Js) + 'static) {
+        runtime().set_message_listener(self.id, Rc::new(cb));
+    }
+}
+
+/// Returned by `set_timeout` to cancel the timer with `clear_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimerId(usize);
+
+#[derive(Debug, Clone, Copy)]
 pub enum ThreadPoolTaskKind {
     FileRead,
+    FileWrite,
+    FileAppend,
+    Stat,
+    ReadDir,
+    Mkdir,
+    Remove,
+    Rename,
+    CopyFile,
+    Walk,
+    Open,
+    Truncate,
+    Sync,
+    CloseFile,
     Encrypt,
+    Hash,
+    HashFile,
+    Hmac,
+    Pbkdf2,
+    Scrypt,
+    RandomBytes,
+    Seal,
+    Unseal,
+    Lookup,
     Close,
 }
 
+impl ThreadPoolTaskKind {
+    /// Tasks that keep a thread busy computing rather than waiting on the
+    /// disk. See `MAX_CPU_BOUND_TASKS`.
+    pub fn is_cpu_bound(self) -> bool {
+        use ThreadPoolTaskKind::*;
+        matches!(
+            self,
+            Encrypt | Hash | HashFile | Hmac | Pbkdf2 | Scrypt | Seal | Unseal
+        )
+    }
+}
+
 impl Display for ThreadPoolTaskKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use ThreadPoolTaskKind::*;
         match self {
             FileRead => write!(f, "File read"),
+            FileWrite => write!(f, "File write"),
+            FileAppend => write!(f, "File append"),
+            Stat => write!(f, "Stat"),
+            ReadDir => write!(f, "Read dir"),
+            Mkdir => write!(f, "Mkdir"),
+            Remove => write!(f, "Remove"),
+            Rename => write!(f, "Rename"),
+            CopyFile => write!(f, "Copy file"),
+            Walk => write!(f, "Walk"),
+            Open => write!(f, "Open"),
+            Truncate => write!(f, "Truncate"),
+            Sync => write!(f, "Sync"),
+            CloseFile => write!(f, "Close file"),
             Encrypt => write!(f, "Encrypt"),
+            Hash => write!(f, "Hash"),
+            HashFile => write!(f, "Hash file"),
+            Hmac => write!(f, "Hmac"),
+            Pbkdf2 => write!(f, "PBKDF2"),
+            Scrypt => write!(f, "Scrypt"),
+            RandomBytes => write!(f, "Random bytes"),
+            Seal => write!(f, "Seal"),
+            Unseal => write!(f, "Unseal"),
+            Lookup => write!(f, "DNS lookup"),
             Close => write!(f, "Close"),
         }
     }
 }
 
+/// Where a callback that panicked was registered from.
+#[derive(Debug, Clone, Copy)]
+pub enum CallbackOrigin {
+    Script,
+    Timer,
+    Immediate,
+    Microtask,
+    ThreadPool(ThreadPoolTaskKind),
+    IoUring(ThreadPoolTaskKind),
+    Epoll,
+    Remote,
+    Close,
+}
+
+impl Display for CallbackOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use CallbackOrigin::*;
+        match self {
+            Script => write!(f, "Script"),
+            Timer => write!(f, "Timer"),
+            Immediate => write!(f, "Immediate"),
+            Microtask => write!(f, "Microtask"),
+            ThreadPool(kind) => write!(f, "Thread pool ({})", kind),
+            IoUring(kind) => write!(f, "io_uring ({})", kind),
+            Epoll => write!(f, "Epoll"),
+            Remote => write!(f, "Remote"),
+            Close => write!(f, "Close"),
+        }
+    }
+}
+
+/// A panic that escaped a callback.
+#[derive(Debug)]
+pub struct UncaughtException {
+    pub payload: Box<dyn Any + Send>,
+    pub origin: CallbackOrigin,
+}
+
+impl UncaughtException {
+    /// The panic message, if the payload was a string.
+    pub fn message(&self) -> Option<&str> {
+        match self.payload.downcast_ref::<&str>() {
+            Some(msg) => Some(msg),
+            None => self.payload.downcast_ref::<String>().map(|s| s.as_str()),
+        }
+    }
+}
+
+impl Display for UncaughtException {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = self.message().unwrap_or("Box<dyn Any>");
+        write!(f, "{} callback panicked: {}", self.origin, msg)
+    }
+}
+
+impl std::error::Error for UncaughtException {}
+
+#[derive(Debug)]
 pub enum Js {
     Undefined,
     String(String),
     Int(usize),
+    Bytes(Vec<u8>),
+    Array(Vec<Js>),
+    Object(BTreeMap<String, Js>),
+    Error(io::Error),
 }
 
 impl Js {
@@ -362,10 +1096,557 @@ impl Js {
             _ => None,
         }
     }
+
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            Js::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn into_array(self) -> Option<Vec<Js>> {
+        match self {
+            Js::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn into_object(self) -> Option<BTreeMap<String, Js>> {
+        match self {
+            Js::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+
+    pub fn into_error(self) -> Option<io::Error> {
+        match self {
+            Js::Error(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 enum PollEvent {
-    ThreadPool((usize, usize, Js)),
+    /// The task's result, or what it panicked with.
+    ThreadPool((usize, usize, thread::Result<Js>)),
     Epoll(usize),
     Timeout,
+    Remote(Box<dyn FnOnce(&mut Runtime) + Send>),
+}
+
+/// A `Send` handle to a runtime for use from other OS threads. Everything
+/// sent through it is delivered to the loop thread in the poll phase.
+#[derive(Clone)]
+pub struct RemoteHandle {
+    sender: Sender<PollEvent>,
+    waker: Arc<EventFd>,
+    _keep_alive: Arc<KeepAlive>,
+}
+
+impl RemoteHandle {
+    /// Runs `f` on the loop thread as a regular callback.
+    pub fn post(&self, f: impl FnOnce() + Send + 'static) {
+        self.send(move |rt| {
+            let callback_id = rt.register_event_remote(move |_| f());
+            rt.callbacks_to_run.push_back((callback_id, Js::Undefined));
+        });
+    }
+
+    /// Calls the callback registered with `Runtime::register_event_remote`.
+    pub fn resolve(&self, callback_id: usize, data: Js) {
+        self.send(move |rt| rt.callbacks_to_run.push_back((callback_id, data)));
+    }
+
+    /// Schedules a timer counting from now, not from when the loop notices.
+    pub fn set_timeout(&self, ms: u64, cb: impl FnOnce(Js) + Send + 'static) {
+        let timeout = Instant::now() + Duration::from_millis(ms);
+        self.send(move |rt| {
+            rt.add_timer(timeout, cb);
+        });
+    }
+
+    /// Work sent after the runtime has shut down is dropped.
+    fn send(&self, f: impl FnOnce(&mut Runtime) + Send + 'static) {
+        if self.sender.send(PollEvent::Remote(Box::new(f))).is_ok() {
+            self.waker.notify().expect("remote wakeup");
+        }
+    }
+}
+
+/// Shared by all clones of a `RemoteHandle`. Dropping the last one wakes up
+/// the loop so it can notice it may be done.
+struct KeepAlive {
+    sender: Sender<PollEvent>,
+    waker: Arc<EventFd>,
+}
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        if self
+            .sender
+            .send(PollEvent::Remote(Box::new(|_| ())))
+            .is_ok()
+        {
+            let _ = self.waker.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn run_logged(f: impl Fn(Rc<RefCell<Vec<&'static str>>>)) -> Vec<&'static str> {
+        let log = Rc::new(RefCell::new(vec![]));
+        let script_log = log.clone();
+        Runtime::new().run(move || f(script_log.clone())).unwrap();
+        let log = log.borrow().clone();
+        log
+    }
+
+    #[test]
+    fn test_microtasks_drain_after_every_callback() {
+        let log = run_logged(|log| {
+            let l = log.clone();
+            queue_microtask(move || l.borrow_mut().push("script microtask"));
+            let l = log.clone();
+            set_timeout(0, move |_| {
+                l.borrow_mut().push("timeout 1");
+                let l = l.clone();
+                queue_microtask(move || {
+                    l.borrow_mut().push("microtask 1");
+                    let l = l.clone();
+                    queue_microtask(move || l.borrow_mut().push("nested microtask 1"));
+                });
+            });
+            let l = log.clone();
+            set_timeout(0, move |_| {
+                l.borrow_mut().push("timeout 2");
+                let l = l.clone();
+                queue_microtask(move || l.borrow_mut().push("microtask 2"));
+            });
+        });
+
+        assert_eq!(
+            log,
+            vec![
+                "script microtask",
+                "timeout 1",
+                "microtask 1",
+                "nested microtask 1",
+                "timeout 2",
+                "microtask 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_phase_order() {
+        let log = run_logged(|log| {
+            let l = log.clone();
+            set_timeout(0, move |_| l.borrow_mut().push("timer"));
+            let l = log.clone();
+            runtime().register_event_threadpool(
+                || Js::Undefined,
+                ThreadPoolTaskKind::Encrypt,
+                move |_| {
+                    l.borrow_mut().push("poll");
+                    let c = l.clone();
+                    runtime().register_close_callback(move |_| c.borrow_mut().push("close"));
+                    let i = l.clone();
+                    set_immediate(move |_| i.borrow_mut().push("check"));
+                },
+            );
+        });
+
+        assert_eq!(log, vec!["timer", "poll", "check", "close"]);
+    }
+
+    #[test]
+    fn test_clear_timeout() {
+        let start = Instant::now();
+        let log = run_logged(|log| {
+            let l = log.clone();
+            let cleared = set_timeout(5000, move |_| l.borrow_mut().push("cleared"));
+            let l = log.clone();
+            let soon = set_timeout(10, move |_| l.borrow_mut().push("fired"));
+            set_timeout(10, move |_| clear_timeout(cleared));
+            clear_timeout(soon);
+            let l = log.clone();
+            set_timeout(20, move |_| l.borrow_mut().push("fired"));
+        });
+
+        assert_eq!(log, vec!["fired"]);
+        // The loop didn't wait for the cleared timer.
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_unref_timer() {
+        let start = Instant::now();
+        let log = run_logged(|log| {
+            let l = log.clone();
+            let fires = set_timeout(10, move |_| l.borrow_mut().push("unrefed"));
+            unref_timer(fires);
+            let l = log.clone();
+            set_timeout(30, move |_| l.borrow_mut().push("fired"));
+            let l = log.clone();
+            let late = set_timeout(5000, move |_| l.borrow_mut().push("late"));
+            unref_timer(late);
+            unref_timer(late);
+            // Clearing one doesn't take it off the count twice.
+            let cleared = set_timeout(5000, |_| {});
+            unref_timer(cleared);
+            clear_timeout(cleared);
+        });
+
+        // It fires while something else keeps the loop going.
+        assert_eq!(log, vec!["unrefed", "fired"]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_immediate_before_timeout_inside_io_callback() {
+        let log = run_logged(|log| {
+            let l = log.clone();
+            runtime().register_event_threadpool(
+                || Js::Undefined,
+                ThreadPoolTaskKind::Encrypt,
+                move |_| {
+                    let t = l.clone();
+                    set_timeout(0, move |_| t.borrow_mut().push("timeout"));
+                    let i = l.clone();
+                    set_immediate(move |_| {
+                        i.borrow_mut().push("immediate");
+                        let n = i.clone();
+                        set_immediate(move |_| n.borrow_mut().push("next tick immediate"));
+                    });
+                },
+            );
+        });
+
+        assert_eq!(log, vec!["immediate", "timeout", "next tick immediate"]);
+    }
+
+    #[test]
+    fn test_cpu_bound_tasks_leave_threads_for_io() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let log = run_logged(|log| {
+            use ThreadPoolTaskKind::*;
+            for &kind in &[Pbkdf2, Hash, Hmac, HashFile] {
+                let (running, max_running) = (running.clone(), max_running.clone());
+                let work = move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(100));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Js::Undefined
+                };
+                let l = log.clone();
+                runtime()
+                    .register_event_threadpool(work, kind, move |_| l.borrow_mut().push("cpu"));
+            }
+            let l = log.clone();
+            runtime().register_event_threadpool(
+                || Js::Undefined,
+                FileRead,
+                move |_| l.borrow_mut().push("io"),
+            );
+        });
+
+        assert_eq!(log, vec!["io", "cpu", "cpu", "cpu", "cpu"]);
+        assert_eq!(max_running.load(Ordering::SeqCst), MAX_CPU_BOUND_TASKS);
+    }
+
+    #[repr(C)]
+    struct PollFd {
+        fd: i32,
+        events: i16,
+        revents: i16,
+    }
+
+    extern "C" {
+        fn poll(fds: *mut PollFd, nfds: u64, timeout: i32) -> i32;
+    }
+
+    fn wait_readable(fd: RawFd, timeout_ms: i32) -> bool {
+        let mut pfd = PollFd {
+            fd,
+            events: 1,
+            revents: 0,
+        };
+        unsafe { poll(&mut pfd, 1, timeout_ms) == 1 }
+    }
+
+    #[test]
+    fn test_run_once_driven_by_host() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut rt = Runtime::new();
+
+        let l = log.clone();
+        rt.enter(move || {
+            runtime().register_event_threadpool(
+                || Js::Int(42),
+                ThreadPoolTaskKind::Encrypt,
+                move |res| l.borrow_mut().push(res.into_int().unwrap()),
+            );
+        })
+        .unwrap();
+        assert_eq!(rt.backend_timeout(), None);
+
+        assert!(wait_readable(rt.backend_fd(), 1000));
+        assert!(!rt.run_once(Some(Duration::from_millis(0))).unwrap());
+        assert_eq!(*log.borrow(), vec![42]);
+        assert!(!wait_readable(rt.backend_fd(), 0));
+
+        rt.shutdown();
+    }
+
+    #[test]
+    fn test_run_once_reports_timers() {
+        let mut rt = Runtime::new();
+
+        rt.enter(|| {
+            set_timeout(20, |_| ());
+        })
+        .unwrap();
+        assert!(rt.run_once(Some(Duration::from_millis(0))).unwrap());
+
+        let timeout = rt.backend_timeout().unwrap();
+        assert!(timeout <= Duration::from_millis(20));
+        thread::sleep(timeout);
+        assert!(!rt.run_once(Some(Duration::from_millis(0))).unwrap());
+
+        rt.shutdown();
+    }
+
+    #[test]
+    fn test_remote_handle_from_foreign_thread() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut rt = Runtime::new();
+        let handle = rt.remote_handle();
+
+        let l = log.clone();
+        rt.run(move || {
+            let l = l.clone();
+            let callback_id = runtime().register_event_remote(move |res| {
+                l.borrow_mut().push(res.into_string().unwrap());
+            });
+
+            let handle = handle.clone();
+            thread::spawn(move || {
+                handle.post(|| print("posted from a foreign thread"));
+                handle.set_timeout(10, |_| print("remote timer"));
+                handle.resolve(callback_id, Js::String("resolved".to_string()));
+            });
+        })
+        .unwrap();
+
+        assert_eq!(*log.borrow(), vec!["resolved".to_string()]);
+    }
+
+    #[test]
+    fn test_worker_message_round_trip() {
+        let log = run_logged(|log| {
+            let worker = Worker::spawn(|port| {
+                let reply = port.clone();
+                port.on_message(move |msg| {
+                    let n = msg.into_int().unwrap();
+                    reply.post_message(Js::Int(n * 2));
+                });
+            });
+            worker.post_message(Js::Int(21));
+
+            let worker = Rc::new(RefCell::new(Some(worker)));
+            let w = worker.clone();
+            worker.borrow().as_ref().unwrap().on_message(move |msg| {
+                assert_eq!(msg.into_int(), Some(42));
+                log.borrow_mut().push("reply");
+                // Letting go of the worker allows both loops to finish.
+                w.borrow_mut().take();
+            });
+        });
+
+        assert_eq!(log, vec!["reply"]);
+    }
+
+    #[test]
+    fn test_messages_wait_for_a_listener() {
+        let log = run_logged(|log| {
+            let worker = Worker::spawn(|port| {
+                port.post_message(Js::Int(1));
+                port.post_message(Js::Int(2));
+                // Listens only once the parent's message is there.
+                let port = port.clone();
+                set_timeout(50, move |_| {
+                    let reply = port.clone();
+                    port.on_message(move |msg| reply.post_message(msg));
+                });
+            });
+            worker.post_message(Js::Int(3));
+
+            let worker = Rc::new(RefCell::new(Some(worker)));
+            let w = worker.clone();
+            set_timeout(20, move |_| {
+                let (log, w) = (log.clone(), w.clone());
+                worker.borrow().as_ref().unwrap().on_message(move |msg| {
+                    let msg = match msg.into_int().unwrap() {
+                        1 => "first",
+                        2 => "second",
+                        _ => "echo",
+                    };
+                    log.borrow_mut().push(msg);
+                    if msg == "echo" {
+                        w.borrow_mut().take();
+                    }
+                });
+            });
+        });
+
+        assert_eq!(log, vec!["first", "second", "echo"]);
+    }
+
+    #[test]
+    fn test_context_follows_callback_chain() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let l = log.clone();
+
+        Runtime::new()
+            .run(move || {
+                for trace_id in [1usize, 2].iter().cloned() {
+                    let l = l.clone();
+                    run_with_context(trace_id, move || {
+                        set_timeout(0, move |_| {
+                            let l = l.clone();
+                            runtime().register_event_threadpool(
+                                || Js::Undefined,
+                                ThreadPoolTaskKind::Encrypt,
+                                move |_| {
+                                    let l = l.clone();
+                                    queue_microtask(move || {
+                                        let trace_id = *get_context::<usize>().unwrap();
+                                        l.borrow_mut().push(trace_id);
+                                    });
+                                },
+                            );
+                        });
+                    });
+                }
+                assert!(get_context::<usize>().is_none());
+            })
+            .unwrap();
+
+        let mut log = log.borrow().clone();
+        log.sort();
+        assert_eq!(log, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_context_restored_after_panic() {
+        Runtime::new()
+            .run(|| {
+                let caught = panic::catch_unwind(|| run_with_context(1usize, || panic!("boom")));
+                assert!(caught.is_err());
+                assert!(get_context::<usize>().is_none());
+                // Nor do the callbacks registered afterwards.
+                set_timeout(0, |_| assert!(get_context::<usize>().is_none()));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_uncaught_exception_stops_the_loop() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let l = log.clone();
+
+        let result = Runtime::new().run(move || {
+            set_timeout(0, |_| panic!("boom"));
+            set_timeout(50, move |_| l.borrow_mut().push("never runs"));
+        });
+
+        let exception = result.unwrap_err();
+        assert!(matches!(exception.origin, CallbackOrigin::Timer));
+        assert_eq!(exception.message(), Some("boom"));
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_uncaught_exception_hook_keeps_the_loop_going() {
+        let log = run_logged(|log| {
+            let l = log.clone();
+            on_uncaught_exception(move |e| {
+                match e.origin {
+                    CallbackOrigin::ThreadPool(_) => l.borrow_mut().push("pool panicked"),
+                    CallbackOrigin::Microtask => l.borrow_mut().push("microtask panicked"),
+                    _ => l.borrow_mut().push("other panicked"),
+                }
+                assert_eq!(e.message(), Some("boom"));
+            });
+
+            runtime().register_event_threadpool(
+                || Js::Undefined,
+                ThreadPoolTaskKind::Encrypt,
+                |_| {
+                    queue_microtask(|| panic!("boom"));
+                    panic!("boom");
+                },
+            );
+            let l = log.clone();
+            set_timeout(20, move |_| l.borrow_mut().push("still running"));
+        });
+
+        assert_eq!(
+            log,
+            vec!["pool panicked", "microtask panicked", "still running"]
+        );
+    }
+
+    #[test]
+    fn test_panicking_pool_task() {
+        let log = run_logged(|log| {
+            let l = log.clone();
+            on_uncaught_exception(move |e| {
+                assert!(matches!(e.origin, CallbackOrigin::ThreadPool(_)));
+                assert_eq!(e.message(), Some("boom"));
+                l.borrow_mut().push("task panicked");
+            });
+
+            let l = log.clone();
+            runtime().register_event_threadpool(
+                || panic!("boom"),
+                ThreadPoolTaskKind::Encrypt,
+                move |_| l.borrow_mut().push("never runs"),
+            );
+            // Every thread is still there for the next tasks.
+            for _ in 0..NUM_THREADS + 1 {
+                let l = log.clone();
+                runtime().register_event_threadpool(
+                    || Js::Undefined,
+                    ThreadPoolTaskKind::Encrypt,
+                    move |_| l.borrow_mut().push("done"),
+                );
+            }
+        });
+
+        let mut log = log;
+        log.sort_unstable();
+        let mut expected = vec!["done"; NUM_THREADS + 1];
+        expected.push("task panicked");
+        assert_eq!(log, expected);
+
+        // Without a hook it stops the loop like any other panic.
+        let result = Runtime::new().run(|| {
+            runtime().register_event_threadpool(
+                || panic!("boom"),
+                ThreadPoolTaskKind::Encrypt,
+                |_| {},
+            );
+        });
+        let exception = result.unwrap_err();
+        assert!(matches!(exception.origin, CallbackOrigin::ThreadPool(_)));
+    }
 }
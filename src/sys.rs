@@ -0,0 +1,485 @@
+//! Thin wrappers around the Linux syscalls the runtime needs that std
+//! doesn't expose.
+use std::ffi::CString;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+
+#[link(name = "c")]
+extern "C" {
+    /// http://man7.org/linux/man-pages/man2/eventfd.2.html
+    fn eventfd(initval: u32, flags: i32) -> i32;
+    fn read(fd: i32, buf: *mut u8, count: usize) -> isize;
+    fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+    fn close(fd: i32) -> i32;
+    /// http://man7.org/linux/man-pages/man7/epoll.7.html
+    fn epoll_create1(flags: i32) -> i32;
+    fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut EpollEvent) -> i32;
+    fn epoll_wait(epfd: i32, events: *mut EpollEvent, maxevents: i32, timeout: i32) -> i32;
+    /// http://man7.org/linux/man-pages/man7/inotify.7.html
+    fn inotify_init1(flags: i32) -> i32;
+    fn inotify_add_watch(fd: i32, pathname: *const i8, mask: u32) -> i32;
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+    /// http://man7.org/linux/man-pages/man2/getrandom.2.html
+    fn getrandom(buf: *mut u8, buflen: usize, flags: u32) -> isize;
+    /// http://man7.org/linux/man-pages/man2/connect.2.html
+    fn socket(domain: i32, ty: i32, protocol: i32) -> i32;
+    fn connect(fd: i32, addr: *const u8, len: u32) -> i32;
+    /// io_uring has no libc wrappers, so it goes through `syscall(2)`.
+    fn syscall(number: i64, ...) -> i64;
+}
+
+const EFD_CLOEXEC: i32 = 0o2000000;
+const EFD_NONBLOCK: i32 = 0o4000;
+const IN_CLOEXEC: i32 = 0o2000000;
+const IN_NONBLOCK: i32 = 0o4000;
+const EPOLL_CLOEXEC: i32 = 0o2000000;
+
+pub const EPOLL_CTL_ADD: i32 = 1;
+pub const EPOLL_CTL_DEL: i32 = 2;
+pub const EPOLL_CTL_MOD: i32 = 3;
+pub const EPOLLIN: u32 = 0x1;
+pub const EPOLLOUT: u32 = 0x4;
+pub const EPOLLERR: u32 = 0x8;
+pub const EPOLLHUP: u32 = 0x10;
+pub const EPOLLONESHOT: u32 = 0x40000000;
+
+fn cvt(res: i32) -> io::Result<i32> {
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(res)
+}
+
+pub fn close_fd(fd: RawFd) {
+    unsafe { close(fd) };
+}
+
+/// A counter fd that is readable while it's non-zero. Any thread can bump it
+/// with `notify` to wake up whoever is polling it.
+pub struct EventFd {
+    fd: RawFd,
+}
+
+impl EventFd {
+    pub fn new() -> io::Result<Self> {
+        let fd = cvt(unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) })?;
+        Ok(EventFd { fd })
+    }
+
+    pub fn notify(&self) -> io::Result<()> {
+        let one = 1u64.to_ne_bytes();
+        let res = unsafe { write(self.fd, one.as_ptr(), one.len()) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Resets the counter so the fd isn't readable anymore. Returns how many
+    /// notifications were pending.
+    pub fn clear(&self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        let res = unsafe { read(self.fd, buf.as_mut_ptr(), buf.len()) };
+        if res == -1 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(0),
+                _ => Err(err),
+            };
+        }
+        Ok(u64::from_ne_bytes(buf))
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        close_fd(self.fd);
+    }
+}
+
+/// `struct epoll_event`. The kernel packs it on x86_64 only.
+#[repr(C)]
+#[cfg_attr(target_arch = "x86_64", repr(packed))]
+#[derive(Clone, Copy)]
+pub struct EpollEvent {
+    pub events: u32,
+    pub data: u64,
+}
+
+/// An epoll instance. Closed on drop.
+pub struct Epoll {
+    fd: RawFd,
+}
+
+impl Epoll {
+    pub fn new() -> io::Result<Self> {
+        let fd = cvt(unsafe { epoll_create1(EPOLL_CLOEXEC) })?;
+        Ok(Epoll { fd })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Blocks until an event is ready or `timeout_ms` (-1 is forever) passed.
+    pub fn wait(&self, events: &mut Vec<EpollEvent>, timeout_ms: i32) -> io::Result<usize> {
+        events.clear();
+        let max = events.capacity() as i32;
+        let n = cvt(unsafe { epoll_wait(self.fd, events.as_mut_ptr(), max, timeout_ms) })?;
+        unsafe { events.set_len(n as usize) };
+        Ok(n as usize)
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        close_fd(self.fd);
+    }
+}
+
+pub fn epoll_control(epfd: RawFd, op: i32, fd: RawFd, events: u32, token: usize) -> io::Result<()> {
+    let mut event = EpollEvent {
+        events,
+        data: token as u64,
+    };
+    cvt(unsafe { epoll_ctl(epfd, op, fd, &mut event) })?;
+    Ok(())
+}
+
+pub const IN_MODIFY: u32 = 0x2;
+pub const IN_MOVED_FROM: u32 = 0x40;
+pub const IN_MOVED_TO: u32 = 0x80;
+pub const IN_CREATE: u32 = 0x100;
+pub const IN_DELETE: u32 = 0x200;
+pub const IN_DELETE_SELF: u32 = 0x400;
+pub const IN_MOVE_SELF: u32 = 0x800;
+pub const IN_IGNORED: u32 = 0x8000;
+pub const IN_ISDIR: u32 = 0x40000000;
+
+/// One record read from an inotify fd.
+pub struct InotifyEvent {
+    pub wd: i32,
+    pub mask: u32,
+    pub cookie: u32,
+    pub name: Option<String>,
+}
+
+/// A non-blocking inotify instance. Closed on drop.
+pub struct Inotify {
+    fd: RawFd,
+}
+
+impl Inotify {
+    pub fn new() -> io::Result<Self> {
+        let fd = cvt(unsafe { inotify_init1(IN_CLOEXEC | IN_NONBLOCK) })?;
+        Ok(Inotify { fd })
+    }
+
+    /// Returns the watch descriptor.
+    pub fn add_watch(&self, path: &Path, mask: u32) -> io::Result<i32> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        cvt(unsafe { inotify_add_watch(self.fd, path.as_ptr(), mask) })
+    }
+
+    /// Reads every event that is queued right now.
+    pub fn read_events(&self) -> io::Result<Vec<InotifyEvent>> {
+        const HEADER: usize = 16;
+        let mut events = vec![];
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let n = unsafe { read(self.fd, buf.as_mut_ptr(), buf.len()) };
+            if n == -1 {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::WouldBlock => Ok(events),
+                    _ => Err(err),
+                };
+            }
+
+            let mut offset = 0;
+            while offset + HEADER <= n as usize {
+                let field = |i: usize| {
+                    let start = offset + i * 4;
+                    u32::from_ne_bytes([buf[start], buf[start + 1], buf[start + 2], buf[start + 3]])
+                };
+                let len = field(3) as usize;
+                let name = &buf[offset + HEADER..offset + HEADER + len];
+                let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+
+                events.push(InotifyEvent {
+                    wd: field(0) as i32,
+                    mask: field(1),
+                    cookie: field(2),
+                    name: if name.is_empty() {
+                        None
+                    } else {
+                        Some(String::from_utf8_lossy(name).into_owned())
+                    },
+                });
+                offset += HEADER + len;
+            }
+        }
+    }
+}
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        close_fd(self.fd);
+    }
+}
+
+/// A shared, writable mapping of `len` bytes of `fd`. Unmapped on drop.
+pub struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    pub fn new(fd: RawFd, len: usize, offset: i64) -> io::Result<Self> {
+        const PROT_READ: i32 = 0x1;
+        const PROT_WRITE: i32 = 0x2;
+        const MAP_SHARED: i32 = 0x1;
+        const MAP_POPULATE: i32 = 0x8000;
+        const MAP_FAILED: *mut u8 = !0 as *mut u8;
+
+        let prot = PROT_READ | PROT_WRITE;
+        let flags = MAP_SHARED | MAP_POPULATE;
+        let ptr = unsafe { mmap(std::ptr::null_mut(), len, prot, flags, fd, offset) };
+        if ptr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len })
+    }
+
+    pub fn ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+/// http://man7.org/linux/man-pages/man2/io_uring_setup.2.html
+/// The numbers are the same on every architecture.
+const SYS_IO_URING_SETUP: i64 = 425;
+const SYS_IO_URING_ENTER: i64 = 426;
+const SYS_IO_URING_REGISTER: i64 = 427;
+
+pub const IORING_OFF_SQ_RING: i64 = 0;
+pub const IORING_OFF_CQ_RING: i64 = 0x8000000;
+pub const IORING_OFF_SQES: i64 = 0x10000000;
+pub const IORING_ENTER_GETEVENTS: u32 = 1;
+pub const IORING_REGISTER_EVENTFD: u32 = 4;
+/// Added in 5.6 together with the read, write, openat and close opcodes.
+pub const IORING_FEAT_RW_CUR_POS: u32 = 1 << 3;
+
+pub const IORING_OP_FSYNC: u8 = 3;
+pub const IORING_OP_OPENAT: u8 = 18;
+pub const IORING_OP_CLOSE: u8 = 19;
+pub const IORING_OP_READ: u8 = 22;
+pub const IORING_OP_WRITE: u8 = 23;
+
+pub const AT_FDCWD: i32 = -100;
+pub const O_RDONLY: i32 = 0;
+pub const O_WRONLY: i32 = 0o1;
+pub const O_CREAT: i32 = 0o100;
+pub const O_TRUNC: i32 = 0o1000;
+pub const O_APPEND: i32 = 0o2000;
+pub const O_CLOEXEC: i32 = 0o2000000;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct SqringOffsets {
+    pub head: u32,
+    pub tail: u32,
+    pub ring_mask: u32,
+    pub ring_entries: u32,
+    pub flags: u32,
+    pub dropped: u32,
+    pub array: u32,
+    pub resv1: u32,
+    pub user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct CqringOffsets {
+    pub head: u32,
+    pub tail: u32,
+    pub ring_mask: u32,
+    pub ring_entries: u32,
+    pub overflow: u32,
+    pub cqes: u32,
+    pub flags: u32,
+    pub resv1: u32,
+    pub user_addr: u64,
+}
+
+/// `struct io_uring_params`
+#[repr(C)]
+#[derive(Default)]
+pub struct IoUringParams {
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    pub flags: u32,
+    pub sq_thread_cpu: u32,
+    pub sq_thread_idle: u32,
+    pub features: u32,
+    pub wq_fd: u32,
+    pub resv: [u32; 3],
+    pub sq_off: SqringOffsets,
+    pub cq_off: CqringOffsets,
+}
+
+/// `struct io_uring_sqe`, with the unions flattened to the fields we use.
+#[repr(C)]
+#[derive(Default)]
+pub struct IoUringSqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub ioprio: u16,
+    pub fd: i32,
+    pub off: u64,
+    pub addr: u64,
+    pub len: u32,
+    pub op_flags: u32,
+    pub user_data: u64,
+    pub buf_index: u16,
+    pub personality: u16,
+    pub splice_fd_in: i32,
+    pub addr3: u64,
+    pub pad: u64,
+}
+
+/// `struct io_uring_cqe`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct IoUringCqe {
+    pub user_data: u64,
+    pub res: i32,
+    pub flags: u32,
+}
+
+pub fn io_uring_setup(entries: u32, params: &mut IoUringParams) -> io::Result<RawFd> {
+    let res = unsafe { syscall(SYS_IO_URING_SETUP, entries, params as *mut IoUringParams) };
+    cvt(res as i32)
+}
+
+pub fn io_uring_enter(fd: RawFd, to_submit: u32, min_complete: u32, flags: u32) -> io::Result<u32> {
+    let null = std::ptr::null::<u8>();
+    let res = unsafe {
+        syscall(
+            SYS_IO_URING_ENTER,
+            fd,
+            to_submit,
+            min_complete,
+            flags,
+            null,
+            0usize,
+        )
+    };
+    cvt(res as i32).map(|n| n as u32)
+}
+
+/// Makes the kernel bump `eventfd` whenever it posts a completion.
+pub fn io_uring_register_eventfd(fd: RawFd, eventfd: RawFd) -> io::Result<()> {
+    let res = unsafe {
+        syscall(
+            SYS_IO_URING_REGISTER,
+            fd,
+            IORING_REGISTER_EVENTFD,
+            &eventfd as *const i32,
+            1u32,
+        )
+    };
+    cvt(res as i32)?;
+    Ok(())
+}
+
+/// Fills `buf` from the kernel's CSPRNG, falling back to `/dev/urandom` on
+/// kernels without `getrandom(2)`.
+pub fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    const ENOSYS: i32 = 38;
+
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        let n = unsafe { getrandom(rest.as_mut_ptr(), rest.len(), 0) };
+        if n == -1 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(ENOSYS) => return fill_from_urandom(rest),
+                _ if err.kind() == io::ErrorKind::Interrupted => continue,
+                _ => return Err(err),
+            }
+        }
+        filled += n as usize;
+    }
+    Ok(())
+}
+
+fn fill_from_urandom(buf: &mut [u8]) -> io::Result<()> {
+    use std::io::Read;
+    std::fs::File::open("/dev/urandom")?.read_exact(buf)
+}
+
+const AF_INET: i32 = 2;
+const AF_INET6: i32 = 10;
+const SOCK_STREAM: i32 = 1;
+const SOCK_NONBLOCK: i32 = 0o4000;
+const SOCK_CLOEXEC: i32 = 0o2000000;
+const EINPROGRESS: i32 = 115;
+
+/// Starts connecting a non-blocking socket to `addr` without waiting for
+/// the handshake. The socket turns writable once it's done, and
+/// `TcpStream::take_error` then tells whether it worked.
+pub fn connect_nonblocking(addr: &SocketAddr) -> io::Result<TcpStream> {
+    // `struct sockaddr_in` and `struct sockaddr_in6`, with the port and
+    // address in network byte order.
+    let mut raw = [0u8; 28];
+    let (domain, len) = match addr {
+        SocketAddr::V4(v4) => {
+            raw[4..8].copy_from_slice(&v4.ip().octets());
+            (AF_INET, 16)
+        }
+        SocketAddr::V6(v6) => {
+            raw[4..8].copy_from_slice(&v6.flowinfo().to_be_bytes());
+            raw[8..24].copy_from_slice(&v6.ip().octets());
+            raw[24..28].copy_from_slice(&v6.scope_id().to_ne_bytes());
+            (AF_INET6, 28)
+        }
+    };
+    raw[..2].copy_from_slice(&(domain as u16).to_ne_bytes());
+    raw[2..4].copy_from_slice(&addr.port().to_be_bytes());
+
+    let fd = cvt(unsafe { socket(domain, SOCK_STREAM | SOCK_NONBLOCK | SOCK_CLOEXEC, 0) })?;
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    if unsafe { connect(fd, raw.as_ptr(), len) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(EINPROGRESS) {
+            return Err(err);
+        }
+    }
+    Ok(stream)
+}
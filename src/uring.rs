@@ -0,0 +1,417 @@
+//! A small io_uring instance used for file I/O instead of the thread pool.
+//! The kernel signals completions on an eventfd, which the runtime registers
+//! with the epoll thread like any other fd.
+use crate::runtime::Js;
+use crate::sys::{self, EventFd, IoUringCqe, IoUringParams, IoUringSqe, Mmap};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The most a single read or write may transfer, as in Linux. Larger ops
+/// are cut down to this, and complete as short reads and writes.
+const MAX_RW_COUNT: usize = 0x7fff_f000;
+
+/// An operation handed to the kernel. Whatever memory the kernel reads or
+/// writes is owned by the operation, and the ring holds on to it until the
+/// completion has been reaped.
+pub enum Op {
+    Open {
+        path: CString,
+        flags: i32,
+        mode: u32,
+    },
+    /// Reads up to `len` bytes. Completes with `Js::Bytes`.
+    Read {
+        fd: RawFd,
+        len: usize,
+        offset: u64,
+    },
+    /// Writes `data[start..]`. Completes with the number of bytes written.
+    Write {
+        fd: RawFd,
+        data: Rc<Vec<u8>>,
+        start: usize,
+        offset: u64,
+    },
+    Fsync {
+        fd: RawFd,
+    },
+    Close {
+        fd: RawFd,
+    },
+}
+
+/// An op that has been handed to the kernel, with the buffer it reads into.
+struct InFlight {
+    op: Op,
+    buf: Vec<u8>,
+}
+
+pub struct Uring {
+    fd: RawFd,
+    sq: Mmap,
+    cq: Mmap,
+    sqes: Mmap,
+    params: IoUringParams,
+    eventfd: EventFd,
+    in_flight: HashMap<u64, InFlight>,
+    /// Ops waiting for room in the ring.
+    backlog: VecDeque<(u64, Op)>,
+}
+
+impl Uring {
+    /// Fails if the kernel doesn't support io_uring or is older than 5.6.
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let mut params = IoUringParams::default();
+        let fd = sys::io_uring_setup(entries, &mut params)?;
+        Uring::map(fd, params).inspect_err(|_| sys::close_fd(fd))
+    }
+
+    fn map(fd: RawFd, params: IoUringParams) -> io::Result<Self> {
+        if params.features & sys::IORING_FEAT_RW_CUR_POS == 0 {
+            return Err(io::Error::other("io_uring is too old."));
+        }
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize
+            + params.cq_entries as usize * std::mem::size_of::<IoUringCqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<IoUringSqe>();
+
+        let sq = Mmap::new(fd, sq_len, sys::IORING_OFF_SQ_RING)?;
+        let cq = Mmap::new(fd, cq_len, sys::IORING_OFF_CQ_RING)?;
+        let sqes = Mmap::new(fd, sqes_len, sys::IORING_OFF_SQES)?;
+
+        let eventfd = EventFd::new()?;
+        sys::io_uring_register_eventfd(fd, eventfd.as_raw_fd())?;
+
+        Ok(Uring {
+            fd,
+            sq,
+            cq,
+            sqes,
+            params,
+            eventfd,
+            in_flight: HashMap::new(),
+            backlog: VecDeque::new(),
+        })
+    }
+
+    /// Readable whenever there are completions to reap.
+    pub fn eventfd(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    /// Hands `op` to the kernel. `user_data` comes back with its completion.
+    /// Returns the ops the kernel wouldn't take, which won't complete, with
+    /// their errors.
+    pub fn submit(&mut self, user_data: u64, op: Op) -> Vec<(u64, Js)> {
+        self.backlog.push_back((user_data, op));
+        self.flush()
+    }
+
+    /// Takes every completion the kernel has posted, then submits whatever
+    /// was waiting for room. Ops that couldn't be submitted are among the
+    /// results, with their errors.
+    pub fn complete(&mut self) -> Vec<(u64, Js)> {
+        let mut done = vec![];
+        let (head, tail) = (self.cq_field(|o| o.head), self.cq_field(|o| o.tail));
+        let mask = unsafe { (*self.cq_field(|o| o.ring_mask)).load(Ordering::Relaxed) };
+
+        let mut current = unsafe { (*head).load(Ordering::Relaxed) };
+        let end = unsafe { (*tail).load(Ordering::Acquire) };
+        while current != end {
+            let cqe = unsafe {
+                let cqes = self.cq.ptr().add(self.params.cq_off.cqes as usize);
+                *(cqes as *const IoUringCqe).add((current & mask) as usize)
+            };
+            current = current.wrapping_add(1);
+
+            if let Some(InFlight { op, mut buf }) = self.in_flight.remove(&cqe.user_data) {
+                let res = if cqe.res < 0 {
+                    Js::Error(io::Error::from_raw_os_error(-cqe.res))
+                } else if let Op::Read { .. } = op {
+                    buf.truncate(cqe.res as usize);
+                    Js::Bytes(buf)
+                } else {
+                    Js::Int(cqe.res as usize)
+                };
+                done.push((cqe.user_data, res));
+            }
+        }
+        unsafe { (*head).store(current, Ordering::Release) };
+
+        done.extend(self.flush());
+        done
+    }
+
+    /// Moves as much of the backlog into the ring as fits without overflowing
+    /// the completion queue, and tells the kernel about it. Returns the ops
+    /// it refused.
+    fn flush(&mut self) -> Vec<(u64, Js)> {
+        let (sq_head, sq_tail) = (self.sq_field(|o| o.head), self.sq_field(|o| o.tail));
+        let mask = unsafe { (*self.sq_field(|o| o.ring_mask)).load(Ordering::Relaxed) };
+
+        let mut tail = unsafe { (*sq_tail).load(Ordering::Relaxed) };
+        // The kernel moves the head as it takes entries.
+        let head = unsafe { (*sq_head).load(Ordering::Acquire) };
+        let mut free = self.params.sq_entries - tail.wrapping_sub(head);
+        while self.in_flight.len() < self.params.cq_entries as usize && free > 0 {
+            let (user_data, op) = match self.backlog.pop_front() {
+                Some(next) => next,
+                None => break,
+            };
+            let mut in_flight = InFlight { op, buf: vec![] };
+            let index = tail & mask;
+            unsafe {
+                let sqe = (self.sqes.ptr() as *mut IoUringSqe).add(index as usize);
+                *sqe = prepare(&mut in_flight, user_data);
+                let array = self.sq.ptr().add(self.params.sq_off.array as usize) as *mut u32;
+                *array.add(index as usize) = index;
+            }
+            self.in_flight.insert(user_data, in_flight);
+            tail = tail.wrapping_add(1);
+            free -= 1;
+        }
+        unsafe { (*sq_tail).store(tail, Ordering::Release) };
+
+        // Entries may be left over from a submission the kernel cut short.
+        loop {
+            let head = unsafe { (*sq_head).load(Ordering::Acquire) };
+            let pending = tail.wrapping_sub(head);
+            if pending == 0 {
+                return vec![];
+            }
+            match sys::io_uring_enter(self.fd, pending, 0, 0) {
+                Ok(0) => return self.unsubmit(io::ErrorKind::WouldBlock.into()),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return self.unsubmit(e),
+            }
+        }
+    }
+
+    /// Takes back the entries the kernel hasn't consumed, so they can fail
+    /// with `e` instead of completing.
+    fn unsubmit(&mut self, e: io::Error) -> Vec<(u64, Js)> {
+        let (sq_head, sq_tail) = (self.sq_field(|o| o.head), self.sq_field(|o| o.tail));
+        let mask = unsafe { (*self.sq_field(|o| o.ring_mask)).load(Ordering::Relaxed) };
+        let head = unsafe { (*sq_head).load(Ordering::Acquire) };
+        let tail = unsafe { (*sq_tail).load(Ordering::Relaxed) };
+
+        let mut failed = vec![];
+        let mut current = head;
+        while current != tail {
+            let user_data = unsafe {
+                let sqes = self.sqes.ptr() as *const IoUringSqe;
+                (*sqes.add((current & mask) as usize)).user_data
+            };
+            self.in_flight.remove(&user_data);
+            let e = match e.raw_os_error() {
+                Some(code) => io::Error::from_raw_os_error(code),
+                None => io::Error::new(e.kind(), e.to_string()),
+            };
+            failed.push((user_data, Js::Error(e)));
+            current = current.wrapping_add(1);
+        }
+        unsafe { (*sq_tail).store(head, Ordering::Release) };
+        failed
+    }
+
+    /// The ring fields are shared with the kernel, hence the atomics. The
+    /// pointers are valid as long as `self` is.
+    fn sq_field(&self, field: impl Fn(&sys::SqringOffsets) -> u32) -> *const AtomicU32 {
+        let offset = field(&self.params.sq_off) as usize;
+        unsafe { self.sq.ptr().add(offset) as *const AtomicU32 }
+    }
+
+    fn cq_field(&self, field: impl Fn(&sys::CqringOffsets) -> u32) -> *const AtomicU32 {
+        let offset = field(&self.params.cq_off) as usize;
+        unsafe { self.cq.ptr().add(offset) as *const AtomicU32 }
+    }
+}
+
+fn prepare(in_flight: &mut InFlight, user_data: u64) -> IoUringSqe {
+    let mut sqe = IoUringSqe {
+        user_data,
+        ..IoUringSqe::default()
+    };
+    match &in_flight.op {
+        Op::Open { path, flags, mode } => {
+            sqe.opcode = sys::IORING_OP_OPENAT;
+            sqe.fd = sys::AT_FDCWD;
+            sqe.addr = path.as_ptr() as u64;
+            sqe.len = *mode;
+            sqe.op_flags = *flags as u32;
+        }
+        Op::Read { fd, len, offset } => {
+            in_flight.buf = vec![0; (*len).min(MAX_RW_COUNT)];
+            sqe.opcode = sys::IORING_OP_READ;
+            sqe.fd = *fd;
+            sqe.addr = in_flight.buf.as_mut_ptr() as u64;
+            sqe.len = in_flight.buf.len() as u32;
+            sqe.off = *offset;
+        }
+        Op::Write {
+            fd,
+            data,
+            start,
+            offset,
+        } => {
+            sqe.opcode = sys::IORING_OP_WRITE;
+            sqe.fd = *fd;
+            sqe.addr = data[*start..].as_ptr() as u64;
+            sqe.len = (data.len() - start).min(MAX_RW_COUNT) as u32;
+            sqe.off = *offset;
+        }
+        Op::Fsync { fd } => {
+            sqe.opcode = sys::IORING_OP_FSYNC;
+            sqe.fd = *fd;
+        }
+        Op::Close { fd } => {
+            sqe.opcode = sys::IORING_OP_CLOSE;
+            sqe.fd = *fd;
+        }
+    }
+    sqe
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        // The kernel may still be writing into buffers we own.
+        self.backlog.clear();
+        while !self.in_flight.is_empty() {
+            if sys::io_uring_enter(self.fd, 0, 1, sys::IORING_ENTER_GETEVENTS).is_err() {
+                break;
+            }
+            self.complete();
+        }
+        sys::close_fd(self.fd);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn wait_for(uring: &mut Uring, user_data: u64) -> Js {
+        loop {
+            sys::io_uring_enter(uring.fd, 0, 1, sys::IORING_ENTER_GETEVENTS).unwrap();
+            let done = uring.complete();
+            if let Some((_, res)) = done.into_iter().find(|(id, _)| *id == user_data) {
+                return res;
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_write_read_close() {
+        let mut uring = match Uring::new(4) {
+            Ok(uring) => uring,
+            // Nothing to test on kernels without io_uring.
+            Err(_) => return,
+        };
+        let file = std::env::temp_dir().join(format!("adven-uring-{}", std::process::id()));
+        let path = CString::new(file.to_str().unwrap()).unwrap();
+
+        let flags = sys::O_WRONLY | sys::O_CREAT | sys::O_TRUNC | sys::O_CLOEXEC;
+        let open = Op::Open {
+            path: path.clone(),
+            flags,
+            mode: 0o644,
+        };
+        assert!(uring.submit(1, open).is_empty());
+        let fd = wait_for(&mut uring, 1).into_int().unwrap() as RawFd;
+
+        let data = Rc::new(b"Hello io_uring".to_vec());
+        let write = Op::Write {
+            fd,
+            data,
+            start: 6,
+            offset: 0,
+        };
+        assert!(uring.submit(2, write).is_empty());
+        assert!(matches!(wait_for(&mut uring, 2), Js::Int(8)));
+        assert!(uring.submit(3, Op::Close { fd }).is_empty());
+        wait_for(&mut uring, 3);
+
+        let open = Op::Open {
+            path,
+            flags: sys::O_RDONLY | sys::O_CLOEXEC,
+            mode: 0,
+        };
+        assert!(uring.submit(4, open).is_empty());
+        let fd = wait_for(&mut uring, 4).into_int().unwrap() as RawFd;
+        let read = Op::Read {
+            fd,
+            len: 100,
+            offset: 3,
+        };
+        assert!(uring.submit(5, read).is_empty());
+        assert_eq!(wait_for(&mut uring, 5).into_bytes().unwrap(), b"uring");
+        assert!(uring.submit(6, Op::Close { fd }).is_empty());
+        wait_for(&mut uring, 6);
+
+        assert_eq!(fs::read(&file).unwrap(), b"io_uring");
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_more_ops_than_entries() {
+        let mut uring = match Uring::new(2) {
+            Ok(uring) => uring,
+            Err(_) => return,
+        };
+        let file = std::env::temp_dir().join(format!("adven-uring-many-{}", std::process::id()));
+        let f = fs::File::create(&file).unwrap();
+
+        // The ring takes two at a time, the rest wait their turn.
+        for id in 0..10 {
+            let fsync = Op::Fsync { fd: f.as_raw_fd() };
+            assert!(uring.submit(id, fsync).is_empty());
+        }
+        let mut done = vec![];
+        while done.len() < 10 {
+            sys::io_uring_enter(uring.fd, 0, 1, sys::IORING_ENTER_GETEVENTS).unwrap();
+            done.extend(uring.complete().into_iter().map(|(id, res)| {
+                assert!(matches!(res, Js::Int(0)));
+                id
+            }));
+        }
+        done.sort_unstable();
+        assert_eq!(done, (0..10).collect::<Vec<_>>());
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_failed_submission() {
+        let mut uring = match Uring::new(2) {
+            Ok(uring) => uring,
+            Err(_) => return,
+        };
+        // Makes `io_uring_enter` fail.
+        let fd = std::mem::replace(&mut uring.fd, -1);
+        let mut failed = uring.submit(7, Op::Fsync { fd: 0 });
+        uring.fd = fd;
+
+        // It's reported once, right away, and never completes.
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 7);
+        let e = std::mem::replace(&mut failed[0].1, Js::Undefined);
+        assert_eq!(e.into_error().unwrap().raw_os_error(), Some(9));
+        assert!(uring.in_flight.is_empty());
+        let head = unsafe { (*uring.sq_field(|o| o.head)).load(Ordering::Acquire) };
+        let tail = unsafe { (*uring.sq_field(|o| o.tail)).load(Ordering::Relaxed) };
+        assert_eq!(head, tail);
+
+        // The ring still works.
+        assert!(uring.submit(8, Op::Close { fd: -1 }).is_empty());
+        sys::io_uring_enter(uring.fd, 0, 1, sys::IORING_ENTER_GETEVENTS).unwrap();
+        let done = uring.complete();
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].0, 8);
+    }
+}